@@ -0,0 +1,149 @@
+use instant::Instant;
+use shakmaty::fen::Fen;
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::time::Duration;
+
+use crate::mcts::{eval_to_cp, Mcts};
+use crate::output;
+use crate::search::to_uci;
+use crate::state::State;
+use crate::transposition_table::TranspositionTable;
+
+pub const DEFAULT_ANALYZE_PLAYOUTS: usize = 10000;
+
+const PV_LENGTH: usize = 16;
+
+// Playouts between checks of the time limit
+const TIME_CHECK_INTERVAL: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Limit {
+    Playouts(usize),
+    Time(Duration),
+}
+
+/// The result of searching a position, with the eval relative to the side to move.
+pub struct Analysis {
+    pub fen: String,
+    pub best_move: Option<String>,
+    pub pv: Vec<String>,
+    pub eval: f32,
+    pub draw: f32,
+    pub playouts: usize,
+    // Visits of each root move, most visited first
+    pub visits: Vec<(String, u32)>,
+}
+
+impl Analysis {
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let quoted = |s: &String| format!("\"{s}\"");
+
+        let pv: Vec<String> = self.pv.iter().map(quoted).collect();
+        let visits: Vec<String> = self
+            .visits
+            .iter()
+            .map(|(m, v)| format!("\"{m}\":{v}"))
+            .collect();
+
+        format!(
+            "{{\"fen\":\"{}\",\"bestmove\":{},\"pv\":[{}],\"cp\":{},\"q\":{},\"draw\":{},\"playouts\":{},\"visits\":{{{}}}}}",
+            self.fen,
+            self.best_move.as_ref().map_or("null".to_string(), quoted),
+            pv.join(","),
+            eval_to_cp(self.eval),
+            self.eval,
+            self.draw,
+            self.playouts,
+            visits.join(",")
+        )
+    }
+}
+
+/// Searches `state` on the calling thread until `limit` is reached.
+pub(crate) fn search(state: State, limit: Limit) -> Mcts {
+    let mcts = Mcts::new(
+        state,
+        TranspositionTable::empty(),
+        TranspositionTable::zero(),
+    );
+
+    match limit {
+        Limit::Playouts(n) => mcts.playout_sync_n(n),
+        Limit::Time(duration) => {
+            let start = Instant::now();
+            while start.elapsed() < duration {
+                mcts.playout_sync_n(TIME_CHECK_INTERVAL);
+            }
+        }
+    }
+
+    mcts
+}
+
+/// Searches the position in `fen` on the calling thread until `limit` is reached.
+/// Returns `None` if the FEN is invalid.
+#[must_use]
+pub fn analyze(fen: &str, limit: Limit) -> Option<Analysis> {
+    let state = State::from_fen_and_moves(Some(fen), &[])?;
+    let fen = Fen::from_setup(state.board()).to_string();
+    let mcts = search(state, limit);
+
+    let tree = mcts.tree();
+
+    let mut visits: Vec<(String, u32)> = tree
+        .root_node()
+        .hots()
+        .iter()
+        .map(|h| (h.get_move().to_uci(), h.visits()))
+        .collect();
+    visits.sort_by_key(|(_, v)| Reverse(*v));
+
+    Some(Analysis {
+        fen,
+        best_move: mcts.best_move().as_ref().map(to_uci),
+        pv: mcts
+            .principal_variation(PV_LENGTH)
+            .iter()
+            .map(to_uci)
+            .collect(),
+        eval: tree.eval(),
+        draw: tree.draw(),
+        playouts: tree.playouts(),
+        visits,
+    })
+}
+
+/// Analyzes each FEN in the file at `path`, one per line, printing the results as
+/// JSON lines.
+///
+/// # Errors
+///
+/// Fails if the file can't be read. Invalid positions are logged and skipped.
+pub fn run(path: &str, limit: Limit) -> io::Result<()> {
+    let result = analyze_file(path, limit);
+    output::flush();
+    result
+}
+
+fn analyze_file(path: &str, limit: Limit) -> io::Result<()> {
+    let file = BufReader::new(File::open(path)?);
+
+    for line in file.lines() {
+        let line = line?;
+        let fen = line.trim();
+
+        if fen.is_empty() {
+            continue;
+        }
+
+        match analyze(fen, limit) {
+            Some(analysis) => output!("{}", analysis.to_json()),
+            None => error!("Bad position: {}", fen),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,265 @@
+//! Annotates the games of a PGN file with evals, marks for inaccuracies, mistakes and
+//! blunders, and optionally the line the engine prefers after each mistake.
+
+use pgn_reader::{BufferedReader, Outcome, RawHeader, SanPlus, Skip, Visitor};
+use shakmaty::{Chess, Color, Move, Position, Setup};
+use std::fs::File;
+use std::io::{self, BufWriter, Stdout, Write};
+
+use crate::analyze::{self, Limit};
+use crate::mcts::eval_to_cp;
+use crate::state::{Builder as StateBuilder, State};
+
+// Drops in the mover's expected score, on a scale from -1 to 1, for each mark
+const INACCURACY: f32 = 0.1;
+const MISTAKE: f32 = 0.2;
+const BLUNDER: f32 = 0.3;
+
+const NAG_MISTAKE: u8 = 2;
+const NAG_BLUNDER: u8 = 4;
+const NAG_INACCURACY: u8 = 6;
+
+const PV_LENGTH: usize = 8;
+const LINE_WIDTH: usize = 80;
+
+#[allow(clippy::module_name_repetitions)]
+pub struct AnnotateOptions {
+    pub limit: Limit,
+    // Add the engine's line after mistakes and blunders
+    pub variations: bool,
+}
+
+struct Annotator<'a> {
+    options: &'a AnnotateOptions,
+    out: BufWriter<Stdout>,
+    headers: Vec<(String, String)>,
+    state: StateBuilder,
+    outcome: Option<Outcome>,
+    skip: bool,
+    games: usize,
+}
+
+// A searched position, with the eval for the side to move
+struct Evaluation {
+    eval: f32,
+    pv: Vec<Move>,
+}
+
+fn evaluate(state: &State, limit: Limit) -> Evaluation {
+    match state.board().outcome() {
+        Some(Outcome::Decisive { .. }) => Evaluation {
+            eval: -1.,
+            pv: Vec::new(),
+        },
+        Some(Outcome::Draw) => Evaluation {
+            eval: 0.,
+            pv: Vec::new(),
+        },
+        None => {
+            let mcts = analyze::search(state.clone(), limit);
+            Evaluation {
+                eval: mcts.eval(),
+                pv: mcts.principal_variation(PV_LENGTH),
+            }
+        }
+    }
+}
+
+fn nag(loss: f32) -> Option<u8> {
+    if loss >= BLUNDER {
+        Some(NAG_BLUNDER)
+    } else if loss >= MISTAKE {
+        Some(NAG_MISTAKE)
+    } else if loss >= INACCURACY {
+        Some(NAG_INACCURACY)
+    } else {
+        None
+    }
+}
+
+fn move_number(pos: &Chess, force: bool) -> Option<String> {
+    match pos.turn() {
+        Color::White => Some(format!("{}.", pos.fullmoves())),
+        Color::Black if force => Some(format!("{}...", pos.fullmoves())),
+        Color::Black => None,
+    }
+}
+
+// The moves of `line` from `pos` in SAN, numbered as in a PGN
+fn san_line(pos: &Chess, line: &[Move]) -> Vec<String> {
+    let mut pos = pos.clone();
+    let mut tokens = Vec::new();
+
+    for (i, mov) in line.iter().enumerate() {
+        tokens.extend(move_number(&pos, i == 0));
+        tokens.push(SanPlus::from_move_and_play_unchecked(&mut pos, mov).to_string());
+    }
+
+    tokens
+}
+
+fn wrap(tokens: &[String]) -> String {
+    let mut text = String::new();
+    let mut line_len = 0;
+
+    for token in tokens {
+        if line_len > 0 && line_len + 1 + token.len() > LINE_WIDTH {
+            text.push('\n');
+            line_len = 0;
+        } else if line_len > 0 {
+            text.push(' ');
+            line_len += 1;
+        }
+
+        text.push_str(token);
+        line_len += token.len();
+    }
+
+    text
+}
+
+impl<'a> Annotator<'a> {
+    fn write_game(&mut self) -> io::Result<()> {
+        let (mut state, moves) = self.state.extract();
+        let mut pos = state.board().clone();
+
+        let mut evaluations = Vec::with_capacity(moves.len() + 1);
+        for mov in &moves {
+            evaluations.push(evaluate(&state, self.options.limit));
+            state.make_move(mov);
+        }
+        evaluations.push(evaluate(&state, self.options.limit));
+
+        let result = match self.outcome {
+            Some(outcome) => outcome.to_string(),
+            None => "*".into(),
+        };
+
+        for (key, value) in &self.headers {
+            writeln!(self.out, "[{key} \"{value}\"]")?;
+        }
+        writeln!(self.out, "[Annotator \"Princhess\"]")?;
+        writeln!(self.out)?;
+
+        let mut tokens = Vec::new();
+
+        for (i, mov) in moves.iter().enumerate() {
+            let before = &evaluations[i];
+            let after = -evaluations[i + 1].eval;
+            let mark = nag(before.eval - after);
+
+            let start = pos.clone();
+            tokens.extend(move_number(&pos, true));
+            tokens.push(SanPlus::from_move_and_play_unchecked(&mut pos, mov).to_string());
+
+            if let Some(nag) = mark {
+                tokens.push(format!("${nag}"));
+            }
+
+            let white_eval = start.turn().fold_wb(after, -after);
+            tokens.push(format!(
+                "{{ [%eval {:.2}] }}",
+                eval_to_cp(white_eval) as f32 / 100.
+            ));
+
+            let is_mistake = mark.map_or(false, |nag| nag != NAG_INACCURACY);
+            if self.options.variations && is_mistake && before.pv.first() != Some(mov) {
+                let line = san_line(&start, &before.pv);
+                if let Some((first, rest)) = line.split_first() {
+                    tokens.push(format!("({first}"));
+                    tokens.extend(rest.iter().cloned());
+                    if let Some(last) = tokens.last_mut() {
+                        last.push(')');
+                    }
+                }
+            }
+        }
+
+        tokens.push(result);
+
+        writeln!(self.out, "{}", wrap(&tokens))?;
+        writeln!(self.out)?;
+        self.out.flush()
+    }
+}
+
+impl<'a> Visitor for Annotator<'a> {
+    type Result = ();
+
+    fn begin_game(&mut self) {
+        self.headers.clear();
+        self.state = StateBuilder::default();
+        self.outcome = None;
+        self.skip = false;
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let key = String::from_utf8_lossy(key).into_owned();
+        let value = value.decode_utf8_lossy().into_owned();
+
+        if key == "FEN" {
+            match StateBuilder::from_fen(&value) {
+                Some(state) => self.state = state,
+                None => self.skip = true,
+            }
+        }
+
+        self.headers.push((key, value));
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        Skip(self.skip)
+    }
+
+    fn san(&mut self, san: SanPlus) {
+        if self.skip {
+            return;
+        }
+
+        match san.san.to_move(self.state.chess()) {
+            Ok(m) => self.state.make_move(m),
+            Err(_) => self.skip = true,
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn outcome(&mut self, outcome: Option<Outcome>) {
+        self.outcome = outcome;
+    }
+
+    fn end_game(&mut self) -> Self::Result {
+        if self.skip {
+            return;
+        }
+
+        self.games += 1;
+        info!("Annotating game {}", self.games);
+
+        if let Err(e) = self.write_game() {
+            error!("Unable to write game {}: {:?}", self.games, e);
+        }
+    }
+}
+
+/// Searches every position of the games in the PGN file at `path` and prints them
+/// back as PGN with `[%eval]` comments and marks for the moves losing the most.
+///
+/// # Errors
+///
+/// Fails if the file can't be read.
+pub fn annotate(path: &str, options: &AnnotateOptions) -> io::Result<()> {
+    let mut annotator = Annotator {
+        options,
+        out: BufWriter::new(io::stdout()),
+        headers: Vec::new(),
+        state: StateBuilder::default(),
+        outcome: None,
+        skip: true,
+        games: 0,
+    };
+
+    BufferedReader::new(File::open(path)?).read_all(&mut annotator)
+}
@@ -1,77 +1,276 @@
 use arc_swap::ArcSwap;
 use log::debug;
+#[cfg(not(target_arch = "wasm32"))]
 use memmap::MmapMut;
-use std::cell::UnsafeCell;
+use once_cell::sync::Lazy;
+use std::cell::{Cell, UnsafeCell};
 use std::collections::{HashSet, VecDeque};
 use std::mem;
-use std::ptr;
+use std::ptr::null_mut;
 use std::slice;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Mutex;
 
+#[cfg(not(target_arch = "wasm32"))]
+use crate::options::is_numa;
+
 #[derive(Debug)]
 pub enum Error {
     Full,
 }
 
 const CHUNK_SIZE: usize = 1 << 21; // 2MB
+#[cfg(not(target_arch = "wasm32"))]
+const PAGE_SIZE: usize = 4096;
 
 static IDS: AtomicU64 = AtomicU64::new(0);
 
+const ALIGN: usize = 8;
+
+// Anything a handle refers to starts on this boundary within its chunk, so that
+// 32 bits of handle cover 64GB
+const HANDLE_ALIGN: usize = 16;
+
+// A handle is a chunk's slot in the registry and an offset into it in units of
+// HANDLE_ALIGN
+const OFFSET_BITS: u32 = (CHUNK_SIZE / HANDLE_ALIGN).trailing_zeros();
+const OFFSET_MASK: u32 = (1 << OFFSET_BITS) - 1;
+const MAX_CHUNKS: usize = 1 << (32 - OFFSET_BITS);
+
+// The chunks of every arena, so handles resolve without knowing their arena.
+// Slot zero is never used, which makes the zero handle null.
+static CHUNKS: Lazy<Box<[AtomicPtr<u8>]>> =
+    Lazy::new(|| (0..MAX_CHUNKS).map(|_| AtomicPtr::default()).collect());
+
+// Slots are handed out from the top of the used range, reusing released ones first
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(1);
+static FREE_SLOTS: Lazy<Mutex<Vec<u32>>> = Lazy::new(Mutex::default);
+
+fn register_chunk(base: *mut u8) -> Option<u32> {
+    let slot = FREE_SLOTS.lock().unwrap().pop().or_else(|| {
+        NEXT_SLOT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |slot| {
+                (slot < MAX_CHUNKS).then(|| slot + 1)
+            })
+            .ok()
+            .map(|slot| slot as u32)
+    })?;
+
+    CHUNKS[slot as usize].store(base, Ordering::SeqCst);
+    Some(slot)
+}
+
+fn release_chunk(slot: u32) {
+    CHUNKS[slot as usize].store(null_mut(), Ordering::SeqCst);
+    FREE_SLOTS.lock().unwrap().push(slot);
+}
+
+/// A 32 bit reference to memory handed out by any arena.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Handle(u32);
+
+impl Handle {
+    pub const NULL: Self = Self(0);
+
+    fn new(slot: u32, offset: usize) -> Self {
+        debug_assert!(offset % HANDLE_ALIGN == 0 && offset < CHUNK_SIZE);
+        Self(slot << OFFSET_BITS | (offset / HANDLE_ALIGN) as u32)
+    }
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_null(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Resolves the handle, or `None` for the null handle.
+    ///
+    /// # Safety
+    ///
+    /// The handle must come from allocating a `T`, and the arena it came from must
+    /// not have been cleared since.
+    pub unsafe fn get<'a, T>(self) -> Option<&'a T> {
+        if self.is_null() {
+            return None;
+        }
+
+        let slot = (self.0 >> OFFSET_BITS) as usize;
+        let offset = (self.0 & OFFSET_MASK) as usize * HANDLE_ALIGN;
+        debug_assert!(offset + mem::size_of::<T>() <= CHUNK_SIZE);
+
+        let base = CHUNKS[slot].load(Ordering::Relaxed);
+        debug_assert!(!base.is_null(), "handle into a released chunk");
+
+        Some(&*base.add(offset).cast::<T>())
+    }
+
+    /// Hints the CPU to start loading the start of what the handle points at into
+    /// cache. Only the chunk registry is read, so this is safe on any handle.
+    #[inline]
+    pub fn prefetch(self) {
+        if self.is_null() {
+            return;
+        }
+
+        let slot = (self.0 >> OFFSET_BITS) as usize;
+        let offset = (self.0 & OFFSET_MASK) as usize * HANDLE_ALIGN;
+        let base = CHUNKS[slot].load(Ordering::Relaxed);
+
+        if !base.is_null() {
+            prefetch(base.wrapping_add(offset));
+        }
+    }
+}
+
+/// Hints the CPU to start loading the cache line at `ptr`, which need not be valid.
+#[inline]
+pub fn prefetch<T>(ptr: *const T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        _mm_prefetch(ptr.cast::<i8>(), _MM_HINT_T0);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
+
+// A chunk registered under `slot`, which is released when the chunk is dropped
+struct OwnedChunk {
+    slot: u32,
+    // Only reached through the registry once registered, so accesses through
+    // handles and through allocators share one pointer
+    _chunk: Chunk,
+}
+
+impl OwnedChunk {
+    fn new(mut chunk: Chunk) -> Option<Self> {
+        let slot = register_chunk(chunk.as_mut_ptr())?;
+        Some(Self {
+            slot,
+            _chunk: chunk,
+        })
+    }
+
+    fn memory(&self) -> *mut u8 {
+        CHUNKS[self.slot as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OwnedChunk {
+    fn drop(&mut self) {
+        release_chunk(self.slot);
+    }
+}
+
+// There's no mmap on wasm, where chunks come from the heap instead
+#[cfg(not(target_arch = "wasm32"))]
+type Chunk = MmapMut;
+#[cfg(target_arch = "wasm32")]
+type Chunk = Box<[u8]>;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn new_chunk() -> Chunk {
+    let mut chunk = MmapMut::map_anon(CHUNK_SIZE).unwrap();
+
+    // Pages go to the NUMA node of the thread first writing them, so fault them in
+    // from the allocating thread instead of whichever touches them first
+    if is_numa() {
+        for page in chunk.chunks_mut(PAGE_SIZE) {
+            page[0] = 0;
+        }
+    }
+
+    chunk
+}
+
+#[cfg(target_arch = "wasm32")]
+fn new_chunk() -> Chunk {
+    vec![0; CHUNK_SIZE].into_boxed_slice()
+}
+
 pub struct Arena {
-    owned_mappings: Mutex<VecDeque<MmapMut>>,
-    max_chunks: usize,
+    owned_mappings: Mutex<VecDeque<OwnedChunk>>,
+    max_chunks: AtomicUsize,
     allocators: ArcSwap<HashSet<u64>>,
+    // Chunks handed out since the counters were last reset
+    chunks_allocated: AtomicUsize,
+    // Chunks held right now, readable without the lock
+    chunks_in_use: AtomicUsize,
 }
 
 impl Arena {
     pub fn new(max_size_mb: usize) -> Self {
-        let max_chunks = (max_size_mb << 20) / CHUNK_SIZE;
+        let max_chunks = Self::chunks_for_mb(max_size_mb);
         debug!(
             "Creating Arena of {}mb = {} chunks",
             max_size_mb, max_chunks
         );
         Self {
             owned_mappings: Mutex::default(),
-            max_chunks,
+            max_chunks: AtomicUsize::new(max_chunks),
             allocators: ArcSwap::default(),
+            chunks_allocated: AtomicUsize::new(0),
+            chunks_in_use: AtomicUsize::new(0),
         }
     }
 
+    fn chunks_for_mb(size_mb: usize) -> usize {
+        (size_mb << 20) / CHUNK_SIZE
+    }
+
+    /// Changes the limit for future allocations. Shrinking below the current usage
+    /// just makes the arena full.
+    pub fn set_max_size_mb(&self, max_size_mb: usize) {
+        let max_chunks = Self::chunks_for_mb(max_size_mb);
+        debug!(
+            "Resizing Arena to {}mb = {} chunks",
+            max_size_mb, max_chunks
+        );
+        self.max_chunks.store(max_chunks, Ordering::Relaxed);
+    }
+
     pub fn full(&self) -> bool {
         let owned_mappings = self.owned_mappings.lock().unwrap();
-        owned_mappings.len() > self.max_chunks
+        owned_mappings.len() > self.max_chunks.load(Ordering::Relaxed)
     }
 
-    fn give_mmap(&self, mut map: MmapMut) -> Result<&mut [u8], Error> {
-        let result = ptr::addr_of_mut!(*map);
-        let mut owned_mappings = self.owned_mappings.lock().unwrap();
-        if owned_mappings.len() > self.max_chunks {
-            Err(Error::Full)
-        } else {
-            owned_mappings.push_back(map);
-            unsafe { Ok(&mut *result) }
-        }
+    /// The number of chunks the arena can hand out.
+    pub fn capacity_chunks(&self) -> usize {
+        self.max_chunks.load(Ordering::Relaxed) + 1
     }
 
-    fn alloc_chunk(&self, id: u64) -> Result<&mut [u8], Error> {
-        let allocators = self.allocators.load();
-        if !allocators.contains(&id) {
-            self.allocators.rcu(|als| {
-                let mut als = HashSet::clone(als);
-                als.insert(id);
-                als
-            });
+    /// Chunks handed out as a share of the capacity, in permille. Chunks still being
+    /// filled count as used.
+    pub fn usage_permille(&self) -> usize {
+        let used = self.chunks_in_use.load(Ordering::Relaxed);
+        (used * 1000 / self.capacity_chunks()).min(1000)
+    }
+
+    fn give_mmap(&self, map: Chunk) -> Result<(u32, &mut [u8]), Error> {
+        let mut owned_mappings = self.owned_mappings.lock().unwrap();
+        if owned_mappings.len() > self.max_chunks.load(Ordering::Relaxed) {
+            return Err(Error::Full);
         }
 
-        self.give_mmap(MmapMut::map_anon(CHUNK_SIZE).unwrap())
+        // Every handle is in use, which needs tables of tens of gigabytes
+        let owned = OwnedChunk::new(map).ok_or(Error::Full)?;
+        let slot = owned.slot;
+        let result = owned.memory();
+
+        owned_mappings.push_back(owned);
+        self.chunks_allocated.fetch_add(1, Ordering::Relaxed);
+        self.chunks_in_use
+            .store(owned_mappings.len(), Ordering::Relaxed);
+        unsafe { Ok((slot, slice::from_raw_parts_mut(result, CHUNK_SIZE))) }
     }
 
-    // This is a combination of give_mmap and alloc_chunk that always succeeds.
-    // This means not checking if we exceed the max chunks.
-    // This is because we always want creating of an allocator to succeed, even if already full.
-    #[allow(clippy::mut_from_ref)]
-    fn allocator_chunk(&self, id: u64) -> &mut [u8] {
+    fn alloc_chunk(&self, id: u64) -> Result<(u32, &mut [u8]), Error> {
         let allocators = self.allocators.load();
         if !allocators.contains(&id) {
             self.allocators.rcu(|als| {
@@ -81,25 +280,30 @@ impl Arena {
             });
         }
 
-        let mut mmap = MmapMut::map_anon(CHUNK_SIZE).unwrap();
-
-        let result = ptr::addr_of_mut!(*mmap);
-        let mut owned_mappings = self.owned_mappings.lock().unwrap();
-
-        owned_mappings.push_back(mmap);
-        unsafe { &mut *result }
+        self.give_mmap(new_chunk())
     }
 
+    /// A new allocator, which takes its first chunk once it's used, so creating
+    /// one always succeeds even if the arena is full.
     pub fn allocator(&self) -> Allocator {
         let id = IDS.fetch_add(1, Ordering::SeqCst);
 
         Allocator {
             id,
             arena: self,
-            memory: UnsafeCell::new(self.allocator_chunk(id)),
+            memory: UnsafeCell::new(&mut []),
+            chunk: Cell::new((0, 0)),
         }
     }
 
+    pub fn chunks_allocated(&self) -> usize {
+        self.chunks_allocated.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_counters(&self) {
+        self.chunks_allocated.store(0, Ordering::Relaxed);
+    }
+
     pub fn is_allocator_valid(&self, id: u64) -> bool {
         self.allocators.load().contains(&id)
     }
@@ -107,6 +311,7 @@ impl Arena {
     pub fn clear(&self) {
         let mut owned_mappings = self.owned_mappings.lock().unwrap();
         owned_mappings.clear();
+        self.chunks_in_use.store(0, Ordering::Relaxed);
 
         self.allocators.store(HashSet::new().into());
     }
@@ -116,17 +321,35 @@ pub struct Allocator<'a> {
     id: u64,
     arena: &'a Arena,
     memory: UnsafeCell<&'a mut [u8]>,
+    // Slot and start address of the chunk `memory` is carved from
+    chunk: Cell<(u32, usize)>,
 }
 
-const ALIGN: usize = 8;
-
 impl<'a> Allocator<'a> {
+    fn next_chunk(&self) -> Result<(), Error> {
+        let (slot, chunk) = self.arena.alloc_chunk(self.id)?;
+        self.chunk.set((slot, chunk.as_ptr() as usize));
+        unsafe { *self.memory.get() = chunk };
+        Ok(())
+    }
+
+    // Skips to where a handle can point in the current chunk
+    fn align_for_handle(&self) {
+        let memory = unsafe { &mut *self.memory.get() };
+        let offset = (memory.as_ptr() as usize).wrapping_sub(self.chunk.get().1);
+        let padding = offset.wrapping_neg() % HANDLE_ALIGN;
+
+        let rest = mem::take(memory);
+        let len = rest.len();
+        *memory = &mut rest[padding.min(len)..];
+    }
+
     fn get_memory(&self, sz: usize) -> Result<&'a mut [u8], Error> {
         let memory = unsafe { &mut *self.memory.get() };
 
         if !self.arena.is_allocator_valid(self.id) {
             debug!("Invalid allocator {}", self.id);
-            *memory = self.arena.alloc_chunk(self.id)?;
+            self.next_chunk()?;
             self.get_memory(sz)
         } else if sz <= memory.len() {
             let (left, right) = memory.split_at_mut(sz);
@@ -136,17 +359,23 @@ impl<'a> Allocator<'a> {
             debug!("sz > CHUNK_SIZE, {} > {}", sz, CHUNK_SIZE);
             Err(Error::Full)
         } else {
-            *memory = self.arena.alloc_chunk(self.id)?;
+            self.next_chunk()?;
             self.get_memory(sz)
         }
     }
 
-    pub fn alloc_one<T>(&self) -> Result<&'a mut T, Error> {
+    /// Allocates a `T`, along with a handle that resolves to it.
+    pub fn alloc_one<T>(&self) -> Result<(Handle, &'a mut T), Error> {
         assert!(ALIGN % mem::align_of::<T>() == 0);
         let x = mem::size_of::<T>();
         let x = x + ((!x + 1) % ALIGN); // TODO fix panic when x=0
+        self.align_for_handle();
         let x = self.get_memory(x)?;
-        Ok(unsafe { &mut *(x.as_mut_ptr().cast::<T>()) })
+
+        let (slot, base) = self.chunk.get();
+        let handle = Handle::new(slot, x.as_ptr() as usize - base);
+
+        Ok((handle, unsafe { &mut *(x.as_mut_ptr().cast::<T>()) }))
     }
 
     pub fn alloc_slice<T>(&self, sz: usize) -> Result<&'a mut [T], Error> {
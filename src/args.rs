@@ -1,9 +1,20 @@
 extern crate argparse;
-use self::argparse::{ArgumentParser, Collect, Store, StoreOption};
+use self::argparse::{ArgumentParser, Collect, Store, StoreOption, StoreTrue};
 
 pub struct Options {
     pub train_pgn: Option<String>,
     pub train_output_path: String,
+    pub train_format: String,
+    pub train_features: String,
+    pub convert_pgn: Option<String>,
+    pub min_elo: u32,
+    pub no_bullet: bool,
+    pub skip_plies: usize,
+    pub rescore: bool,
+    pub analyze: Option<String>,
+    pub variations: bool,
+    pub nodes: Option<usize>,
+    pub movetime: Option<u64>,
     pub extra: Vec<String>,
 }
 
@@ -12,6 +23,17 @@ impl Default for Options {
         Options {
             train_pgn: None,
             train_output_path: "train_data.libsvm".into(),
+            train_format: "libsvm".into(),
+            train_features: "standard".into(),
+            convert_pgn: None,
+            min_elo: 0,
+            no_bullet: false,
+            skip_plies: 8,
+            rescore: false,
+            analyze: None,
+            variations: false,
+            nodes: None,
+            movetime: None,
             extra: Vec::new(),
         }
     }
@@ -31,6 +53,61 @@ pub fn init() {
             Store,
             "train output path",
         );
+        ap.refer(&mut options.train_format).add_option(
+            &["-f", "--format"],
+            Store,
+            "train output format (libsvm or bullet)",
+        );
+        ap.refer(&mut options.train_features).add_option(
+            &["--features"],
+            Store,
+            "train: libsvm input features (standard or extended)",
+        );
+        ap.refer(&mut options.convert_pgn).add_option(
+            &["-c", "--convert"],
+            StoreOption,
+            "path to .pgn to convert into bullet training data",
+        );
+        ap.refer(&mut options.min_elo).add_option(
+            &["--min-elo"],
+            Store,
+            "convert: skip games where either player is rated below this",
+        );
+        ap.refer(&mut options.no_bullet).add_option(
+            &["--no-bullet"],
+            StoreTrue,
+            "convert: skip bullet games",
+        );
+        ap.refer(&mut options.skip_plies).add_option(
+            &["--skip-plies"],
+            Store,
+            "convert: number of opening plies to skip",
+        );
+        ap.refer(&mut options.rescore).add_option(
+            &["--rescore"],
+            StoreTrue,
+            "convert: rescore positions with the current nets instead of [%eval] comments",
+        );
+        ap.refer(&mut options.analyze).add_option(
+            &["-a", "--analyze"],
+            StoreOption,
+            "path to a file of FENs to analyze, printing the results as JSON lines",
+        );
+        ap.refer(&mut options.variations).add_option(
+            &["--variations"],
+            StoreTrue,
+            "annotate: add the engine's line after mistakes and blunders",
+        );
+        ap.refer(&mut options.nodes).add_option(
+            &["--nodes"],
+            StoreOption,
+            "analyze, annotate, epdtest: playouts per position",
+        );
+        ap.refer(&mut options.movetime).add_option(
+            &["--movetime"],
+            StoreOption,
+            "analyze, annotate, epdtest: ms per position, instead of a number of playouts",
+        );
         ap.refer(&mut options.extra).add_argument(
             "uci_commands",
             Collect,
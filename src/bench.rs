@@ -0,0 +1,58 @@
+use instant::Instant;
+
+use crate::mcts::Mcts;
+use crate::output;
+use crate::state::State;
+use crate::transposition_table::TranspositionTable;
+
+pub const DEFAULT_BENCH_PLAYOUTS: usize = 5000;
+
+const BENCH_FENS: [&str; 16] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+    "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+    "r1bqkb1r/pppp1ppp/2n2n2/4p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4",
+    "r1bq1rk1/pp2ppbp/2np1np1/8/3NP3/2N1BP2/PPPQ2PP/R3KB1R w KQ - 3 9",
+    "rnbqkb1r/pp3ppp/4pn2/2pp4/2PP4/2N1PN2/PP3PPP/R1BQKB1R w KQkq - 0 5",
+    "2rq1rk1/pb1nbppp/1p2pn2/2pp4/2PP4/1PNBPN2/PB3PPP/2RQ1RK1 w - - 4 11",
+    "r2qr1k1/1p1nbppp/p2pbn2/4p3/4P3/1NN1BP2/PPPQ2PP/2KR1B1R w - - 6 12",
+    "6k1/5ppp/8/8/8/8/5PPP/3R2K1 w - - 0 1",
+    "8/8/4k3/8/2p5/8/B2K4/8 w - - 0 1",
+    "8/5pk1/6p1/7p/3P3P/5KP1/8/8 w - - 0 1",
+    "4rrk1/pp1n3p/3q2pQ/2p1pb2/2PP4/2P3N1/P2B2PP/4RRK1 b - - 7 19",
+    "r1b1k2r/ppppnppp/2n2q2/2b5/3NP3/2P1B3/PP3PPP/RN1QKB1R w KQkq - 0 1",
+];
+
+/// Searches a fixed set of positions for `playouts` playouts each on a single thread,
+/// printing the total node count, which only changes with the search, and the speed.
+pub fn run(playouts: usize) {
+    let mut nodes = 0;
+    let start = Instant::now();
+
+    for fen in BENCH_FENS {
+        let state = match State::from_fen_and_moves(Some(fen), &[]) {
+            Some(state) => state,
+            None => {
+                error!("Bad bench position: {}", fen);
+                continue;
+            }
+        };
+
+        let mcts = Mcts::new(
+            state,
+            TranspositionTable::empty(),
+            TranspositionTable::zero(),
+        );
+        mcts.playout_sync_n(playouts);
+
+        nodes += mcts.tree().num_nodes();
+    }
+
+    let elapsed_ms = start.elapsed().as_millis().max(1) as usize;
+
+    output!("{} nodes {} nps", nodes, nodes * 1000 / elapsed_ms);
+    output::flush();
+}
@@ -0,0 +1,133 @@
+use arc_swap::ArcSwapOption;
+use once_cell::sync::Lazy;
+use rand::Rng;
+use shakmaty::uci::Uci;
+use shakmaty::{CastlingMode, Move};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::math;
+use crate::options::is_book_best_move;
+use crate::state::State;
+
+// Polyglot entries are a big endian key, move, weight and learn value
+const ENTRY_SIZE: usize = 16;
+
+static BOOK: Lazy<ArcSwapOption<Book>> = Lazy::new(ArcSwapOption::empty);
+
+/// A Polyglot opening book, with its entries sorted by key.
+pub struct Book {
+    entries: Vec<u8>,
+}
+
+impl Book {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let entries = fs::read(path)?;
+
+        if entries.len() % ENTRY_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a polyglot book",
+            ));
+        }
+
+        Ok(Self { entries })
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    fn entry(&self, idx: usize) -> (u64, u16, u16) {
+        let e = &self.entries[idx * ENTRY_SIZE..(idx + 1) * ENTRY_SIZE];
+        let mut key = [0; 8];
+        key.copy_from_slice(&e[..8]);
+
+        (
+            u64::from_be_bytes(key),
+            u16::from_be_bytes([e[8], e[9]]),
+            u16::from_be_bytes([e[10], e[11]]),
+        )
+    }
+
+    /// The book moves for `key` with their weights.
+    fn lookup(&self, key: u64) -> Vec<(u16, u16)> {
+        // First entry not below the key
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid).0 < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        (lo..self.len())
+            .map(|idx| self.entry(idx))
+            .take_while(|(k, _, _)| *k == key)
+            .map(|(_, mov, weight)| (mov, weight))
+            .collect()
+    }
+}
+
+pub fn set_book_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let book = Book::from_file(path)?;
+    BOOK.store(Some(Arc::new(book)));
+    Ok(())
+}
+
+pub fn clear_book() {
+    BOOK.store(None);
+}
+
+// Polyglot moves are the to square, from square and promotion piece in 3 bit fields,
+// with castling as the king taking its own rook. Squares are indexed like shakmaty's.
+fn polyglot_code(mov: &Move) -> u16 {
+    match mov.to_uci(CastlingMode::Chess960) {
+        Uci::Normal {
+            from,
+            to,
+            promotion,
+        } => to as u16 | (from as u16) << 6 | promotion.map_or(0, |r| r as u16 - 1) << 12,
+        _ => 0,
+    }
+}
+
+/// Picks a book move for the position, either the one with the highest weight or
+/// one at random in proportion to the weights, depending on `BookBestMove`.
+pub fn probe_book(state: &State) -> Option<Move> {
+    let book = BOOK.load();
+    let entries = book.as_ref()?.lookup(state.zobrist_key());
+
+    let moves = state.available_moves();
+    let candidates: Vec<(&Move, u32)> = entries
+        .iter()
+        .filter_map(|(code, weight)| {
+            moves
+                .iter()
+                .find(|m| polyglot_code(m) == *code)
+                .map(|m| (m, u32::from(*weight)))
+        })
+        .collect();
+
+    let total: u32 = candidates.iter().map(|(_, w)| w).sum();
+
+    let choice = if is_book_best_move() || total == 0 {
+        candidates.iter().max_by_key(|(_, w)| *w)
+    } else {
+        let mut pick = math::rng().gen_range(0, total);
+        candidates.iter().find(|(_, w)| {
+            if pick < *w {
+                true
+            } else {
+                pick -= w;
+                false
+            }
+        })
+    };
+
+    choice.map(|(m, _)| Move::clone(m))
+}
@@ -0,0 +1,252 @@
+use pgn_reader::{BufferedReader, Outcome, RawComment, RawHeader, SanPlus, Skip, Visitor};
+use shakmaty::{Color, Move};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::mem;
+use std::str;
+
+use crate::datafmt::{ChessBoard, PolicyRecord};
+use crate::mcts::{eval_to_cp, Mcts};
+use crate::output;
+use crate::state::{Builder as StateBuilder, State};
+use crate::training::GameResult;
+use crate::transposition_table::TranspositionTable;
+
+const RESCORE_PLAYOUTS: usize = 1000;
+
+// Estimated game duration below which lichess calls a game bullet
+const BULLET_SECONDS: u32 = 180;
+
+#[allow(clippy::module_name_repetitions)]
+pub struct ConvertOptions {
+    pub min_elo: u32,
+    pub no_bullet: bool,
+    pub skip_plies: usize,
+    pub rescore: bool,
+}
+
+struct Converter<'a> {
+    options: &'a ConvertOptions,
+    out_file: BufWriter<File>,
+    policy_file: BufWriter<File>,
+    state: StateBuilder,
+    // White relative centipawns from the `[%eval]` comment after each move
+    evals: Vec<Option<i32>>,
+    skip: bool,
+    games_read: usize,
+    games_used: usize,
+    rows_written: usize,
+}
+
+impl<'a> Converter<'a> {
+    fn write_position(
+        &mut self,
+        state: &State,
+        result: GameResult,
+        score: i16,
+        visits: &[(Move, u32)],
+    ) {
+        let board = ChessBoard::from_state(state, score, result);
+        board.write(&mut self.out_file).unwrap();
+        PolicyRecord::from_visits(state, board, visits)
+            .write(&mut self.policy_file)
+            .unwrap();
+
+        self.rows_written += 1;
+
+        if self.rows_written % 100_000 == 0 {
+            output!("{} rows written", self.rows_written);
+        }
+    }
+}
+
+impl<'a> Visitor for Converter<'a> {
+    type Result = ();
+
+    fn begin_game(&mut self) {
+        self.state = StateBuilder::default();
+        self.evals.clear();
+        self.skip = false;
+        self.games_read += 1;
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let value = value.decode_utf8_lossy();
+
+        match key {
+            b"WhiteElo" | b"BlackElo" => {
+                let min_elo = self.options.min_elo;
+                if min_elo > 0 && value.parse::<u32>().map_or(true, |elo| elo < min_elo) {
+                    self.skip = true;
+                }
+            }
+            b"TimeControl" => {
+                if self.options.no_bullet && is_bullet(&value) {
+                    self.skip = true;
+                }
+            }
+            b"FEN" | b"SetUp" => self.skip = true,
+            b"Variant" => self.skip |= value != "Standard",
+            _ => {}
+        }
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        Skip(self.skip)
+    }
+
+    fn san(&mut self, san: SanPlus) {
+        if self.skip {
+            return;
+        }
+
+        match san.san.to_move(self.state.chess()) {
+            Ok(m) => {
+                self.state.make_move(m);
+                self.evals.push(None);
+            }
+            Err(_) => self.skip = true,
+        }
+    }
+
+    fn comment(&mut self, comment: RawComment<'_>) {
+        if let Some(eval) = self.evals.last_mut() {
+            *eval = str::from_utf8(comment.as_bytes())
+                .ok()
+                .and_then(parse_eval_comment);
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn outcome(&mut self, outcome: Option<Outcome>) {
+        if self.skip {
+            return;
+        }
+
+        let game_result = match outcome {
+            Some(Outcome::Draw) => GameResult::Draw,
+            Some(Outcome::Decisive { winner }) => {
+                if winner == Color::White {
+                    GameResult::WhiteWin
+                } else {
+                    GameResult::BlackWin
+                }
+            }
+            None => return,
+        };
+
+        self.games_used += 1;
+
+        let evals = mem::take(&mut self.evals);
+        let (mut state, moves) = self.state.extract();
+
+        for (i, made) in moves.into_iter().enumerate() {
+            if i >= self.options.skip_plies.max(1) {
+                if self.options.rescore {
+                    let mcts = Mcts::new(
+                        state.clone(),
+                        TranspositionTable::empty(),
+                        TranspositionTable::zero(),
+                    );
+                    mcts.playout_sync_n(RESCORE_PLAYOUTS);
+
+                    let visits: Vec<_> = mcts
+                        .tree()
+                        .root_node()
+                        .hots()
+                        .iter()
+                        .filter_map(|h| Some((h.get_move().to_move(&state)?, h.visits())))
+                        .collect();
+
+                    self.write_position(
+                        &state,
+                        game_result,
+                        eval_to_cp(mcts.eval()) as i16,
+                        &visits,
+                    );
+                } else if let Some(cp) = evals[i - 1] {
+                    let score = if state.side_to_move() == Color::White {
+                        cp
+                    } else {
+                        -cp
+                    };
+                    let score = score.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+
+                    self.write_position(&state, game_result, score, &[(made.clone(), 1)]);
+                }
+            }
+            state.make_move(&made);
+        }
+    }
+
+    fn end_game(&mut self) -> Self::Result {}
+}
+
+/// Parses the `[%eval 0.17]` annotation of a lichess comment into centipawns.
+/// Mate scores are ignored.
+fn parse_eval_comment(comment: &str) -> Option<i32> {
+    let rest = &comment[comment.find("[%eval ")? + 7..];
+    let value = &rest[..rest.find(']')?];
+
+    if value.starts_with('#') {
+        return None;
+    }
+
+    value
+        .trim()
+        .parse::<f32>()
+        .ok()
+        .map(|pawns| (pawns * 100.).round() as i32)
+}
+
+/// A `TimeControl` header is `base+increment` in seconds, or `-` when untimed.
+fn is_bullet(time_control: &str) -> bool {
+    let mut parts = time_control.split('+');
+    let base = parts.next().and_then(|s| s.parse::<u32>().ok());
+    let inc = parts
+        .next()
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(0);
+
+    base.map_or(false, |base| base + 40 * inc < BULLET_SECONDS)
+}
+
+/// Writes a training record for the positions of the lichess games in `in_path` that
+/// `options` lets through.
+///
+/// # Panics
+///
+/// Panics if `in_path` can't be read or the output files can't be written.
+pub fn convert(in_path: &str, out_path: &str, options: &ConvertOptions) {
+    let out_file = BufWriter::new(File::create(out_path).expect("create"));
+    let policy_file = BufWriter::new(File::create(format!("{out_path}.policy")).expect("create"));
+
+    let mut converter = Converter {
+        options,
+        out_file,
+        policy_file,
+        state: StateBuilder::default(),
+        evals: Vec::new(),
+        skip: true,
+        games_read: 0,
+        games_used: 0,
+        rows_written: 0,
+    };
+
+    output!("Converting {in_path}...");
+
+    let file = File::open(in_path).expect("fopen");
+    BufferedReader::new(file).read_all(&mut converter).unwrap();
+
+    converter.out_file.flush().unwrap();
+    converter.policy_file.flush().unwrap();
+
+    output!(
+        "{} of {} games used, {} rows written",
+        converter.games_used, converter.games_read, converter.rows_written
+    );
+    output::flush();
+}
@@ -0,0 +1,146 @@
+//! Binary training data.
+//!
+//! Value data uses the 32 byte `ChessBoard` record of bulletformat, so it can be fed
+//! straight into bullet and compatible trainers. Everything is relative to the side to
+//! move: the board is flipped vertically when black is to move, piece nibbles are
+//! `(is_opponent << 3) | (role - 1)` in ascending square order, the score is in
+//! centipawns and the result is 0 (loss), 1 (draw) or 2 (win).
+//!
+//! Policy data is a `ChessBoard` followed by a move count (`u8`) and that many
+//! `(move: u16, visits: u16)` pairs, with moves encoded as
+//! `from | to << 6 | promotion role << 12` using the same flipped squares.
+
+use shakmaty::uci::Uci;
+use shakmaty::{CastlingMode, Color, Move, Setup, Square};
+use std::io::{self, Write};
+
+use crate::state::State;
+use crate::training::GameResult;
+
+pub const CHESS_BOARD_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChessBoard {
+    occ: u64,
+    pcs: [u8; 16],
+    score: i16,
+    result: u8,
+    ksq: u8,
+    opp_ksq: u8,
+    extra: [u8; 3],
+}
+
+impl ChessBoard {
+    pub fn from_state(state: &State, score: i16, result: GameResult) -> Self {
+        let stm = state.side_to_move();
+        let board = state.board().board();
+        let flip = |sq: Square| {
+            if stm == Color::Black {
+                sq.flip_vertical()
+            } else {
+                sq
+            }
+        };
+
+        let mut pieces: Vec<(u8, u8)> = board
+            .occupied()
+            .into_iter()
+            .filter_map(|sq| {
+                let pc = board.piece_at(sq)?;
+                let nibble = (u8::from(pc.color != stm) << 3) | (pc.role as u8 - 1);
+                Some((flip(sq) as u8, nibble))
+            })
+            .collect();
+        pieces.sort_unstable();
+
+        let mut occ = 0;
+        let mut pcs = [0; 16];
+
+        for (i, (sq, nibble)) in pieces.into_iter().take(32).enumerate() {
+            occ |= 1 << sq;
+            pcs[i / 2] |= nibble << (4 * (i & 1));
+        }
+
+        let king_sq = |c| board.king_of(c).map_or(0, |sq| flip(sq) as u8);
+
+        let result = match (result, stm) {
+            (GameResult::Draw, _) => 1,
+            (GameResult::WhiteWin, Color::White) | (GameResult::BlackWin, Color::Black) => 2,
+            _ => 0,
+        };
+
+        Self {
+            occ,
+            pcs,
+            score,
+            result,
+            ksq: king_sq(stm),
+            opp_ksq: king_sq(!stm) ^ 56,
+            extra: [0; 3],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; CHESS_BOARD_SIZE] {
+        let mut bytes = [0; CHESS_BOARD_SIZE];
+        bytes[0..8].copy_from_slice(&self.occ.to_le_bytes());
+        bytes[8..24].copy_from_slice(&self.pcs);
+        bytes[24..26].copy_from_slice(&self.score.to_le_bytes());
+        bytes[26] = self.result;
+        bytes[27] = self.ksq;
+        bytes[28] = self.opp_ksq;
+        bytes[29..32].copy_from_slice(&self.extra);
+        bytes
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_bytes())
+    }
+}
+
+pub struct PolicyRecord {
+    pub board: ChessBoard,
+    pub moves: Vec<(u16, u16)>,
+}
+
+impl PolicyRecord {
+    /// Builds a record from visit counts, keeping at most 255 moves.
+    pub fn from_visits(state: &State, board: ChessBoard, visits: &[(Move, u32)]) -> Self {
+        let moves = visits
+            .iter()
+            .take(usize::from(u8::MAX))
+            .map(|(mv, n)| (encode_move(state, mv), (*n).min(u32::from(u16::MAX)) as u16))
+            .collect();
+
+        Self { board, moves }
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.board.write(w)?;
+        w.write_all(&[self.moves.len() as u8])?;
+        for (mv, visits) in &self.moves {
+            w.write_all(&mv.to_le_bytes())?;
+            w.write_all(&visits.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_move(state: &State, mv: &Move) -> u16 {
+    let flip = |sq: Square| {
+        let sq = if state.side_to_move() == Color::Black {
+            sq.flip_vertical()
+        } else {
+            sq
+        };
+        sq as u16
+    };
+
+    match mv.to_uci(CastlingMode::Standard) {
+        Uci::Normal {
+            from,
+            to,
+            promotion,
+        } => flip(from) | flip(to) << 6 | promotion.map_or(0, |r| r as u16) << 12,
+        _ => 0,
+    }
+}
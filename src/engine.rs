@@ -0,0 +1,200 @@
+//! Searching positions from another program, without going through UCI.
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::mcts::Mcts;
+#[cfg(feature = "threads")]
+use crate::options::get_num_threads;
+use crate::options::{set_hash_size_mb, set_num_threads};
+use crate::search::{to_uci, TimeManagement};
+use crate::state::State;
+use crate::transposition_table::TranspositionTable;
+use crate::uci;
+
+const PV_LENGTH: usize = 16;
+
+/// Settings for an `Engine`. They're process wide, so engines share them.
+#[allow(clippy::module_name_repetitions)]
+#[derive(Clone, Copy, Debug)]
+pub struct EngineOptions {
+    pub threads: usize,
+    pub hash_size_mb: usize,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            threads: 1,
+            hash_size_mb: 16,
+        }
+    }
+}
+
+/// When a search ends. Without any limits it runs until `Engine::stop`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub nodes: Option<usize>,
+    pub movetime: Option<Duration>,
+    // The side to move's clock
+    pub remaining: Option<Duration>,
+    pub increment: Duration,
+    pub movestogo: Option<u32>,
+}
+
+impl Limits {
+    fn time_management(&self) -> TimeManagement {
+        let tm = match (self.movetime, self.remaining) {
+            (Some(movetime), _) => TimeManagement::from_duration(movetime),
+            (None, Some(remaining)) => {
+                TimeManagement::from_clock(remaining, self.increment, self.movestogo)
+            }
+            (None, None) => TimeManagement::infinite(),
+        };
+
+        tm.with_node_limit(self.nodes)
+    }
+}
+
+/// The outcome of a search, with moves in UCI notation and the eval relative to the
+/// side to move in [-1, 1].
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub best_move: Option<String>,
+    pub pv: Vec<String>,
+    pub eval: f32,
+    pub draw: f32,
+    pub nodes: usize,
+}
+
+pub struct Engine {
+    state: Mutex<State>,
+    sender: Sender<String>,
+    #[cfg_attr(not(feature = "threads"), allow(dead_code))]
+    receiver: Mutex<Receiver<String>>,
+}
+
+impl Engine {
+    #[must_use]
+    pub fn new(options: EngineOptions) -> Self {
+        set_num_threads(options.threads);
+        set_hash_size_mb(options.hash_size_mb);
+
+        let (sender, receiver) = channel();
+
+        Self {
+            state: Mutex::new(State::default()),
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+
+    /// Sets an option by its UCI name, as `setoption` would. Options are process wide,
+    /// so this affects every engine.
+    pub fn set_option(&self, name: &str, value: &str) {
+        uci::set_option(name, value);
+    }
+
+    /// Sets the position to search, from `fen` or the start position followed by
+    /// `moves` in UCI notation. Returns false, keeping the old position, if either
+    /// is invalid.
+    #[must_use]
+    pub fn set_position(&self, fen: Option<&str>, moves: &[&str]) -> bool {
+        match State::from_fen_and_moves(fen, moves) {
+            Some(state) => {
+                *self.state() = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The Zobrist key of the current position, the same as a Polyglot book's.
+    #[must_use]
+    pub fn zobrist_key(&self) -> u64 {
+        self.state().zobrist_key()
+    }
+
+    /// Keys of the earlier positions that count for repetitions, oldest first.
+    #[must_use]
+    pub fn history_keys(&self) -> Vec<u64> {
+        self.state().history_keys().to_vec()
+    }
+
+    /// How many times the current position occurred before.
+    #[must_use]
+    pub fn repetition_count(&self) -> usize {
+        self.state().repetition_count()
+    }
+
+    // The position is only ever replaced whole, so it's still sound after a panic
+    // elsewhere poisoned the lock
+    fn state(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Searches the current position until a limit is reached or `stop` is called
+    /// from another thread.
+    #[cfg(feature = "threads")]
+    #[must_use]
+    pub fn go(&self, limits: Limits) -> SearchResult {
+        let state = self.state().clone();
+        let receiver = self.receiver.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Forget stops that came in while nothing was searching
+        while receiver.try_recv().is_ok() {}
+
+        let search = Mcts::new(
+            state,
+            TranspositionTable::empty(),
+            TranspositionTable::zero(),
+        )
+        .into_playout_parallel_async(
+            get_num_threads(),
+            limits.time_management(),
+            &self.sender,
+        );
+
+        // The search sends a stop once it reaches its limits
+        receiver.recv().unwrap_or_default();
+
+        search_result(&search.halt())
+    }
+
+    /// Searches the current position on the calling thread until a limit is reached.
+    /// Without limits it never returns, as nothing can stop it.
+    #[cfg(not(feature = "threads"))]
+    #[must_use]
+    pub fn go(&self, limits: Limits) -> SearchResult {
+        let state = self.state().clone();
+
+        let mcts = Mcts::new(
+            state,
+            TranspositionTable::empty(),
+            TranspositionTable::zero(),
+        );
+        mcts.playout_sync_until(limits.time_management());
+
+        search_result(&mcts)
+    }
+
+    /// Ends the running search.
+    pub fn stop(&self) {
+        self.sender.send("stop".to_string()).unwrap_or(());
+    }
+}
+
+fn search_result(mcts: &Mcts) -> SearchResult {
+    SearchResult {
+        best_move: mcts.best_move().as_ref().map(to_uci),
+        pv: mcts
+            .principal_variation(PV_LENGTH)
+            .iter()
+            .map(to_uci)
+            .collect(),
+        eval: mcts.eval(),
+        draw: mcts.tree().draw(),
+        nodes: mcts.tree().num_nodes(),
+    }
+}
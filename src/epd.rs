@@ -0,0 +1,201 @@
+//! Runs EPD test suites, searching each position and checking the move found
+//! against its `bm` (best move) and `am` (avoid move) opcodes.
+
+use instant::Instant;
+use shakmaty::san::SanPlus;
+use shakmaty::Move;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::time::Duration;
+
+use crate::analyze::Limit;
+use crate::mcts::Mcts;
+use crate::output;
+use crate::search::to_uci;
+use crate::state::State;
+use crate::transposition_table::TranspositionTable;
+
+// Playouts between checks of the best move
+const CHECK_INTERVAL: usize = 256;
+
+/// A position from an EPD file, with the moves that solve it.
+#[allow(clippy::module_name_repetitions)]
+pub struct EpdPosition {
+    pub id: String,
+    pub state: State,
+    pub best_moves: Vec<Move>,
+    pub avoid_moves: Vec<Move>,
+}
+
+impl EpdPosition {
+    /// Parses the four FEN fields of an EPD line followed by its opcodes. Returns
+    /// `None` if the position or a move in `bm` or `am` is invalid, or if it has
+    /// neither.
+    #[must_use]
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(5, ' ');
+        let fen: Vec<&str> = fields.by_ref().take(4).collect();
+        let opcodes = fields.next().unwrap_or("");
+
+        let fen = format!("{} 0 1", fen.join(" "));
+        let state = State::from_fen_and_moves(Some(&fen), &[])?;
+
+        let mut id = fen.clone();
+        let mut best_moves = Vec::new();
+        let mut avoid_moves = Vec::new();
+
+        for opcode in opcodes.split(';').map(str::trim) {
+            let (name, operands) = opcode.split_once(' ').unwrap_or((opcode, ""));
+
+            let parse_moves = |moves: &mut Vec<Move>| -> Option<()> {
+                for san in operands.split_whitespace() {
+                    let san: SanPlus = san.parse().ok()?;
+                    moves.push(san.san.to_move(state.board()).ok()?);
+                }
+                Some(())
+            };
+
+            match name {
+                "bm" => parse_moves(&mut best_moves)?,
+                "am" => parse_moves(&mut avoid_moves)?,
+                "id" => id = operands.trim_matches('"').to_owned(),
+                _ => (),
+            }
+        }
+
+        if best_moves.is_empty() && avoid_moves.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            id,
+            state,
+            best_moves,
+            avoid_moves,
+        })
+    }
+
+    #[must_use]
+    pub fn is_solution(&self, mov: &Move) -> bool {
+        (self.best_moves.is_empty() || self.best_moves.contains(mov))
+            && !self.avoid_moves.contains(mov)
+    }
+}
+
+/// The outcome of searching an EPD position.
+#[allow(clippy::module_name_repetitions)]
+pub struct EpdResult {
+    pub best_move: Option<Move>,
+    pub solved: bool,
+    // When the search settled on a solution for good
+    pub time_to_solution: Option<Duration>,
+    pub playouts: usize,
+}
+
+/// Searches `position` on the calling thread until `limit` is reached, checking the
+/// best move as it goes.
+#[must_use]
+pub fn solve(position: &EpdPosition, limit: Limit) -> EpdResult {
+    let mcts = Mcts::new(
+        position.state.clone(),
+        TranspositionTable::empty(),
+        TranspositionTable::zero(),
+    );
+
+    let start = Instant::now();
+    let mut time_to_solution = None;
+
+    loop {
+        let done = match limit {
+            Limit::Playouts(n) => mcts.tree().playouts() >= n,
+            Limit::Time(duration) => start.elapsed() >= duration,
+        };
+
+        if done {
+            break;
+        }
+
+        mcts.playout_sync_n(CHECK_INTERVAL);
+
+        match mcts.best_move() {
+            Some(mov) if position.is_solution(&mov) => {
+                time_to_solution.get_or_insert_with(|| start.elapsed());
+            }
+            _ => time_to_solution = None,
+        }
+    }
+
+    let best_move = mcts.best_move();
+
+    EpdResult {
+        solved: best_move
+            .as_ref()
+            .map_or(false, |m| position.is_solution(m)),
+        best_move,
+        time_to_solution,
+        playouts: mcts.tree().playouts(),
+    }
+}
+
+/// Searches each position in the EPD file at `path`, printing whether it was solved,
+/// then the number solved and their average time to solution.
+///
+/// # Errors
+///
+/// Fails if the file can't be read. Invalid lines are logged and skipped.
+pub fn run(path: &str, limit: Limit) -> io::Result<()> {
+    let result = solve_file(path, limit);
+    output::flush();
+    result
+}
+
+fn solve_file(path: &str, limit: Limit) -> io::Result<()> {
+    let file = BufReader::new(File::open(path)?);
+
+    let mut total = 0;
+    let mut solved = 0;
+    let mut solution_time = Duration::ZERO;
+
+    for line in file.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let Some(position) = EpdPosition::parse(line) else {
+            error!("Bad EPD line: {}", line);
+            continue;
+        };
+
+        let result = solve(&position, limit);
+        total += 1;
+
+        if result.solved {
+            solved += 1;
+            solution_time += result.time_to_solution.unwrap_or_default();
+        }
+
+        output!(
+            "{} {}: played {} in {} playouts{}",
+            position.id,
+            if result.solved { "solved" } else { "failed" },
+            result.best_move.as_ref().map_or("none".into(), to_uci),
+            result.playouts,
+            result.time_to_solution.map_or(String::new(), |t| format!(
+                ", found after {} ms",
+                t.as_millis()
+            ))
+        );
+    }
+
+    output!(
+        "Solved {}/{}, average time to solution {} ms",
+        solved,
+        total,
+        (solution_time / solved.max(1) as u32).as_millis()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 16 bytes each, 4MB in all
+const NUMBER_ENTRIES: usize = 1 << 18;
+
+/// An evaluation and the key it was stored under, xored with it, so that an entry
+/// torn by two threads writing at once just fails to match.
+#[derive(Default)]
+struct Entry {
+    check: AtomicU64,
+    data: AtomicU64,
+}
+
+static CACHE: Lazy<Box<[Entry]>> =
+    Lazy::new(|| (0..NUMBER_ENTRIES).map(|_| Entry::default()).collect());
+
+fn entry(key: u64) -> &'static Entry {
+    &CACHE[key as usize % NUMBER_ENTRIES]
+}
+
+/// The evaluation and draw probability stored for `key`, if they're still there.
+pub fn probe(key: u64) -> Option<(f32, f32)> {
+    let entry = entry(key);
+    let data = entry.data.load(Ordering::Relaxed);
+
+    if key == 0 || entry.check.load(Ordering::Relaxed) ^ data != key {
+        return None;
+    }
+
+    Some((
+        f32::from_bits((data >> 32) as u32),
+        f32::from_bits(data as u32),
+    ))
+}
+
+/// Stores an evaluation, replacing whatever shared its slot.
+pub fn store(key: u64, eval: f32, draw: f32) {
+    let entry = entry(key);
+    let data = u64::from(eval.to_bits()) << 32 | u64::from(draw.to_bits());
+
+    entry.data.store(data, Ordering::Relaxed);
+    entry.check.store(key ^ data, Ordering::Relaxed);
+}
+
+/// Forgets every evaluation, for when the evaluator changes.
+pub fn clear() {
+    for entry in CACHE.iter() {
+        entry.check.store(0, Ordering::Relaxed);
+        entry.data.store(0, Ordering::Relaxed);
+    }
+}
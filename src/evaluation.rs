@@ -1,14 +1,27 @@
-use shakmaty::{MoveList, Position};
-use shakmaty_syzygy::Wdl;
-use std::mem::{self, MaybeUninit};
-use std::ptr;
+use arc_swap::ArcSwap;
+use instant::Instant;
+use once_cell::sync::Lazy;
+use shakmaty::fen::Fen;
+use shakmaty::{MoveList, Position, Setup};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::eval_cache;
 use crate::math;
+use crate::options::{
+    get_policy_capture_boost, get_policy_check_boost, get_policy_entropy_scale,
+    get_policy_promotion_boost, get_policy_temperature, get_syzygy_probe_depth,
+};
 use crate::search::SCALE;
-use crate::state::{self, State};
-use crate::tablebase::probe_tablebase_wdl;
+use crate::state::{self, FeatureSet, State};
+use crate::tablebase::{probe_tablebase_dtz, probe_tablebase_wdl, Wdl};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Flag {
     Standard,
     TerminalWin,
@@ -30,6 +43,27 @@ impl Flag {
         }
     }
 
+    pub const fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Flag::TerminalWin,
+            2 => Flag::TerminalDraw,
+            3 => Flag::TerminalLoss,
+            4 => Flag::TablebaseWin,
+            5 => Flag::TablebaseDraw,
+            6 => Flag::TablebaseLoss,
+            _ => Flag::Standard,
+        }
+    }
+
+    /// A proven win or loss, either at the node itself or propagated from its children.
+    pub fn is_decisive(self) -> bool {
+        matches!(self, Flag::TerminalWin | Flag::TerminalLoss)
+    }
+
     pub fn is_terminal(self) -> bool {
         matches!(
             self,
@@ -45,26 +79,80 @@ impl Flag {
     }
 }
 
-pub fn evaluate_state(state: &State) -> i64 {
-    let state_evaluation = (run_eval_net(state) * SCALE) as i64;
-    state
-        .side_to_move()
-        .fold_wb(state_evaluation, -state_evaluation)
+/// Returns the evaluation from White's point of view and the draw probability, both
+/// scaled by `SCALE`.
+pub fn evaluate_state(state: &State, accumulators: &mut Accumulators) -> (i64, i64) {
+    // Positions transposed into before their node exists are only evaluated once
+    let key = state.eval_key();
+    let (q, draw) = eval_cache::probe(key).unwrap_or_else(|| {
+        let (q, draw) = EVALUATOR.load().evaluate(state, accumulators);
+        eval_cache::store(key, q, draw);
+        (q, draw)
+    });
+    scale_evaluation(state, q, draw)
 }
 
-pub fn evaluate_state_flag(state: &State, moves: &MoveList) -> Flag {
+/// Evaluates a batch of leaves like `evaluate_state`, handing those missing from the
+/// eval cache to the evaluator in a single call.
+pub fn evaluate_states<'s, I: Iterator<Item = &'s State>>(
+    states: I,
+    accumulators: &mut Accumulators,
+) -> Vec<(i64, i64)> {
+    let states: Vec<&State> = states.collect();
+    let keys: Vec<u64> = states.iter().map(|state| state.eval_key()).collect();
+    let mut evals: Vec<Option<(f32, f32)>> = keys.iter().map(|&k| eval_cache::probe(k)).collect();
+
+    let missing: Vec<usize> = (0..states.len()).filter(|&i| evals[i].is_none()).collect();
+    if !missing.is_empty() {
+        let batch: Vec<&State> = missing.iter().map(|&i| states[i]).collect();
+        let results = EVALUATOR.load().evaluate_batch(&batch, accumulators);
+
+        for (&i, (q, draw)) in missing.iter().zip(results) {
+            eval_cache::store(keys[i], q, draw);
+            evals[i] = Some((q, draw));
+        }
+    }
+
+    states
+        .iter()
+        .zip(evals)
+        .map(|(state, eval)| {
+            let (q, draw) = eval.expect("every state evaluated");
+            scale_evaluation(state, q, draw)
+        })
+        .collect()
+}
+
+// From the side to move's score to White's, both scaled by `SCALE`
+fn scale_evaluation(state: &State, q: f32, draw: f32) -> (i64, i64) {
+    let state_evaluation = (q * SCALE) as i64;
+    (
+        state
+            .side_to_move()
+            .fold_wb(state_evaluation, -state_evaluation),
+        (draw * SCALE) as i64,
+    )
+}
+
+/// Draw probability for nets with a single value output. Draws are assumed to be most
+/// likely when the eval is level, fading out linearly towards a decisive eval.
+pub fn draw_estimate(q: f32) -> f32 {
+    0.5 * (1. - q.abs().min(1.))
+}
+
+/// `depth` is the distance from the search root, tablebases are only probed up to
+/// `SyzygyProbeDepth`.
+pub fn evaluate_state_flag(state: &State, moves: &MoveList, depth: usize) -> Flag {
     let flag = if moves.is_empty() {
         if state.board().is_check() {
             Flag::TerminalLoss
         } else {
             Flag::TerminalDraw
         }
+    } else if depth > get_syzygy_probe_depth() {
+        Flag::Standard
     } else if let Some(wdl) = probe_tablebase_wdl(state.board()) {
-        match wdl {
-            Wdl::Win => Flag::TablebaseWin,
-            Wdl::Loss => Flag::TablebaseLoss,
-            _ => Flag::TablebaseDraw,
-        }
+        tablebase_flag(state, wdl, depth == 0)
     } else {
         Flag::Standard
     };
@@ -72,13 +160,60 @@ pub fn evaluate_state_flag(state: &State, moves: &MoveList) -> Flag {
     state.side_to_move().fold_wb(flag, flag.flip())
 }
 
-pub fn evaluate_policy(state: &State, moves: &MoveList) -> Vec<f32> {
-    run_policy_net(state, moves)
+// Halfmoves after which the 50-move rule draws, and the slack left for DTZ being
+// rounded in some tables
+const FIFTY_MOVE_PLIES: usize = 100;
+const DTZ_ROUNDING_SLACK: usize = 1;
+
+/// The flag for a tablebase result under the 50-move rule. The WDL is for a freshly
+/// zeroed clock, so cursed wins and blessed losses are draws. At the root, wins and
+/// losses only hold with plies already on the clock if DTZ shows the clock is zeroed
+/// in time. DTZ probes are too slow for the rest of the tree, where the playouts
+/// carry on to the next zeroing move anyway.
+fn tablebase_flag(state: &State, wdl: Wdl, is_root: bool) -> Flag {
+    let decisive = match wdl {
+        Wdl::Win => Flag::TablebaseWin,
+        Wdl::Loss => Flag::TablebaseLoss,
+        Wdl::CursedWin | Wdl::BlessedLoss | Wdl::Draw => return Flag::TablebaseDraw,
+    };
+
+    let halfmoves = state.halfmove_counter();
+    if halfmoves == 0 || !is_root {
+        return decisive;
+    }
+
+    let Some(dtz) = probe_tablebase_dtz(state.board()) else {
+        return decisive;
+    };
+
+    // Judged pessimistically for the side to move, as DTZ may be a ply off
+    let plies = dtz.unsigned_abs() as usize + halfmoves;
+    let drawn = if decisive == Flag::TablebaseWin {
+        plies + DTZ_ROUNDING_SLACK > FIFTY_MOVE_PLIES
+    } else {
+        plies > FIFTY_MOVE_PLIES + DTZ_ROUNDING_SLACK
+    };
+
+    if drawn {
+        Flag::TablebaseDraw
+    } else {
+        decisive
+    }
+}
+
+/// Policy nets reading the eval net's hidden layer share the thread's `accumulators`.
+pub fn evaluate_policy(
+    state: &State,
+    moves: &MoveList,
+    accumulators: &mut Accumulators,
+) -> Vec<f32> {
+    run_policy_net(state, moves, accumulators)
 }
 
 const STATE_NUMBER_INPUTS: usize = state::NUMBER_FEATURES;
 const NUMBER_HIDDEN: usize = 192;
 const NUMBER_OUTPUTS: usize = 1;
+const NUMBER_L2: usize = 16;
 
 #[allow(clippy::excessive_precision, clippy::unreadable_literal)]
 static EVAL_HIDDEN_BIAS: [f32; NUMBER_HIDDEN] = include!("model/hidden_bias_0");
@@ -94,58 +229,888 @@ static EVAL_OUTPUT_WEIGHTS: [[f32; NUMBER_HIDDEN]; NUMBER_OUTPUTS] =
 const POLICY_NUMBER_INPUTS: usize = state::NUMBER_FEATURES;
 
 #[allow(clippy::excessive_precision, clippy::unreadable_literal)]
-static POLICY_WEIGHTS: [[f32; POLICY_NUMBER_INPUTS]; 384] = include!("policy/output_weights");
+static POLICY_WEIGHTS: [[f32; POLICY_NUMBER_INPUTS]; state::NUMBER_MOVE_IDX] =
+    include!("policy/output_weights");
 
-fn run_eval_net(state: &State) -> f32 {
-    let mut hidden_layer: [f32; NUMBER_HIDDEN] = unsafe {
-        let mut out: [MaybeUninit<f32>; NUMBER_HIDDEN] = MaybeUninit::uninit().assume_init();
+const EVAL_NET_MAGIC: &[u8; 4] = b"PEVL";
+const POLICY_NET_MAGIC: &[u8; 4] = b"PPOL";
 
-        ptr::copy_nonoverlapping(
-            EVAL_HIDDEN_BIAS.as_ptr(),
-            out.as_mut_ptr().cast::<f32>(),
-            NUMBER_HIDDEN,
-        );
+static EVALUATOR: Lazy<ArcSwap<Box<dyn Evaluator>>> =
+    Lazy::new(|| ArcSwap::from_pointee(Box::new(NetEvaluator) as Box<dyn Evaluator>));
 
-        mem::transmute(out)
-    };
+static NEXT_NET_ID: AtomicUsize = AtomicUsize::new(1);
+
+static EVAL_NET: Lazy<ArcSwap<EvalNet>> = Lazy::new(|| ArcSwap::from_pointee(EvalNet::default()));
+
+static POLICY_NET: Lazy<ArcSwap<Policy>> =
+    Lazy::new(|| ArcSwap::from_pointee(Policy::Features(PolicyNet::default())));
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    Magic,
+    Dimensions(u32, u32),
+    Size(usize),
+    Hash,
+    Curve(f32, f32),
+}
+
+/// Reads a net file: a 4 byte magic, input and output counts as little endian u32,
+/// the little endian f32 weights, and an FNV-1a hash of the weight bytes as a u64.
+/// `is_supported` checks the input count, output count and number of weights.
+fn read_net_file<P: AsRef<Path>, F: FnOnce(usize, usize, usize) -> bool>(
+    path: P,
+    magic: &[u8; 4],
+    is_supported: F,
+) -> Result<(usize, Vec<f32>), NetError> {
+    let bytes = fs::read(path).map_err(NetError::Io)?;
+
+    if bytes.len() < 12 || &bytes[..4] != magic {
+        return Err(NetError::Magic);
+    }
+
+    let read_u32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    let inputs = read_u32(&bytes[4..8]);
+    let outputs = read_u32(&bytes[8..12]);
+
+    let data = &bytes[12..];
+
+    if data.len() < 8 || data.len() % 4 != 0 {
+        return Err(NetError::Size(data.len()));
+    }
+
+    let number_weights = (data.len() - 8) / 4;
+
+    if !is_supported(inputs as usize, outputs as usize, number_weights) {
+        return Err(NetError::Dimensions(inputs, outputs));
+    }
+
+    let (weights, hash) = data.split_at(number_weights * 4);
+    let mut expected_hash = [0; 8];
+    expected_hash.copy_from_slice(hash);
+
+    if fnv1a(weights) != u64::from_le_bytes(expected_hash) {
+        return Err(NetError::Hash);
+    }
+
+    Ok((
+        inputs as usize,
+        weights
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+    ))
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// Nets may split their inputs by the side to move's king square. The 32 relative
+// king squares (files E-H) are divided into this many groups of consecutive squares,
+// ordered by rank then file, and each group has its own set of input weights.
+fn is_supported_king_buckets(king_buckets: usize) -> bool {
+    (1..=32).contains(&king_buckets) && 32 % king_buckets == 0
+}
+
+fn king_bucket(relative_king_square: usize, king_buckets: usize) -> usize {
+    let rank = relative_king_square / 8;
+    let file = relative_king_square % 8 - 4;
+    (rank * 4 + file) * king_buckets / 32
+}
+
+// Nets may also have several output heads, picked by the number of pieces on the
+// board, with the possible piece counts split evenly between the heads.
+fn is_supported_output_buckets(output_buckets: usize) -> bool {
+    (1..=8).contains(&output_buckets) && 32 % output_buckets == 0
+}
+
+// Each head is either a single value, or win, draw and loss logits.
+fn is_supported_output_rows(rows: usize) -> bool {
+    is_supported_output_buckets(rows) || (rows % 3 == 0 && is_supported_output_buckets(rows / 3))
+}
+
+// Eval nets may end with the two parameters of their centipawn curve, after the
+// output heads.
+const EVAL_CURVE_PARAMS: usize = 2;
+
+fn has_eval_curve(inputs: usize, weights: usize) -> Option<bool> {
+    if output_layout(inputs, weights).is_some() {
+        Some(false)
+    } else {
+        output_layout(inputs, weights.checked_sub(EVAL_CURVE_PARAMS)?).map(|_| true)
+    }
+}
+
+fn output_bucket(state: &State, output_buckets: usize) -> usize {
+    let pieces = state.board().board().occupied().count();
+    pieces.saturating_sub(2).min(31) * output_buckets / 32
+}
+
+// What follows the hidden layer is either the output heads, or a dense layer of
+// `NUMBER_L2` neurons (biases, then weights by neuron) with output heads of its own.
+// Returns whether there is a dense layer, and the number of output rows.
+fn output_layout(inputs: usize, weights: usize) -> Option<(bool, usize)> {
+    let rest = weights.checked_sub(NUMBER_HIDDEN * (inputs + 1))?;
+    let rows = rest / NUMBER_HIDDEN;
 
-    state.features_map(|idx| {
-        for (j, l) in hidden_layer.iter_mut().enumerate() {
-            *l += EVAL_HIDDEN_WEIGHTS[idx][j];
+    if rest % NUMBER_HIDDEN == 0 && is_supported_output_rows(rows) {
+        return Some((false, rows));
+    }
+
+    let rest = rest.checked_sub(NUMBER_L2 * (NUMBER_HIDDEN + 1))?;
+    let rows = rest / NUMBER_L2;
+
+    if rest % NUMBER_L2 == 0 && is_supported_output_rows(rows) {
+        Some((true, rows))
+    } else {
+        None
+    }
+}
+
+/// A dense layer between the hidden layer and the output heads, quantized to i8 with
+/// one scale per neuron.
+struct DenseLayer {
+    bias: [f32; NUMBER_L2],
+    weights: [[i8; NUMBER_HIDDEN]; NUMBER_L2],
+    scales: [f32; NUMBER_L2],
+    output_weights: Box<[[f32; NUMBER_L2]]>,
+}
+
+impl DenseLayer {
+    fn new(bias: &[f32], weights: &[f32], output_weights: &[f32]) -> Self {
+        let mut layer = Self {
+            bias: [0.; NUMBER_L2],
+            weights: [[0; NUMBER_HIDDEN]; NUMBER_L2],
+            scales: [0.; NUMBER_L2],
+            output_weights: vec![[0.; NUMBER_L2]; output_weights.len() / NUMBER_L2]
+                .into_boxed_slice(),
+        };
+
+        layer.bias.copy_from_slice(bias);
+
+        for (neuron, row) in weights.chunks_exact(NUMBER_HIDDEN).enumerate() {
+            let max_abs = row.iter().fold(0f32, |m, w| m.max(w.abs()));
+            let scale = if max_abs > 0. { max_abs / 127. } else { 1. };
+
+            layer.scales[neuron] = scale;
+
+            for (q, w) in layer.weights[neuron].iter_mut().zip(row) {
+                *q = (w / scale).round() as i8;
+            }
         }
-    });
 
-    let mut result = 0.;
-    let weights = EVAL_OUTPUT_WEIGHTS[0];
+        for (row, src) in layer
+            .output_weights
+            .iter_mut()
+            .zip(output_weights.chunks_exact(NUMBER_L2))
+        {
+            row.copy_from_slice(src);
+        }
 
-    for i in 0..hidden_layer.len() {
-        result += weights[i] * hidden_layer[i].max(0.);
+        layer
     }
 
-    result.tanh()
+    #[allow(clippy::cast_sign_loss)]
+    fn output(&self, hidden: &[f32; NUMBER_HIDDEN], row: usize) -> f32 {
+        // The activations are quantized against their largest value, so each neuron
+        // is a single integer dot product
+        let max = hidden.iter().fold(0f32, |m, h| m.max(*h));
+        let scale = if max > 0. { max / 255. } else { 1. };
+
+        let mut activations = [0u8; NUMBER_HIDDEN];
+        for (a, h) in activations.iter_mut().zip(hidden) {
+            *a = (h.max(0.) / scale).round() as u8;
+        }
+
+        let mut output = 0.;
+
+        for (neuron, w) in self.output_weights[row].iter().enumerate() {
+            let dot = math::dot_u8_i8(&activations, &self.weights[neuron]);
+            let x = dot as f32 * scale * self.scales[neuron] + self.bias[neuron];
+            output += x.max(0.) * w;
+        }
+
+        output
+    }
 }
 
-fn run_policy_net(state: &State, moves: &MoveList) -> Vec<f32> {
-    let mut evalns = Vec::with_capacity(moves.len());
+enum OutputLayer {
+    Linear(Box<[[f32; NUMBER_HIDDEN]]>),
+    Dense(Box<DenseLayer>),
+}
 
-    if moves.is_empty() {
-        return evalns;
+impl OutputLayer {
+    fn rows(&self) -> usize {
+        match self {
+            OutputLayer::Linear(weights) => weights.len(),
+            OutputLayer::Dense(layer) => layer.output_weights.len(),
+        }
+    }
+
+    fn output(&self, hidden: &[f32; NUMBER_HIDDEN], row: usize) -> f32 {
+        match self {
+            OutputLayer::Linear(weights) => math::relu_dot(hidden, &weights[row]),
+            OutputLayer::Dense(layer) => layer.output(hidden, row),
+        }
     }
+}
 
-    let mut move_idxs = Vec::with_capacity(moves.len());
+pub struct EvalNet {
+    id: usize,
+    features: FeatureSet,
+    king_buckets: usize,
+    hidden_bias: [f32; NUMBER_HIDDEN],
+    hidden_weights: Box<[[f32; NUMBER_HIDDEN]]>,
+    output: OutputLayer,
+    // Whether each head is win, draw and loss logits rather than a single value
+    wdl: bool,
+    curve: Option<EvalCurve>,
+}
 
-    for m in 0..moves.len() {
-        move_idxs.push(state.move_to_index(&moves[m]));
-        evalns.push(0.);
+/// A logistic fit of the expected score to classical centipawns, with separate
+/// scales for the side that is ahead and the side that is behind.
+#[derive(Clone, Copy, Debug)]
+pub struct EvalCurve {
+    win_scale: f32,
+    loss_scale: f32,
+}
+
+impl EvalCurve {
+    // The largest score reported, well clear of anything a static eval says
+    const MAX_CP: f32 = 10000.;
+
+    /// Converts an eval in [-1, 1] to centipawns, where an expected score `s` is
+    /// `scale * ln(s / (1 - s))` centipawns.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn to_cp(self, eval: f32) -> i64 {
+        let eval = eval.clamp(-0.9999, 0.9999);
+        let scale = if eval >= 0. {
+            self.win_scale
+        } else {
+            self.loss_scale
+        };
+        (2. * scale * eval.atanh())
+            .round()
+            .clamp(-Self::MAX_CP, Self::MAX_CP) as i64
     }
+}
 
-    state.features_map(|idx| {
-        for m in 0..moves.len() {
-            evalns[m] += POLICY_WEIGHTS[move_idxs[m]][idx];
+impl Default for EvalNet {
+    fn default() -> Self {
+        Self {
+            id: NEXT_NET_ID.fetch_add(1, Ordering::Relaxed),
+            features: FeatureSet::Standard,
+            king_buckets: 1,
+            hidden_bias: EVAL_HIDDEN_BIAS,
+            hidden_weights: EVAL_HIDDEN_WEIGHTS.to_vec().into_boxed_slice(),
+            output: OutputLayer::Linear(EVAL_OUTPUT_WEIGHTS.to_vec().into_boxed_slice()),
+            wdl: false,
+            curve: None,
         }
-    });
+    }
+}
+
+impl EvalNet {
+    fn output_buckets(&self) -> usize {
+        if self.wdl {
+            self.output.rows() / 3
+        } else {
+            self.output.rows()
+        }
+    }
+
+    // The feature set of a net with `inputs` inputs, a whole number of king buckets
+    // of it
+    fn feature_set(inputs: usize) -> Option<FeatureSet> {
+        [FeatureSet::Standard, FeatureSet::Extended]
+            .into_iter()
+            .find(|f| inputs % f.inputs() == 0 && is_supported_king_buckets(inputs / f.inputs()))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, NetError> {
+        let (inputs, weights) = read_net_file(path, EVAL_NET_MAGIC, |inputs, outputs, weights| {
+            outputs == NUMBER_HIDDEN
+                && Self::feature_set(inputs).is_some()
+                && has_eval_curve(inputs, weights).is_some()
+        })?;
+
+        let features = Self::feature_set(inputs).unwrap_or(FeatureSet::Standard);
+
+        let (weights, curve) = match has_eval_curve(inputs, weights.len()) {
+            Some(true) => {
+                let (weights, curve) = weights.split_at(weights.len() - EVAL_CURVE_PARAMS);
+                if curve.iter().any(|s| !s.is_finite() || *s <= 0.) {
+                    return Err(NetError::Curve(curve[0], curve[1]));
+                }
+                let curve = EvalCurve {
+                    win_scale: curve[0],
+                    loss_scale: curve[1],
+                };
+                (weights, Some(curve))
+            }
+            _ => (&weights[..], None),
+        };
+
+        let (hidden_bias, rest) = weights.split_at(NUMBER_HIDDEN);
+        let (hidden_weights, rest) = rest.split_at(NUMBER_HIDDEN * inputs);
+
+        let output = match output_layout(inputs, weights.len()) {
+            Some((true, _)) => {
+                let (bias, rest) = rest.split_at(NUMBER_L2);
+                let (weights, output_weights) = rest.split_at(NUMBER_L2 * NUMBER_HIDDEN);
+                OutputLayer::Dense(Box::new(DenseLayer::new(bias, weights, output_weights)))
+            }
+            _ => {
+                let mut output_weights =
+                    vec![[0.; NUMBER_HIDDEN]; rest.len() / NUMBER_HIDDEN].into_boxed_slice();
+                for (row, src) in output_weights
+                    .iter_mut()
+                    .zip(rest.chunks_exact(NUMBER_HIDDEN))
+                {
+                    row.copy_from_slice(src);
+                }
+                OutputLayer::Linear(output_weights)
+            }
+        };
+
+        let mut net = Self {
+            id: NEXT_NET_ID.fetch_add(1, Ordering::Relaxed),
+            features,
+            king_buckets: inputs / features.inputs(),
+            hidden_bias: [0.; NUMBER_HIDDEN],
+            hidden_weights: vec![[0.; NUMBER_HIDDEN]; inputs].into_boxed_slice(),
+            wdl: output.rows() % 3 == 0,
+            output,
+            curve,
+        };
+
+        net.hidden_bias.copy_from_slice(hidden_bias);
+        for (row, src) in net
+            .hidden_weights
+            .iter_mut()
+            .zip(hidden_weights.chunks_exact(NUMBER_HIDDEN))
+        {
+            row.copy_from_slice(src);
+        }
+
+        Ok(net)
+    }
+}
+
+pub fn set_eval_file<P: AsRef<Path>>(path: P) -> Result<(), NetError> {
+    let net = EvalNet::from_file(path)?;
+    EVAL_NET.store(Arc::new(net));
+    eval_cache::clear();
+    Ok(())
+}
+
+/// The centipawn curve of the eval net in use, if its file has one.
+pub fn eval_curve() -> Option<EvalCurve> {
+    EVAL_NET.load().curve
+}
+
+/// The policy net, quantized to i8 with one scale per move index and stored transposed,
+/// so each active feature adds one contiguous row into an i16 accumulator.
+pub struct PolicyNet {
+    weights: Box<[[i8; state::NUMBER_MOVE_IDX]]>,
+    scales: [f32; state::NUMBER_MOVE_IDX],
+}
+
+impl Default for PolicyNet {
+    fn default() -> Self {
+        Self::quantize(&POLICY_WEIGHTS)
+    }
+}
+
+impl PolicyNet {
+    fn from_weights(weights: &[f32]) -> Self {
+        let mut rows = vec![[0.; POLICY_NUMBER_INPUTS]; state::NUMBER_MOVE_IDX];
+
+        for (row, src) in rows
+            .iter_mut()
+            .zip(weights.chunks_exact(POLICY_NUMBER_INPUTS))
+        {
+            row.copy_from_slice(src);
+        }
+
+        Self::quantize(&rows)
+    }
+
+    fn quantize(weights: &[[f32; POLICY_NUMBER_INPUTS]]) -> Self {
+        let mut net = Self {
+            weights: vec![[0; state::NUMBER_MOVE_IDX]; POLICY_NUMBER_INPUTS].into_boxed_slice(),
+            scales: [0.; state::NUMBER_MOVE_IDX],
+        };
+
+        for (move_idx, row) in weights.iter().enumerate() {
+            let max_abs = row.iter().fold(0f32, |m, w| m.max(w.abs()));
+            let scale = if max_abs > 0. { max_abs / 127. } else { 1. };
+
+            net.scales[move_idx] = scale;
+
+            for (feature, w) in row.iter().enumerate() {
+                net.weights[feature][move_idx] = (w / scale).round() as i8;
+            }
+        }
+
+        net
+    }
+}
+
+/// Policy nets either read the state features directly, or the eval net's hidden layer
+/// with one row of weights per move index.
+enum Policy {
+    Features(PolicyNet),
+    Hidden(Box<[[f32; NUMBER_HIDDEN]]>),
+}
+
+impl Policy {
+    fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, NetError> {
+        let (inputs, weights) =
+            read_net_file(path, POLICY_NET_MAGIC, |inputs, outputs, weights| {
+                (inputs == POLICY_NUMBER_INPUTS || inputs == NUMBER_HIDDEN)
+                    && outputs == state::NUMBER_MOVE_IDX
+                    && weights == inputs * outputs
+            })?;
+
+        if inputs == POLICY_NUMBER_INPUTS {
+            return Ok(Policy::Features(PolicyNet::from_weights(&weights)));
+        }
+
+        let mut rows = vec![[0.; NUMBER_HIDDEN]; state::NUMBER_MOVE_IDX].into_boxed_slice();
+
+        for (row, src) in rows.iter_mut().zip(weights.chunks_exact(NUMBER_HIDDEN)) {
+            row.copy_from_slice(src);
+        }
+
+        Ok(Policy::Hidden(rows))
+    }
+}
+
+pub fn set_policy_file<P: AsRef<Path>>(path: P) -> Result<(), NetError> {
+    let net = Policy::from_file(path)?;
+    POLICY_NET.store(Arc::new(net));
+    Ok(())
+}
+
+/// The eval and policy nets in use, kept to switch back to them with `use_nets`.
+#[derive(Clone)]
+pub struct Nets {
+    eval: Arc<EvalNet>,
+    policy: Arc<Policy>,
+}
+
+pub fn current_nets() -> Nets {
+    Nets {
+        eval: EVAL_NET.load_full(),
+        policy: POLICY_NET.load_full(),
+    }
+}
+
+pub fn use_nets(nets: &Nets) {
+    if !Arc::ptr_eq(&EVAL_NET.load(), &nets.eval) {
+        EVAL_NET.store(nets.eval.clone());
+        eval_cache::clear();
+    }
+    POLICY_NET.store(nets.policy.clone());
+}
+
+/// Evaluates positions for the search, returning the expected score in [-1, 1] for the
+/// side to move and the draw probability.
+pub trait Evaluator: Send + Sync {
+    fn evaluate(&self, state: &State, accumulators: &mut Accumulators) -> (f32, f32);
+
+    /// Evaluates several positions, in order. Evaluators with a cost per call rather
+    /// than per position pay it once for the whole batch.
+    fn evaluate_batch(
+        &self,
+        states: &[&State],
+        accumulators: &mut Accumulators,
+    ) -> Vec<(f32, f32)> {
+        states
+            .iter()
+            .map(|state| self.evaluate(state, accumulators))
+            .collect()
+    }
+}
+
+/// The eval net, run on the CPU with the searching thread's accumulators.
+pub struct NetEvaluator;
+
+impl Evaluator for NetEvaluator {
+    fn evaluate(&self, state: &State, accumulators: &mut Accumulators) -> (f32, f32) {
+        accumulators.evaluate(state)
+    }
+}
+
+/// Sends positions to an evaluation server as FEN lines, and reads back lines holding
+/// the score for the side to move, optionally followed by the draw probability.
+/// A batch is sent in one write and its replies read back in order, so it takes a
+/// single round trip. Connections are pooled, so each searching thread ends up with
+/// its own.
+pub struct SocketEvaluator {
+    addr: String,
+    connections: Mutex<Vec<BufReader<TcpStream>>>,
+    created: Instant,
+    // Failures in a row, and the time since `created` in ms until which the server
+    // is left alone after the last one, or zero
+    failures: AtomicU32,
+    retry_at_ms: AtomicU64,
+}
+
+// How long to wait on the eval server before falling back to the eval net
+const SERVER_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const SERVER_IO_TIMEOUT: Duration = Duration::from_secs(1);
+// After a failure the server isn't tried for a while, doubling with each failure in
+// a row up to the maximum
+const SERVER_MIN_BACKOFF_MS: u64 = 100;
+const SERVER_MAX_BACKOFF_MS: u64 = 10_000;
+
+impl SocketEvaluator {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let connection = Self::open(addr)?;
+
+        Ok(Self {
+            addr: addr.to_string(),
+            connections: Mutex::new(vec![connection]),
+            created: Instant::now(),
+            failures: AtomicU32::new(0),
+            retry_at_ms: AtomicU64::new(0),
+        })
+    }
+
+    fn open(addr: &str) -> io::Result<BufReader<TcpStream>> {
+        let mut last_error =
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+
+        for socket_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, SERVER_CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(SERVER_IO_TIMEOUT))?;
+                    stream.set_write_timeout(Some(SERVER_IO_TIMEOUT))?;
+                    return Ok(BufReader::new(stream));
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+
+    fn is_backing_off(&self) -> bool {
+        let retry_at_ms = self.retry_at_ms.load(Ordering::Relaxed);
+        retry_at_ms != 0 && self.elapsed_ms() < retry_at_ms
+    }
+
+    // Only the first failure in a row is logged, so a server that is down doesn't
+    // flood the log
+    fn record_failure(&self, e: &io::Error) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed);
+        let backoff_ms = (SERVER_MIN_BACKOFF_MS << failures.min(16)).min(SERVER_MAX_BACKOFF_MS);
+        self.retry_at_ms
+            .store(self.elapsed_ms() + backoff_ms, Ordering::Relaxed);
+
+        if failures == 0 {
+            warn!(
+                "Eval server failed, using the eval net until it is back: {:?}",
+                e
+            );
+        }
+    }
+
+    fn record_success(&self) {
+        if self.failures.swap(0, Ordering::Relaxed) != 0 {
+            self.retry_at_ms.store(0, Ordering::Relaxed);
+            info!("Eval server is back");
+        }
+    }
+
+    fn query(&self, states: &[&State]) -> io::Result<Vec<(f32, f32)>> {
+        let pooled = self.connections.lock().unwrap().pop();
+        let mut connection = match pooled {
+            Some(c) => c,
+            None => Self::open(&self.addr)?,
+        };
+
+        let request: String = states
+            .iter()
+            .map(|state| format!("{}\n", Fen::from_setup(state.board())))
+            .collect();
+        connection.get_mut().write_all(request.as_bytes())?;
+
+        let replies = states
+            .iter()
+            .map(|_| Self::read_reply(&mut connection))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Only connections that worked go back in the pool
+        self.connections.lock().unwrap().push(connection);
+
+        Ok(replies)
+    }
+
+    fn read_reply(connection: &mut BufReader<TcpStream>) -> io::Result<(f32, f32)> {
+        let mut line = String::new();
+        connection.read_line(&mut line)?;
+
+        let mut values = line.split_whitespace().map(str::parse::<f32>);
+
+        let q = match values.next() {
+            Some(Ok(q)) => q.max(-1.).min(1.),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "bad reply from eval server",
+                ))
+            }
+        };
+        let draw = match values.next() {
+            Some(Ok(draw)) => draw.max(0.).min(1.),
+            _ => draw_estimate(q),
+        };
+
+        Ok((q, draw))
+    }
+}
+
+impl Evaluator for SocketEvaluator {
+    fn evaluate(&self, state: &State, accumulators: &mut Accumulators) -> (f32, f32) {
+        self.evaluate_batch(&[state], accumulators)[0]
+    }
+
+    fn evaluate_batch(
+        &self,
+        states: &[&State],
+        accumulators: &mut Accumulators,
+    ) -> Vec<(f32, f32)> {
+        if !self.is_backing_off() {
+            match self.query(states) {
+                Ok(evals) => {
+                    self.record_success();
+                    return evals;
+                }
+                Err(e) => self.record_failure(&e),
+            }
+        }
+
+        states
+            .iter()
+            .map(|state| accumulators.evaluate(state))
+            .collect()
+    }
+}
+
+pub fn set_evaluator(evaluator: Box<dyn Evaluator>) {
+    EVALUATOR.store(Arc::new(evaluator));
+    eval_cache::clear();
+}
+
+// Incremental updates accumulate float error, so refresh from scratch now and then
+const ACCUMULATOR_REFRESH_INTERVAL: usize = 64;
+
+struct Accumulator {
+    features: Vec<usize>,
+    hidden: [f32; NUMBER_HIDDEN],
+    updates: usize,
+}
+
+impl Accumulator {
+    fn new(features: Vec<usize>, net: &EvalNet) -> Self {
+        let mut hidden = net.hidden_bias;
+
+        for &idx in &features {
+            math::add_to_all(&mut hidden, &net.hidden_weights[idx]);
+        }
+
+        Self {
+            features,
+            hidden,
+            updates: 0,
+        }
+    }
+
+    fn update(&mut self, features: Vec<usize>, net: &EvalNet) {
+        let old = &self.features;
+        let new = &features;
+
+        // Both feature lists are sorted, so a merge walk yields the difference
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() || j < new.len() {
+            if j == new.len() || (i < old.len() && old[i] < new[j]) {
+                math::sub_from_all(&mut self.hidden, &net.hidden_weights[old[i]]);
+                i += 1;
+            } else if i == old.len() || new[j] < old[i] {
+                math::add_to_all(&mut self.hidden, &net.hidden_weights[new[j]]);
+                j += 1;
+            } else {
+                i += 1;
+                j += 1;
+            }
+        }
+
+        self.features = features;
+        self.updates += 1;
+    }
+
+    /// Returns the expected score in [-1, 1] and the draw probability.
+    fn output(&self, net: &EvalNet, output_bucket: usize) -> (f32, f32) {
+        if !net.wdl {
+            let q = net.output.output(&self.hidden, output_bucket).tanh();
+            return (q, draw_estimate(q));
+        }
+
+        let mut wdl = [0.; 3];
+        for (i, x) in wdl.iter_mut().enumerate() {
+            *x = net.output.output(&self.hidden, 3 * output_bucket + i);
+        }
+        math::softmax(&mut wdl);
+
+        (wdl[0] - wdl[2], wdl[1])
+    }
+}
+
+/// Per-thread eval net accumulators, one per feature and king bucket, each updated
+/// incrementally from the last position evaluated in that bucket. Consecutive playouts
+/// mostly share their path, so only a handful of features change between evaluations.
+#[derive(Default)]
+pub struct Accumulators {
+    net_id: usize,
+    buckets: Vec<Option<Accumulator>>,
+}
+
+impl Accumulators {
+    fn evaluate(&mut self, state: &State) -> (f32, f32) {
+        let net = EVAL_NET.load();
+        let output_bucket = output_bucket(state, net.output_buckets());
+
+        self.accumulator(state, &net).output(&net, output_bucket)
+    }
+
+    /// Brings the accumulator for `state` up to date with it and returns it.
+    fn accumulator(&mut self, state: &State, net: &EvalNet) -> &Accumulator {
+        if net.id != self.net_id {
+            self.net_id = net.id;
+            self.buckets = (0..4 * net.king_buckets).map(|_| None).collect();
+        }
+
+        let king_bucket = king_bucket(state.relative_king_square(), net.king_buckets);
+        let offset = king_bucket * net.features.inputs();
+
+        let mut features = Vec::with_capacity(64);
+        state.feature_set_map(net.features, |idx| features.push(offset + idx));
+        features.sort_unstable();
+
+        let slot = &mut self.buckets[state.feature_bucket() * net.king_buckets + king_bucket];
+
+        let refresh = slot
+            .as_ref()
+            .map_or(true, |acc| acc.updates >= ACCUMULATOR_REFRESH_INTERVAL);
+
+        if refresh {
+            slot.insert(Accumulator::new(features, net))
+        } else {
+            let acc = slot.as_mut().unwrap();
+            acc.update(features, net);
+            acc
+        }
+    }
+}
+
+fn run_policy_net(state: &State, moves: &MoveList, accumulators: &mut Accumulators) -> Vec<f32> {
+    if moves.is_empty() {
+        return Vec::new();
+    }
+
+    let mut evalns = match &**POLICY_NET.load() {
+        Policy::Features(net) => run_feature_policy(net, state, moves),
+        Policy::Hidden(weights) => {
+            let acc = accumulators.accumulator(state, &EVAL_NET.load());
+            moves
+                .iter()
+                .map(|m| math::relu_dot(&acc.hidden, &weights[state.move_to_index(m)]))
+                .collect()
+        }
+    };
+
+    // Kept to soften the policy again from, if it turns out too confident
+    let entropy_scale = get_policy_entropy_scale();
+    let logits = (entropy_scale > 0. && evalns.len() > 1).then(|| evalns.clone());
+
+    let temperature = get_policy_temperature();
+    for e in &mut evalns {
+        *e /= temperature;
+    }
 
     math::softmax(&mut evalns);
 
+    // Confident policies are softened further, in proportion to how far their
+    // entropy is below that of a uniform policy
+    if let Some(logits) = logits {
+        let sharpness = 1. - math::entropy(&evalns) / (evalns.len() as f32).ln();
+        let temperature = temperature * (1. + entropy_scale * sharpness);
+
+        for (e, logit) in evalns.iter_mut().zip(logits) {
+            *e = logit / temperature;
+        }
+
+        math::softmax(&mut evalns);
+    }
+
+    boost_tactics(state, moves, &mut evalns);
+
     evalns
 }
+
+/// Multiplies the policy of checks, captures of more valuable pieces and queen
+/// promotions by their boosts, which the small policy nets tend to underrate,
+/// and renormalizes.
+#[allow(clippy::float_cmp)]
+fn boost_tactics(state: &State, moves: &MoveList, policy: &mut [f32]) {
+    let check = get_policy_check_boost();
+    let capture = get_policy_capture_boost();
+    let promotion = get_policy_promotion_boost();
+
+    if check == 1. && capture == 1. && promotion == 1. {
+        return;
+    }
+
+    for (p, mov) in policy.iter_mut().zip(moves) {
+        if State::is_winning_capture(mov) {
+            *p *= capture;
+        }
+        if State::is_queen_promotion(mov) {
+            *p *= promotion;
+        }
+        if check != 1. && state.gives_check(mov) {
+            *p *= check;
+        }
+    }
+
+    let total: f32 = policy.iter().sum();
+    for p in policy.iter_mut() {
+        *p /= total;
+    }
+}
+
+fn run_feature_policy(net: &PolicyNet, state: &State, moves: &MoveList) -> Vec<f32> {
+    // At most 65 active features of magnitude <= 127 each, well within i16
+    let mut acc = [0i16; state::NUMBER_MOVE_IDX];
+
+    state.features_map(|idx| math::add_i8_to_all(&mut acc, &net.weights[idx]));
+
+    moves
+        .iter()
+        .map(|m| {
+            let move_idx = state.move_to_index(m);
+            f32::from(acc[move_idx]) * net.scales[move_idx]
+        })
+        .collect()
+}
@@ -0,0 +1,425 @@
+//! Plays games between two configurations of the engine, for checking a change
+//! without setting up an external match runner.
+
+use instant::Instant;
+use shakmaty::{Color, Outcome, Position};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::time::Duration;
+
+use crate::engine::{Engine, EngineOptions, Limits};
+use crate::evaluation::{current_nets, use_nets, Nets};
+use crate::options::find_tunable;
+use crate::output;
+use crate::state::State;
+
+pub const DEFAULT_MATCH_GAMES: usize = 100;
+
+const DEFAULT_BASE_MS: u64 = 10_000;
+const DEFAULT_INCREMENT_MS: u64 = 100;
+
+// Games still going after this many plies are adjudicated as draws
+const MAX_PLIES: usize = 400;
+
+const SPRT_ALPHA: f64 = 0.05;
+const SPRT_BETA: f64 = 0.05;
+
+// Options that load nets. Each side's nets are loaded once and switched between
+// moves instead of being read again.
+const NET_OPTIONS: [&str; 2] = ["evalfile", "policyfile"];
+
+#[derive(Clone, Copy, Debug)]
+pub enum TimeControl {
+    Clock { base: Duration, increment: Duration },
+    Nodes(usize),
+}
+
+/// A match between engines A and B, which differ in the UCI options set for each.
+#[derive(Clone, Debug)]
+pub struct MatchOptions {
+    pub games: usize,
+    pub time_control: TimeControl,
+    // A file of FENs, one per line, each played twice with colors swapped
+    pub openings: Option<String>,
+    pub elo0: f64,
+    pub elo1: f64,
+    pub options_a: Vec<(String, String)>,
+    pub options_b: Vec<(String, String)>,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            games: DEFAULT_MATCH_GAMES,
+            time_control: TimeControl::Clock {
+                base: Duration::from_millis(DEFAULT_BASE_MS),
+                increment: Duration::from_millis(DEFAULT_INCREMENT_MS),
+            },
+            openings: None,
+            elo0: 0.,
+            elo1: 5.,
+            options_a: Vec::new(),
+            options_b: Vec::new(),
+        }
+    }
+}
+
+impl MatchOptions {
+    /// Reads `key=value` arguments: `games`, `tc` (seconds + increment, as `10+0.1`),
+    /// `nodes`, `openings`, `elo0` and `elo1`, and UCI options for either engine as
+    /// `a.<name>=<value>` or `b.<name>=<value>`.
+    ///
+    /// # Errors
+    ///
+    /// Fails on an argument that isn't `key=value` or a value that doesn't parse.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        for arg in args {
+            let (key, value) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{arg}'"))?;
+            let bad_value = || format!("Bad value for {key}: '{value}'");
+
+            match key.to_lowercase().as_str() {
+                "games" => options.games = value.parse().map_err(|_| bad_value())?,
+                "tc" => options.time_control = parse_clock(value).ok_or_else(bad_value)?,
+                "nodes" => {
+                    options.time_control =
+                        TimeControl::Nodes(value.parse().map_err(|_| bad_value())?);
+                }
+                "openings" => options.openings = Some(value.to_owned()),
+                "elo0" => options.elo0 = value.parse().map_err(|_| bad_value())?,
+                "elo1" => options.elo1 = value.parse().map_err(|_| bad_value())?,
+                key => match key.split_once('.') {
+                    Some(("a", name)) => options.options_a.push((name.into(), value.into())),
+                    Some(("b", name)) => options.options_b.push((name.into(), value.into())),
+                    _ => return Err(format!("Unknown match setting: {key}")),
+                },
+            }
+        }
+
+        if options.elo1 <= options.elo0 {
+            return Err("elo1 must be above elo0".into());
+        }
+
+        balance_options(&mut options.options_a, &options.options_b)?;
+        balance_options(&mut options.options_b, &options.options_a)?;
+
+        Ok(options)
+    }
+}
+
+fn parse_clock(tc: &str) -> Option<TimeControl> {
+    let (base, increment) = tc.split_once('+').unwrap_or((tc, "0"));
+
+    Some(TimeControl::Clock {
+        base: Duration::try_from_secs_f64(base.parse().ok()?).ok()?,
+        increment: Duration::try_from_secs_f64(increment.parse().ok()?).ok()?,
+    })
+}
+
+// Options are process wide, so an option set for only the other engine has to be
+// put back for this one. Tunables go back to their defaults, and nets to the ones
+// loaded at the start; anything else must be given for both.
+fn balance_options(
+    options: &mut Vec<(String, String)>,
+    other: &[(String, String)],
+) -> Result<(), String> {
+    for (name, _) in other {
+        if NET_OPTIONS.contains(&name.as_str()) || options.iter().any(|(n, _)| n == name) {
+            continue;
+        }
+
+        match find_tunable(name) {
+            Some(t) => options.push((name.clone(), t.default.to_string())),
+            None => return Err(format!("{name} must be set for both engines")),
+        }
+    }
+
+    Ok(())
+}
+
+struct Player {
+    name: &'static str,
+    options: Vec<(String, String)>,
+    nets: Nets,
+}
+
+impl Player {
+    fn new(name: &'static str, options: &[(String, String)], engine: &Engine, base: &Nets) -> Self {
+        use_nets(base);
+        for (n, v) in options {
+            engine.set_option(n, v);
+        }
+
+        Self {
+            name,
+            options: options.to_vec(),
+            nets: current_nets(),
+        }
+    }
+
+    fn make_current(&self, engine: &Engine) {
+        use_nets(&self.nets);
+        for (n, v) in &self.options {
+            if !NET_OPTIONS.contains(&n.as_str()) {
+                engine.set_option(n, v);
+            }
+        }
+    }
+}
+
+/// Counts of game pairs by A's points over the pair, 0 to 2 in half points.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Pentanomial([usize; 5]);
+
+impl Pentanomial {
+    #[must_use]
+    pub fn pairs(&self) -> usize {
+        self.0.iter().sum()
+    }
+
+    // Mean and variance of A's score per pair, from 0 to 1
+    fn mean_and_variance(&self) -> (f64, f64) {
+        let pairs = self.pairs().max(1) as f64;
+        let score = |i: usize| i as f64 / 4.;
+
+        let mean = (0..5).map(|i| score(i) * self.0[i] as f64).sum::<f64>() / pairs;
+        let variance = (0..5)
+            .map(|i| (score(i) - mean).powi(2) * self.0[i] as f64)
+            .sum::<f64>()
+            / pairs;
+
+        (mean, variance)
+    }
+
+    /// The Elo difference of A over B, with the half width of its 95% interval.
+    #[must_use]
+    pub fn elo(&self) -> (f64, f64) {
+        let (mean, variance) = self.mean_and_variance();
+        let error = 1.96 * (variance / self.pairs().max(1) as f64).sqrt();
+
+        let elo = score_to_elo(mean);
+        let upper = score_to_elo(mean + error);
+        let lower = score_to_elo(mean - error);
+
+        (elo, (upper - lower) / 2.)
+    }
+
+    /// The log likelihood ratio of A being `elo1` rather than `elo0` stronger.
+    #[must_use]
+    pub fn llr(&self, elo0: f64, elo1: f64) -> f64 {
+        let (mean, variance) = self.mean_and_variance();
+
+        if variance <= 0. {
+            return 0.;
+        }
+
+        let s0 = elo_to_score(elo0);
+        let s1 = elo_to_score(elo1);
+
+        (s1 - s0) * (2. * mean - s0 - s1) / (2. * variance) * self.pairs() as f64
+    }
+}
+
+fn elo_to_score(elo: f64) -> f64 {
+    1. / (1. + 10f64.powf(-elo / 400.))
+}
+
+fn score_to_elo(score: f64) -> f64 {
+    let score = score.clamp(1e-6, 1. - 1e-6);
+    -400. * (1. / score - 1.).log10()
+}
+
+fn sprt_bounds() -> (f64, f64) {
+    (
+        (SPRT_BETA / (1. - SPRT_ALPHA)).ln(),
+        ((1. - SPRT_BETA) / SPRT_ALPHA).ln(),
+    )
+}
+
+fn game_over(state: &State, plies: usize) -> Option<Outcome> {
+    if let Some(outcome) = state.board().outcome() {
+        return Some(outcome);
+    }
+
+    if state.repetition_count() >= 2 || state.drawn_by_fifty_move_rule() || plies >= MAX_PLIES {
+        return Some(Outcome::Draw);
+    }
+
+    None
+}
+
+fn play_game(
+    engine: &Engine,
+    opening: Option<&str>,
+    white: &Player,
+    black: &Player,
+    time_control: TimeControl,
+) -> Outcome {
+    let mut moves: Vec<String> = Vec::new();
+    let mut clocks = match time_control {
+        TimeControl::Clock { base, .. } => [base, base],
+        TimeControl::Nodes(_) => [Duration::ZERO; 2],
+    };
+
+    loop {
+        let move_refs: Vec<&str> = moves.iter().map(String::as_str).collect();
+        let state = State::from_fen_and_moves(opening, &move_refs).expect("legal moves");
+
+        if let Some(outcome) = game_over(&state, moves.len()) {
+            return outcome;
+        }
+
+        let stm = state.side_to_move();
+        let player = stm.fold_wb(white, black);
+        let clock = &mut clocks[stm.fold_wb(0, 1)];
+
+        player.make_current(engine);
+        // Can't fail, as the same position was just built
+        let _ = engine.set_position(opening, &move_refs);
+
+        let limits = match time_control {
+            TimeControl::Clock { increment, .. } => Limits {
+                remaining: Some(*clock),
+                increment,
+                ..Limits::default()
+            },
+            TimeControl::Nodes(nodes) => Limits {
+                nodes: Some(nodes),
+                ..Limits::default()
+            },
+        };
+
+        let start = Instant::now();
+        let result = engine.go(limits);
+
+        if let TimeControl::Clock { increment, .. } = time_control {
+            match clock.checked_sub(start.elapsed()) {
+                Some(left) => *clock = left + increment,
+                None => {
+                    info!("{} lost on time", player.name);
+                    return Outcome::Decisive { winner: !stm };
+                }
+            }
+        }
+
+        let is_legal = |mov: &String| {
+            let mut next = move_refs.clone();
+            next.push(mov);
+            State::from_fen_and_moves(opening, &next).is_some()
+        };
+
+        match result.best_move {
+            Some(mov) if is_legal(&mov) => moves.push(mov),
+            mov => {
+                error!("{} played an illegal move: {:?}", player.name, mov);
+                return Outcome::Decisive { winner: !stm };
+            }
+        }
+    }
+}
+
+fn read_openings(path: &str) -> io::Result<Vec<String>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut openings = Vec::new();
+
+    for line in file.lines() {
+        let line = line?;
+        let fen = line.trim();
+
+        if fen.is_empty() {
+            continue;
+        }
+
+        if State::from_fen_and_moves(Some(fen), &[]).is_some() {
+            openings.push(fen.to_owned());
+        } else {
+            error!("Bad opening: {}", fen);
+        }
+    }
+
+    Ok(openings)
+}
+
+// A's points for the game in half points
+fn half_points(outcome: Outcome, a_color: Color) -> usize {
+    match outcome {
+        Outcome::Draw => 1,
+        Outcome::Decisive { winner } if winner == a_color => 2,
+        Outcome::Decisive { .. } => 0,
+    }
+}
+
+/// Plays game pairs between A and B, each opening once with either color, printing
+/// the score and SPRT status after each pair. Stops at the number of games or once
+/// the SPRT accepts either hypothesis.
+///
+/// # Errors
+///
+/// Fails if the openings file can't be read.
+pub fn run(options: &MatchOptions) -> io::Result<()> {
+    let openings = match &options.openings {
+        Some(path) => read_openings(path)?,
+        None => Vec::new(),
+    };
+
+    let engine = Engine::new(EngineOptions::default());
+    let base = current_nets();
+    let a = Player::new("A", &options.options_a, &engine, &base);
+    let b = Player::new("B", &options.options_b, &engine, &base);
+
+    let mut ptnml = Pentanomial::default();
+    let mut wdl = [0; 3];
+    let (lower, upper) = sprt_bounds();
+    let mut llr = 0.;
+
+    for pair in 0..(options.games + 1) / 2 {
+        let opening = (!openings.is_empty()).then(|| openings[pair % openings.len()].as_str());
+
+        let first = play_game(&engine, opening, &a, &b, options.time_control);
+        let second = play_game(&engine, opening, &b, &a, options.time_control);
+
+        let points = [
+            half_points(first, Color::White),
+            half_points(second, Color::Black),
+        ];
+        for p in points {
+            wdl[2 - p] += 1;
+        }
+        ptnml.0[points[0] + points[1]] += 1;
+
+        let (elo, error) = ptnml.elo();
+        llr = ptnml.llr(options.elo0, options.elo1);
+
+        output!(
+            "Games {}: A +{} -{} ={}, Elo {:.1} +/- {:.1}, LLR {:.2} ({:.2}, {:.2}), Ptnml {:?}",
+            ptnml.pairs() * 2,
+            wdl[0],
+            wdl[2],
+            wdl[1],
+            elo,
+            error,
+            llr,
+            lower,
+            upper,
+            ptnml.0
+        );
+
+        if llr <= lower || llr >= upper {
+            break;
+        }
+    }
+
+    if llr >= upper {
+        output!("SPRT: H1 accepted, A is stronger");
+    } else if llr <= lower {
+        output!("SPRT: H0 accepted, A is not stronger");
+    } else {
+        output!("SPRT: inconclusive");
+    }
+
+    output::flush();
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Princhess, a chess engine searching with MCTS guided by neural nets.
+//!
+//! Besides the UCI binary, the engine can be embedded through `engine::Engine`.
+
+#![warn(clippy::all, clippy::pedantic)]
+#![allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+
+#[macro_use]
+extern crate log;
+extern crate arc_swap;
+extern crate arrayvec;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate core_affinity;
+extern crate fastapprox;
+extern crate instant;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate memmap;
+extern crate once_cell;
+extern crate rand;
+#[cfg(feature = "lichess")]
+extern crate serde_json;
+extern crate shakmaty;
+#[cfg(feature = "syzygy")]
+extern crate shakmaty_syzygy;
+#[cfg(feature = "lichess")]
+extern crate ureq;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+
+#[macro_use]
+mod output;
+
+mod arena;
+mod math;
+mod mcts;
+mod move_code;
+mod numa;
+mod options;
+mod search_tree;
+mod tablebase;
+mod transposition_table;
+mod tree_policy;
+
+pub mod analyze;
+pub mod annotate;
+pub mod bench;
+mod book;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod convert;
+#[cfg(not(target_arch = "wasm32"))]
+mod datafmt;
+pub mod engine;
+pub mod epd;
+mod eval_cache;
+mod evaluation;
+pub mod game_manager;
+#[cfg(feature = "lichess")]
+pub mod lichess;
+mod search;
+mod state;
+mod strength;
+mod telemetry;
+mod threadpool;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod training;
+pub mod uci;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,340 @@
+//! Plays on Lichess as a bot account through the Bot API, using the API token in
+//! `LICHESS_TOKEN`. One game is played at a time; challenges arriving meanwhile are
+//! declined.
+
+use serde_json::Value;
+use shakmaty::Color;
+use std::env;
+use std::io::{self, BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::engine::{Engine, EngineOptions, Limits};
+use crate::options::{get_hash_size_mb, get_num_threads};
+use crate::state::State;
+use crate::uci;
+
+const API_URL: &str = "https://lichess.org/api";
+const TOKEN_VAR: &str = "LICHESS_TOKEN";
+
+const DEFAULT_MIN_TIME_SECS: u64 = 60;
+const DEFAULT_MAX_TIME_SECS: u64 = 1800;
+
+#[derive(Debug)]
+pub enum Error {
+    Token,
+    Io(io::Error),
+    Http(Box<ureq::Error>),
+}
+
+impl From<ureq::Error> for Error {
+    fn from(e: ureq::Error) -> Self {
+        Self::Http(Box::new(e))
+    }
+}
+
+/// Which challenges to accept, and the UCI options to play with.
+#[derive(Clone, Debug)]
+pub struct BotOptions {
+    // Lichess variant keys, such as standard or chess960
+    pub variants: Vec<String>,
+    // Bounds on the initial clock time
+    pub min_time: Duration,
+    pub max_time: Duration,
+    // Only rated or only casual games, or either for `None`
+    pub rated: Option<bool>,
+    pub engine_options: Vec<(String, String)>,
+}
+
+impl Default for BotOptions {
+    fn default() -> Self {
+        Self {
+            variants: vec!["standard".into()],
+            min_time: Duration::from_secs(DEFAULT_MIN_TIME_SECS),
+            max_time: Duration::from_secs(DEFAULT_MAX_TIME_SECS),
+            rated: None,
+            engine_options: Vec::new(),
+        }
+    }
+}
+
+impl BotOptions {
+    /// Reads `key=value` arguments: `variants` (comma separated), `min_time` and
+    /// `max_time` in seconds, and `rated` (true, false or any). Anything else is
+    /// taken as a UCI option.
+    ///
+    /// # Errors
+    ///
+    /// Fails on an argument that isn't `key=value` or a value that doesn't parse.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        for arg in args {
+            let (key, value) = arg
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{arg}'"))?;
+            let bad_value = || format!("Bad value for {key}: '{value}'");
+
+            match key {
+                "variants" => options.variants = value.split(',').map(String::from).collect(),
+                "min_time" => {
+                    options.min_time = Duration::from_secs(value.parse().map_err(|_| bad_value())?);
+                }
+                "max_time" => {
+                    options.max_time = Duration::from_secs(value.parse().map_err(|_| bad_value())?);
+                }
+                "rated" => {
+                    options.rated = match value {
+                        "any" => None,
+                        v => Some(v.parse().map_err(|_| bad_value())?),
+                    };
+                }
+                name => options.engine_options.push((name.into(), value.into())),
+            }
+        }
+
+        Ok(options)
+    }
+
+    // A Lichess decline reason if the challenge doesn't pass the filters
+    fn decline_reason(&self, challenge: &Value) -> Option<&'static str> {
+        let variant = challenge["variant"]["key"].as_str().unwrap_or_default();
+        if !self.variants.iter().any(|v| v == variant) {
+            return Some("variant");
+        }
+
+        let time_control = &challenge["timeControl"];
+        if time_control["type"].as_str() != Some("clock") {
+            return Some("timeControl");
+        }
+
+        let initial = Duration::from_secs(time_control["limit"].as_u64().unwrap_or_default());
+        if initial < self.min_time {
+            return Some("tooFast");
+        }
+        if initial > self.max_time {
+            return Some("tooSlow");
+        }
+
+        match (self.rated, challenge["rated"].as_bool()) {
+            (Some(true), Some(false)) => Some("rated"),
+            (Some(false), Some(true)) => Some("casual"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    agent: ureq::Agent,
+    authorization: String,
+}
+
+impl Client {
+    fn new(token: &str) -> Self {
+        Self {
+            agent: ureq::Agent::new(),
+            authorization: format!("Bearer {token}"),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<Value, Error> {
+        let response = self
+            .agent
+            .get(&format!("{API_URL}{path}"))
+            .set("Authorization", &self.authorization)
+            .call()?;
+
+        serde_json::from_reader(response.into_reader()).map_err(|e| Error::Io(e.into()))
+    }
+
+    // Reads a stream of JSON lines, skipping the empty lines sent to keep it alive
+    fn stream(&self, path: &str) -> Result<impl Iterator<Item = Value>, Error> {
+        let response = self
+            .agent
+            .get(&format!("{API_URL}{path}"))
+            .set("Authorization", &self.authorization)
+            .call()?;
+
+        Ok(BufReader::new(response.into_reader())
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| serde_json::from_str(&line).ok()))
+    }
+
+    fn post(&self, path: &str, form: &[(&str, &str)]) -> Result<(), Error> {
+        self.agent
+            .post(&format!("{API_URL}{path}"))
+            .set("Authorization", &self.authorization)
+            .send_form(form)?;
+
+        Ok(())
+    }
+}
+
+// Plays the game until it ends, searching with the clock Lichess reports on each move
+fn play_game(client: &Client, game_id: &str, bot_id: &str) -> Result<(), Error> {
+    let engine = Engine::new(EngineOptions {
+        threads: get_num_threads(),
+        hash_size_mb: get_hash_size_mb(),
+    });
+
+    let mut color = Color::White;
+    let mut initial_fen = None;
+
+    for event in client.stream(&format!("/bot/game/stream/{game_id}"))? {
+        let game_state = match event["type"].as_str() {
+            Some("gameFull") => {
+                let white = event["white"]["id"].as_str().unwrap_or_default();
+                color = Color::from_white(white.eq_ignore_ascii_case(bot_id));
+
+                initial_fen = match event["initialFen"].as_str() {
+                    None | Some("startpos") => None,
+                    Some(fen) => Some(fen.to_owned()),
+                };
+
+                let chess960 = event["variant"]["key"].as_str() == Some("chess960");
+                uci::set_option("UCI_Chess960", &chess960.to_string());
+
+                event["state"].clone()
+            }
+            Some("gameState") => event,
+            _ => continue,
+        };
+
+        if game_state["status"].as_str() != Some("started") {
+            break;
+        }
+
+        let moves: Vec<&str> = game_state["moves"]
+            .as_str()
+            .unwrap_or_default()
+            .split_whitespace()
+            .collect();
+
+        let Some(state) = State::from_fen_and_moves(initial_fen.as_deref(), &moves) else {
+            error!("Unable to follow game {}", game_id);
+            break;
+        };
+
+        if state.side_to_move() != color {
+            continue;
+        }
+
+        let clock = |key: &str| Duration::from_millis(game_state[key].as_u64().unwrap_or_default());
+        let (time, increment) = color.fold_wb(("wtime", "winc"), ("btime", "binc"));
+
+        // Can't fail, as the same position was just built
+        let _ = engine.set_position(initial_fen.as_deref(), &moves);
+        let result = engine.go(Limits {
+            remaining: Some(clock(time)),
+            increment: clock(increment),
+            ..Limits::default()
+        });
+
+        if let Some(mov) = result.best_move {
+            client.post(&format!("/bot/game/{game_id}/move/{mov}"), &[])?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Connects to Lichess and plays the challenges `options` lets through until the
+/// event stream closes.
+///
+/// # Errors
+///
+/// Fails without a token in the environment, or if the account or event stream
+/// can't be fetched. Games that fail are logged and don't end the bot.
+pub fn run(options: &BotOptions) -> Result<(), Error> {
+    let token = env::var(TOKEN_VAR).map_err(|_| Error::Token)?;
+    let client = Client::new(&token);
+
+    for (name, value) in &options.engine_options {
+        uci::set_option(name, value);
+    }
+
+    let account = client.get("/account")?;
+    let bot_id = account["id"].as_str().unwrap_or_default().to_owned();
+    info!("Connected to Lichess as {}", bot_id);
+
+    let playing = Arc::new(AtomicBool::new(false));
+    // A challenge accepted whose game hasn't started yet, so no other is accepted
+    // in the meantime
+    let mut pending: Option<String> = None;
+
+    for event in client.stream("/stream/event")? {
+        match event["type"].as_str() {
+            Some("challenge") => {
+                let challenge = &event["challenge"];
+                let id = challenge["id"].as_str().unwrap_or_default();
+                let challenger = challenge["challenger"]["id"].as_str().unwrap_or_default();
+
+                // Challenges sent by the bot show up too
+                if challenger.eq_ignore_ascii_case(&bot_id) {
+                    continue;
+                }
+
+                let reason = if playing.load(Ordering::SeqCst) || pending.is_some() {
+                    Some("later")
+                } else {
+                    options.decline_reason(challenge)
+                };
+
+                let response = match reason {
+                    None => client.post(&format!("/challenge/{id}/accept"), &[]),
+                    Some(reason) => {
+                        client.post(&format!("/challenge/{id}/decline"), &[("reason", reason)])
+                    }
+                };
+
+                match response {
+                    Ok(()) => {
+                        info!("Challenge {} from {}: {:?}", id, challenger, reason);
+                        if reason.is_none() {
+                            pending = Some(id.to_owned());
+                        }
+                    }
+                    Err(e) => warn!("Unable to answer challenge {}: {:?}", id, e),
+                }
+            }
+            Some("challengeCanceled" | "challengeDeclined") => {
+                let id = event["challenge"]["id"].as_str().unwrap_or_default();
+                if pending.as_deref() == Some(id) {
+                    pending = None;
+                }
+            }
+            Some("gameStart") => {
+                let game_id = event["game"]["gameId"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned();
+                pending = None;
+
+                if playing.swap(true, Ordering::SeqCst) {
+                    warn!("Game {} started while already playing", game_id);
+                    continue;
+                }
+
+                let client = client.clone();
+                let bot_id = bot_id.clone();
+                let playing = playing.clone();
+
+                thread::spawn(move || {
+                    info!("Playing game {}", game_id);
+                    if let Err(e) = play_game(&client, &game_id, &bot_id) {
+                        error!("Game {} failed: {:?}", game_id, e);
+                    }
+                    playing.store(false, Ordering::SeqCst);
+                });
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
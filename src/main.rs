@@ -1,36 +1,16 @@
 #![warn(clippy::all, clippy::pedantic)]
-#![allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
 
 #[macro_use]
 extern crate log;
-extern crate arc_swap;
-extern crate arrayvec;
-extern crate crossbeam;
-extern crate dashmap;
-extern crate fastapprox;
-extern crate memmap;
-extern crate nohash_hasher;
-extern crate once_cell;
 extern crate pretty_env_logger;
-extern crate rand;
-extern crate shakmaty;
-extern crate shakmaty_syzygy;
-
-mod arena;
-mod math;
-mod mcts;
-mod options;
-mod search_tree;
-mod tablebase;
-mod transposition_table;
-mod tree_policy;
+extern crate princhess;
+
+#[cfg(feature = "lichess")]
+use princhess::lichess;
+use princhess::{analyze, annotate, bench, convert, epd, game_manager, training, uci};
+use std::time::Duration;
 
 mod args;
-mod evaluation;
-mod search;
-mod state;
-mod training;
-mod uci;
 
 fn main() {
     args::init();
@@ -38,11 +18,94 @@ fn main() {
 
     pretty_env_logger::init();
 
-    if let Some(ref train_pgn) = options.train_pgn {
-        training::train(train_pgn, &options.train_output_path);
+    let limit = match (options.movetime, options.nodes) {
+        (Some(ms), _) => analyze::Limit::Time(Duration::from_millis(ms)),
+        (None, n) => analyze::Limit::Playouts(n.unwrap_or(analyze::DEFAULT_ANALYZE_PLAYOUTS)),
+    };
+
+    if let Some(ref convert_pgn) = options.convert_pgn {
+        let convert_options = convert::ConvertOptions {
+            min_elo: options.min_elo,
+            no_bullet: options.no_bullet,
+            skip_plies: options.skip_plies,
+            rescore: options.rescore,
+        };
+        convert::convert(convert_pgn, &options.train_output_path, &convert_options);
+    } else if let Some(ref train_pgn) = options.train_pgn {
+        match (
+            training::OutputFormat::from_name(&options.train_format),
+            training::FeatureSet::from_name(&options.train_features),
+        ) {
+            (Some(format), Some(features)) => {
+                training::train(train_pgn, &options.train_output_path, format, features);
+            }
+            (None, _) => error!("Unknown train format: {}", options.train_format),
+            (_, None) => error!("Unknown train features: {}", options.train_features),
+        }
+    } else if let Some(ref analyze_path) = options.analyze {
+        if let Err(e) = analyze::run(analyze_path, limit) {
+            error!("Unable to analyze {}: {:?}", analyze_path, e);
+        }
+    } else if options.extra.first().map(String::as_str) == Some("annotate") {
+        let annotate_options = annotate::AnnotateOptions {
+            limit,
+            variations: options.variations,
+        };
+        match options.extra.get(1) {
+            Some(path) => {
+                if let Err(e) = annotate::annotate(path, &annotate_options) {
+                    error!("Unable to annotate {}: {:?}", path, e);
+                }
+            }
+            None => error!("Usage: annotate <file> [--nodes N | --movetime MS] [--variations]"),
+        }
+    } else if options.extra.first().map(String::as_str) == Some("bench") {
+        let playouts = options
+            .extra
+            .get(1)
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(bench::DEFAULT_BENCH_PLAYOUTS);
+        bench::run(playouts);
+    } else if options.extra.first().map(String::as_str) == Some("epdtest") {
+        match options.extra.get(1) {
+            Some(path) => {
+                if let Err(e) = epd::run(path, limit) {
+                    error!("Unable to run {}: {:?}", path, e);
+                }
+            }
+            None => error!("Usage: epdtest <file> [--nodes N | --movetime MS]"),
+        }
+    } else if options.extra.first().map(String::as_str) == Some("lichess") {
+        run_lichess(&options.extra[1..]);
+    } else if options.extra.first().map(String::as_str) == Some("match") {
+        match game_manager::MatchOptions::parse(&options.extra[1..]) {
+            Ok(match_options) => {
+                if let Err(e) = game_manager::run(&match_options) {
+                    error!("Unable to run match: {:?}", e);
+                }
+            }
+            Err(e) => error!("{}", e),
+        }
     } else {
         info!("Init.");
         uci::main(options.extra.clone());
         info!("Exit.");
     }
 }
+
+#[cfg(feature = "lichess")]
+fn run_lichess(args: &[String]) {
+    match lichess::BotOptions::parse(args) {
+        Ok(bot_options) => {
+            if let Err(e) = lichess::run(&bot_options) {
+                error!("Lichess bot stopped: {:?}", e);
+            }
+        }
+        Err(e) => error!("{}", e),
+    }
+}
+
+#[cfg(not(feature = "lichess"))]
+fn run_lichess(_: &[String]) {
+    error!("Built without the lichess feature");
+}
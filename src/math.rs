@@ -1,3 +1,19 @@
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::options::is_deterministic;
+
+const DETERMINISTIC_SEED: u64 = 0x5eed;
+
+/// A fresh RNG, seeded from entropy unless searches are meant to be repeatable.
+pub fn rng() -> SmallRng {
+    if is_deterministic() {
+        SmallRng::seed_from_u64(DETERMINISTIC_SEED)
+    } else {
+        SmallRng::from_entropy()
+    }
+}
+
 pub fn softmax(arr: &mut [f32]) {
     let max = max(arr);
     let mut s = 0.;
@@ -11,6 +27,15 @@ pub fn softmax(arr: &mut [f32]) {
     }
 }
 
+/// Shannon entropy of a probability distribution, in nats.
+pub fn entropy(probs: &[f32]) -> f32 {
+    -probs
+        .iter()
+        .filter(|&&p| p > 0.)
+        .map(|p| p * p.ln())
+        .sum::<f32>()
+}
+
 fn max(arr: &[f32]) -> f32 {
     let mut max = std::f32::NEG_INFINITY;
     for x in arr.iter() {
@@ -18,3 +43,377 @@ fn max(arr: &[f32]) -> f32 {
     }
     max
 }
+
+/// Samples `n` values from a symmetric Dirichlet distribution with concentration `alpha`.
+pub fn dirichlet<R: Rng>(rng: &mut R, alpha: f32, n: usize) -> Vec<f32> {
+    let mut samples: Vec<f32> = (0..n).map(|_| gamma(rng, alpha)).collect();
+    let sum: f32 = samples.iter().sum();
+
+    if sum > 0. {
+        for x in &mut samples {
+            *x /= sum;
+        }
+    }
+
+    samples
+}
+
+// Marsaglia and Tsang's method, with the usual boost for alpha < 1
+fn gamma<R: Rng>(rng: &mut R, alpha: f32) -> f32 {
+    if alpha < 1. {
+        let u: f32 = rng.gen();
+        return gamma(rng, alpha + 1.) * u.powf(1. / alpha);
+    }
+
+    let d = alpha - 1. / 3.;
+    let c = 1. / (9. * d).sqrt();
+
+    loop {
+        let x = standard_normal(rng);
+        let v = 1. + c * x;
+
+        if v <= 0. {
+            continue;
+        }
+
+        let v = v * v * v;
+        let u: f32 = rng.gen();
+
+        if u < 1. - 0.0331 * x.powi(4) || u.ln() < 0.5 * x * x + d * (1. - v + v.ln()) {
+            return d * v;
+        }
+    }
+}
+
+// Box-Muller transform
+fn standard_normal<R: Rng>(rng: &mut R) -> f32 {
+    let u1 = rng.gen::<f32>().max(f32::MIN_POSITIVE);
+    let u2: f32 = rng.gen();
+
+    (-2. * u1.ln()).sqrt() * (2. * std::f32::consts::PI * u2).cos()
+}
+
+/// `acc[i] += weights[i]`, vectorized where the CPU allows.
+pub fn add_to_all(acc: &mut [f32], weights: &[f32]) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        simd::add_to_all_neon(acc, weights);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { simd::add_to_all_avx2(acc, weights) };
+                return;
+            }
+        }
+
+        add_to_all_scalar(acc, weights);
+    }
+}
+
+/// `acc[i] -= weights[i]`, vectorized where the CPU allows.
+pub fn sub_from_all(acc: &mut [f32], weights: &[f32]) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        simd::sub_from_all_neon(acc, weights);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { simd::sub_from_all_avx2(acc, weights) };
+                return;
+            }
+        }
+
+        sub_from_all_scalar(acc, weights);
+    }
+}
+
+/// Dot product of `relu(acc)` with `weights`, vectorized where the CPU allows.
+pub fn relu_dot(acc: &[f32], weights: &[f32]) -> f32 {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        simd::relu_dot_neon(acc, weights)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return unsafe { simd::relu_dot_avx2(acc, weights) };
+            }
+        }
+
+        relu_dot_scalar(acc, weights)
+    }
+}
+
+/// `acc[i] += weights[i]` for quantized weights, vectorized where the CPU allows.
+pub fn add_i8_to_all(acc: &mut [i16], weights: &[i8]) {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        simd::add_i8_to_all_neon(acc, weights);
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                unsafe { simd::add_i8_to_all_avx2(acc, weights) };
+                return;
+            }
+        }
+
+        add_i8_to_all_scalar(acc, weights);
+    }
+}
+
+/// Dot product of unsigned 8 bit activations with i8 weights, vectorized where the CPU allows.
+pub fn dot_u8_i8(acc: &[u8], weights: &[i8]) -> i32 {
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        simd::dot_u8_i8_neon(acc, weights)
+    }
+
+    #[cfg(not(target_arch = "aarch64"))]
+    {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return unsafe { simd::dot_u8_i8_avx2(acc, weights) };
+            }
+        }
+
+        dot_u8_i8_scalar(acc, weights)
+    }
+}
+
+fn add_i8_to_all_scalar(acc: &mut [i16], weights: &[i8]) {
+    for (a, w) in acc.iter_mut().zip(weights) {
+        *a += i16::from(*w);
+    }
+}
+
+fn add_to_all_scalar(acc: &mut [f32], weights: &[f32]) {
+    for (a, w) in acc.iter_mut().zip(weights) {
+        *a += w;
+    }
+}
+
+fn sub_from_all_scalar(acc: &mut [f32], weights: &[f32]) {
+    for (a, w) in acc.iter_mut().zip(weights) {
+        *a -= w;
+    }
+}
+
+fn relu_dot_scalar(acc: &[f32], weights: &[f32]) -> f32 {
+    acc.iter().zip(weights).map(|(a, w)| a.max(0.) * w).sum()
+}
+
+fn dot_u8_i8_scalar(acc: &[u8], weights: &[i8]) -> i32 {
+    acc.iter()
+        .zip(weights)
+        .map(|(a, w)| i32::from(*a) * i32::from(*w))
+        .sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::{
+        __m128i, __m256i, _mm256_add_epi16, _mm256_add_epi32, _mm256_add_ps, _mm256_cvtepi8_epi16,
+        _mm256_cvtepu8_epi16, _mm256_loadu_ps, _mm256_loadu_si256, _mm256_madd_epi16,
+        _mm256_max_ps, _mm256_mul_ps, _mm256_setzero_ps, _mm256_setzero_si256, _mm256_storeu_ps,
+        _mm256_storeu_si256, _mm256_sub_ps, _mm_loadu_si128,
+    };
+
+    const LANES: usize = 8;
+    const I16_LANES: usize = 16;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add_i8_to_all_avx2(acc: &mut [i16], weights: &[i8]) {
+        let n = acc.len().min(weights.len());
+        let chunks = n / I16_LANES;
+
+        for i in 0..chunks {
+            let a = acc.as_mut_ptr().add(i * I16_LANES).cast::<__m256i>();
+            let w = weights.as_ptr().add(i * I16_LANES).cast::<__m128i>();
+            let widened = _mm256_cvtepi8_epi16(_mm_loadu_si128(w));
+            _mm256_storeu_si256(a, _mm256_add_epi16(_mm256_loadu_si256(a), widened));
+        }
+
+        super::add_i8_to_all_scalar(
+            &mut acc[chunks * I16_LANES..n],
+            &weights[chunks * I16_LANES..n],
+        );
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add_to_all_avx2(acc: &mut [f32], weights: &[f32]) {
+        let n = acc.len().min(weights.len());
+        let chunks = n / LANES;
+
+        for i in 0..chunks {
+            let a = acc.as_mut_ptr().add(i * LANES);
+            let w = weights.as_ptr().add(i * LANES);
+            _mm256_storeu_ps(a, _mm256_add_ps(_mm256_loadu_ps(a), _mm256_loadu_ps(w)));
+        }
+
+        super::add_to_all_scalar(&mut acc[chunks * LANES..n], &weights[chunks * LANES..n]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn sub_from_all_avx2(acc: &mut [f32], weights: &[f32]) {
+        let n = acc.len().min(weights.len());
+        let chunks = n / LANES;
+
+        for i in 0..chunks {
+            let a = acc.as_mut_ptr().add(i * LANES);
+            let w = weights.as_ptr().add(i * LANES);
+            _mm256_storeu_ps(a, _mm256_sub_ps(_mm256_loadu_ps(a), _mm256_loadu_ps(w)));
+        }
+
+        super::sub_from_all_scalar(&mut acc[chunks * LANES..n], &weights[chunks * LANES..n]);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn relu_dot_avx2(acc: &[f32], weights: &[f32]) -> f32 {
+        let n = acc.len().min(weights.len());
+        let chunks = n / LANES;
+
+        let zero = _mm256_setzero_ps();
+        let mut sum = _mm256_setzero_ps();
+
+        for i in 0..chunks {
+            let a = _mm256_max_ps(_mm256_loadu_ps(acc.as_ptr().add(i * LANES)), zero);
+            let w = _mm256_loadu_ps(weights.as_ptr().add(i * LANES));
+            sum = _mm256_add_ps(sum, _mm256_mul_ps(a, w));
+        }
+
+        let mut lanes = [0f32; LANES];
+        _mm256_storeu_ps(lanes.as_mut_ptr(), sum);
+
+        lanes.iter().sum::<f32>()
+            + super::relu_dot_scalar(&acc[chunks * LANES..n], &weights[chunks * LANES..n])
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn dot_u8_i8_avx2(acc: &[u8], weights: &[i8]) -> i32 {
+        let n = acc.len().min(weights.len());
+        let chunks = n / I16_LANES;
+
+        let mut sum = _mm256_setzero_si256();
+
+        // Widen both sides to i16, madd then sums adjacent products into i32 lanes
+        for i in 0..chunks {
+            let a = acc.as_ptr().add(i * I16_LANES).cast::<__m128i>();
+            let w = weights.as_ptr().add(i * I16_LANES).cast::<__m128i>();
+            let a = _mm256_cvtepu8_epi16(_mm_loadu_si128(a));
+            let w = _mm256_cvtepi8_epi16(_mm_loadu_si128(w));
+            sum = _mm256_add_epi32(sum, _mm256_madd_epi16(a, w));
+        }
+
+        let mut lanes = [0i32; LANES];
+        _mm256_storeu_si256(lanes.as_mut_ptr().cast::<__m256i>(), sum);
+
+        lanes.iter().sum::<i32>()
+            + super::dot_u8_i8_scalar(&acc[chunks * I16_LANES..n], &weights[chunks * I16_LANES..n])
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod simd {
+    use std::arch::aarch64::{vaddq_f32, vaddvq_f32, vdupq_n_f32, vld1q_f32, vmaxq_f32};
+    use std::arch::aarch64::{vaddq_s16, vld1_s8, vld1q_s16, vmovl_s8, vst1q_s16};
+    use std::arch::aarch64::{
+        vaddvq_s32, vdupq_n_s32, vget_low_s16, vld1_u8, vmlal_high_s16, vmlal_s16, vmovl_u8,
+        vreinterpretq_s16_u16,
+    };
+    use std::arch::aarch64::{vmlaq_f32, vst1q_f32, vsubq_f32};
+
+    const LANES: usize = 4;
+    const I16_LANES: usize = 8;
+
+    pub unsafe fn add_i8_to_all_neon(acc: &mut [i16], weights: &[i8]) {
+        let n = acc.len().min(weights.len());
+        let chunks = n / I16_LANES;
+
+        for i in 0..chunks {
+            let a = acc.as_mut_ptr().add(i * I16_LANES);
+            let w = vmovl_s8(vld1_s8(weights.as_ptr().add(i * I16_LANES)));
+            vst1q_s16(a, vaddq_s16(vld1q_s16(a), w));
+        }
+
+        super::add_i8_to_all_scalar(
+            &mut acc[chunks * I16_LANES..n],
+            &weights[chunks * I16_LANES..n],
+        );
+    }
+
+    pub unsafe fn add_to_all_neon(acc: &mut [f32], weights: &[f32]) {
+        let n = acc.len().min(weights.len());
+        let chunks = n / LANES;
+
+        for i in 0..chunks {
+            let a = acc.as_mut_ptr().add(i * LANES);
+            let w = weights.as_ptr().add(i * LANES);
+            vst1q_f32(a, vaddq_f32(vld1q_f32(a), vld1q_f32(w)));
+        }
+
+        super::add_to_all_scalar(&mut acc[chunks * LANES..n], &weights[chunks * LANES..n]);
+    }
+
+    pub unsafe fn sub_from_all_neon(acc: &mut [f32], weights: &[f32]) {
+        let n = acc.len().min(weights.len());
+        let chunks = n / LANES;
+
+        for i in 0..chunks {
+            let a = acc.as_mut_ptr().add(i * LANES);
+            let w = weights.as_ptr().add(i * LANES);
+            vst1q_f32(a, vsubq_f32(vld1q_f32(a), vld1q_f32(w)));
+        }
+
+        super::sub_from_all_scalar(&mut acc[chunks * LANES..n], &weights[chunks * LANES..n]);
+    }
+
+    pub unsafe fn relu_dot_neon(acc: &[f32], weights: &[f32]) -> f32 {
+        let n = acc.len().min(weights.len());
+        let chunks = n / LANES;
+
+        let zero = vdupq_n_f32(0.);
+        let mut sum = vdupq_n_f32(0.);
+
+        for i in 0..chunks {
+            let a = vmaxq_f32(vld1q_f32(acc.as_ptr().add(i * LANES)), zero);
+            let w = vld1q_f32(weights.as_ptr().add(i * LANES));
+            sum = vmlaq_f32(sum, a, w);
+        }
+
+        vaddvq_f32(sum)
+            + super::relu_dot_scalar(&acc[chunks * LANES..n], &weights[chunks * LANES..n])
+    }
+
+    pub unsafe fn dot_u8_i8_neon(acc: &[u8], weights: &[i8]) -> i32 {
+        let n = acc.len().min(weights.len());
+        let chunks = n / I16_LANES;
+
+        let mut sum = vdupq_n_s32(0);
+
+        for i in 0..chunks {
+            let a = vreinterpretq_s16_u16(vmovl_u8(vld1_u8(acc.as_ptr().add(i * I16_LANES))));
+            let w = vmovl_s8(vld1_s8(weights.as_ptr().add(i * I16_LANES)));
+            sum = vmlal_s16(sum, vget_low_s16(a), vget_low_s16(w));
+            sum = vmlal_high_s16(sum, a, w);
+        }
+
+        vaddvq_s32(sum)
+            + super::dot_u8_i8_scalar(&acc[chunks * I16_LANES..n], &weights[chunks * I16_LANES..n])
+    }
+}
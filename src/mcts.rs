@@ -1,56 +1,216 @@
+use rand::Rng;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
-use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use crate::evaluation;
-use crate::search::{TimeManagement, SCALE};
+use crate::evaluation::{self, eval_curve, Accumulators};
+use crate::math;
+use crate::numa;
+use crate::options::{
+    get_batch_size, get_cpuct, get_fpu_reduction, get_hash_size_mb, get_max_backup_weight,
+    get_max_playout_length, get_moves_left_slope, get_num_thread_groups, get_root_noise_alpha,
+    get_root_noise_epsilon, get_score_type, get_virtual_loss, is_dag_backup, is_history_guard,
+    is_human_like, is_limit_strength, is_numa, ScoreType,
+};
+use crate::search::{to_uci, TimeManagement, SCALE};
 pub use crate::search_tree::*;
 use crate::state::State;
+use crate::strength;
+use crate::threadpool::{self, JobHandle};
 use crate::transposition_table::{LRAllocator, TranspositionTable};
+use crate::tree_policy::Cpuct;
 
 pub struct ThreadData<'a> {
     pub allocator: LRAllocator<'a>,
+    pub accumulators: Accumulators,
+    pub cpuct: Cpuct,
+    pub fpu_reduction: f32,
+    pub moves_left_slope: f32,
+    pub dag_backup: bool,
+    pub history_guard: bool,
+    pub max_backup_weight: f32,
+    pub batch_size: usize,
+    pub max_playout_length: usize,
+    pub virtual_loss: i64,
+    stop_signal: Arc<AtomicBool>,
 }
 
 impl<'a> ThreadData<'a> {
-    fn create(tree: &'a SearchTree) -> Self {
+    fn create(tree: &'a SearchTree, cpuct: f32, stop_signal: Arc<AtomicBool>) -> Self {
         Self {
             allocator: tree.allocator(),
+            accumulators: Accumulators::default(),
+            cpuct: Cpuct::new(cpuct),
+            fpu_reduction: get_fpu_reduction(),
+            moves_left_slope: get_moves_left_slope(),
+            dag_backup: is_dag_backup(),
+            history_guard: is_history_guard(),
+            max_backup_weight: get_max_backup_weight(),
+            batch_size: get_batch_size(),
+            max_playout_length: get_max_playout_length().clamp(1, MAX_PLAYOUT_LENGTH),
+            virtual_loss: (get_virtual_loss() * SCALE) as i64,
+            stop_signal,
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop_signal.load(Ordering::Relaxed)
+    }
+}
+
+// Maximum relative change of cpuct for threads other than the first of a group
+const CPUCT_JITTER: f32 = 0.05;
+
+const ROOT_MERGE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A tree searched by its own group of threads, whose root statistics are
+/// periodically folded into the main tree.
+struct HelperTree {
+    search_tree: SearchTree,
+    cpuct: f32,
+    merged: Mutex<Vec<(u32, i64, i64, u64)>>,
+}
+
+impl HelperTree {
+    fn new(search_tree: SearchTree, cpuct: f32) -> Self {
+        let merged = Mutex::new(vec![(0, 0, 0, 0); search_tree.root_node().hots().len()]);
+        Self {
+            search_tree,
+            cpuct,
+            merged,
         }
     }
 }
 
 pub struct Mcts {
     search_tree: SearchTree,
+    helpers: Vec<HelperTree>,
 }
 
 impl Mcts {
     pub fn new(state: State, table: TranspositionTable, prev_table: TranspositionTable) -> Self {
         let search_tree = SearchTree::new(state, table, prev_table);
-        Self { search_tree }
+        Self {
+            search_tree,
+            helpers: Vec::new(),
+        }
+    }
+
+    pub fn from_tree(search_tree: SearchTree) -> Self {
+        Self {
+            search_tree,
+            helpers: Vec::new(),
+        }
+    }
+
+    pub fn reroot(self, state: State) -> Self {
+        Self {
+            search_tree: self.search_tree.reroot(state),
+            helpers: Vec::new(),
+        }
+    }
+
+    /// Forgets everything searched so far and starts over at `state`.
+    pub fn new_game(self, state: State) -> Self {
+        Self::from_tree(self.search_tree.new_game(state))
+    }
+
+    fn create_helpers(&mut self, num_helpers: usize) {
+        let root_state = self.search_tree.root_state();
+        let root_moves: Vec<shakmaty::Move> = self
+            .search_tree
+            .root_node()
+            .hots()
+            .iter()
+            .filter_map(|h| h.get_move().to_move(root_state))
+            .collect();
+        let hash_size_mb = get_hash_size_mb() / 2 / (num_helpers + 1);
+
+        self.helpers = (1..=num_helpers)
+            .map(|g| {
+                let mut tree = SearchTree::new(
+                    root_state.clone(),
+                    TranspositionTable::with_size_mb(hash_size_mb),
+                    TranspositionTable::zero(),
+                );
+                tree.set_helper();
+                tree.restrict_root_moves(&root_moves);
+                tree.apply_root_noise(get_root_noise_epsilon(), get_root_noise_alpha());
+                HelperTree::new(tree, get_cpuct() * (1. + 0.1 * g as f32))
+            })
+            .collect();
+    }
+
+    fn merge_helpers(&self) {
+        for helper in &self.helpers {
+            let mut merged = helper.merged.lock().unwrap();
+            let stats = helper.search_tree.root_stats();
+
+            let hots = helper.search_tree.root_node().hots();
+            for ((hot, now), before) in hots.iter().zip(&stats).zip(merged.iter()) {
+                self.search_tree.add_root_stats(
+                    hot.get_move(),
+                    now.0 - before.0,
+                    now.1 - before.1,
+                    now.2 - before.2,
+                    // Sums of plies come from rounded averages, so may dip
+                    now.3.saturating_sub(before.3),
+                );
+            }
+
+            *merged = stats;
+        }
+    }
+
+    fn finish_helpers(&mut self) {
+        self.merge_helpers();
+        self.helpers.clear();
+    }
+
+    pub fn resize_table(&self, hash_size_mb: usize) {
+        self.search_tree.resize_table(hash_size_mb);
+    }
+
+    pub fn restrict_root_moves(&mut self, moves: &[shakmaty::Move]) {
+        self.search_tree.restrict_root_moves(moves);
+    }
+
+    pub fn apply_root_noise(&mut self, epsilon: f32, alpha: f32) {
+        self.search_tree.apply_root_noise(epsilon, alpha);
     }
 
     unsafe fn spawn_worker_thread(
-        &self,
+        index: usize,
+        search_tree: &SearchTree,
+        cpuct: f32,
         stop_signal: Arc<AtomicBool>,
         time_managment: TimeManagement,
         sender: &Sender<String>,
-    ) -> JoinHandle<()> {
-        let search_tree = &self.search_tree;
+    ) -> JobHandle {
         let sender_clone = sender.clone();
-        crossbeam::spawn_unsafe(move || {
-            let mut tld = ThreadData::create(search_tree);
-            loop {
-                if stop_signal.load(Ordering::SeqCst) {
-                    break;
-                }
-                if !search_tree.playout(&mut tld, time_managment) {
-                    if !stop_signal.swap(true, Ordering::SeqCst) {
-                        sender_clone.send("stop".to_string()).unwrap_or(());
-                    }
-                    break;
-                }
+        threadpool::spawn_unsafe(move || {
+            // Pinned before its arena chunks are allocated, so they're local too
+            if is_numa() {
+                numa::bind_thread(index);
+            } else {
+                numa::unbind_thread();
+            }
+            let mut tld = ThreadData::create(search_tree, cpuct, stop_signal.clone());
+            while search_tree.playout(&mut tld, time_managment) {}
+            // Only tell the UCI thread to stop if it didn't ask us to
+            if !stop_signal.swap(true, Ordering::SeqCst) {
+                sender_clone.send("stop".to_string()).unwrap_or(());
+            }
+        })
+    }
+
+    unsafe fn spawn_merge_thread(&self, stop_signal: Arc<AtomicBool>) -> JobHandle {
+        threadpool::spawn_unsafe(move || {
+            while !stop_signal.load(Ordering::SeqCst) {
+                thread::sleep(ROOT_MERGE_INTERVAL);
+                self.merge_helpers();
             }
         })
     }
@@ -58,13 +218,23 @@ impl Mcts {
     #[allow(unused)]
     pub fn playout_sync(&self) {
         let search_tree = &self.search_tree;
-        let mut tld = ThreadData::create(search_tree);
+        let stop_signal = Arc::new(AtomicBool::new(false));
+        let mut tld = ThreadData::create(search_tree, get_cpuct(), stop_signal);
         while search_tree.playout(&mut tld, TimeManagement::infinite()) {}
     }
 
+    /// Searches on the calling thread until `time_management` says to stop.
+    pub fn playout_sync_until(&self, time_management: TimeManagement) {
+        let search_tree = &self.search_tree;
+        let stop_signal = Arc::new(AtomicBool::new(false));
+        let mut tld = ThreadData::create(search_tree, get_cpuct(), stop_signal);
+        while search_tree.playout(&mut tld, time_management) {}
+    }
+
     pub fn playout_sync_n(&self, n: usize) {
         let search_tree = &self.search_tree;
-        let mut tld = ThreadData::create(search_tree);
+        let stop_signal = Arc::new(AtomicBool::new(false));
+        let mut tld = ThreadData::create(search_tree, get_cpuct(), stop_signal);
         for _ in 0..n {
             if !search_tree.playout(&mut tld, TimeManagement::infinite()) {
                 break;
@@ -73,33 +243,66 @@ impl Mcts {
     }
 
     pub fn into_playout_parallel_async(
-        self,
+        mut self,
         num_threads: usize,
         time_management: TimeManagement,
         sender: &Sender<String>,
     ) -> AsyncSearchOwned {
         assert!(num_threads != 0);
+        let num_groups = get_num_thread_groups().min(num_threads);
+        self.create_helpers(num_groups - 1);
+
         let self_box = Box::new(self);
         let stop_signal = Arc::new(AtomicBool::new(false));
-        let threads = (0..num_threads)
-            .map(|_| {
+        let mut rng = math::rng();
+        let mut threads: Vec<_> = (0..num_threads)
+            .map(|i| {
                 let stop_signal = stop_signal.clone();
-                unsafe { self_box.spawn_worker_thread(stop_signal, time_management, sender) }
+                // Only the main group keeps time, helpers run until told to stop
+                let (tree, cpuct, tm) = match i % num_groups {
+                    0 => (&self_box.search_tree, get_cpuct(), time_management),
+                    g => {
+                        let helper = &self_box.helpers[g - 1];
+                        (
+                            &helper.search_tree,
+                            helper.cpuct,
+                            TimeManagement::infinite(),
+                        )
+                    }
+                };
+                // Vary exploration between threads sharing a tree
+                let cpuct = if i < num_groups {
+                    cpuct
+                } else {
+                    cpuct * (1. + CPUCT_JITTER * rng.gen_range(-1., 1.))
+                };
+                unsafe { Self::spawn_worker_thread(i, tree, cpuct, stop_signal, tm, sender) }
             })
             .collect();
+
+        if num_groups > 1 {
+            threads.push(unsafe { self_box.spawn_merge_thread(stop_signal.clone()) });
+        }
+
         AsyncSearchOwned {
             manager: Some(self_box),
             stop_signal,
             threads,
+            time_management,
         }
     }
 
     pub fn principal_variation(&self, num_moves: usize) -> Vec<shakmaty::Move> {
+        let mut state = self.search_tree.root_state().clone();
+
         self.search_tree
             .principal_variation(num_moves)
             .into_iter()
-            .map(HotMoveInfo::get_move)
-            .cloned()
+            .map_while(|h| {
+                let mov = h.get_move().to_move(&state)?;
+                state.make_move(&mov);
+                Some(mov)
+            })
             .collect()
     }
 
@@ -107,14 +310,31 @@ impl Mcts {
         &self.search_tree
     }
 
-    pub fn table(self) -> TranspositionTable {
-        self.search_tree.table()
-    }
-
     pub fn best_move(&self) -> Option<shakmaty::Move> {
         self.principal_variation(1).get(0).cloned()
     }
 
+    /// The move to play when weakened by `HumanLike` or `UCI_LimitStrength`: a
+    /// human-like alternative, or one sampled from the root visits at low Elo,
+    /// and the best move otherwise.
+    pub fn weakened_move(&self) -> Option<shakmaty::Move> {
+        let root_hots = self.search_tree.root_node().hots();
+
+        let mut choice = None;
+
+        if is_human_like() {
+            choice = strength::human_like_move(root_hots);
+        }
+
+        if choice.is_none() && is_limit_strength() {
+            choice = strength::sample_move(root_hots, strength::move_temperature());
+        }
+
+        choice
+            .and_then(|h| h.get_move().to_move(self.search_tree.root_state()))
+            .or_else(|| self.best_move())
+    }
+
     pub fn eval(&self) -> f32 {
         self.search_tree.eval()
     }
@@ -126,14 +346,17 @@ impl Mcts {
         let root_moves = root_node.hots();
 
         let state_moves = root_state.available_moves();
-        let state_moves_eval = evaluation::evaluate_policy(root_state, &state_moves);
+        let state_moves_eval =
+            evaluation::evaluate_policy(root_state, &state_moves, &mut Accumulators::default());
 
         let mut moves: Vec<(&HotMoveInfo, f32)> = root_moves.iter().zip(state_moves_eval).collect();
         moves.sort_by_key(|(h, e)| (h.average_reward().unwrap_or(*e) * SCALE) as i64);
         for (mov, e) in moves {
-            println!(
+            output!(
                 "info string {:>6} M: {:>6} P: {:>6} V: {:7} E: {:>6} ({:>8})",
-                format!("{}", mov.get_move()),
+                mov.get_move()
+                    .to_move(root_state)
+                    .map_or_else(|| mov.get_move().to_uci(), |m| m.to_string()),
                 format!("{:3.2}", e * 100.),
                 format!("{:3.2}", mov.policy() * 100.),
                 mov.visits(),
@@ -144,12 +367,44 @@ impl Mcts {
             );
         }
     }
+
+    /// Prints the nets' view of the root without searching: the static eval and
+    /// draw probability for the side to move, the tablebase flag and the policy
+    /// prior of each legal move, highest first.
+    pub fn print_eval(&self) {
+        let state = self.tree().root_state();
+        let stm = state.side_to_move();
+        let moves = state.available_moves();
+        let mut accumulators = Accumulators::default();
+
+        let (eval, draw) = evaluation::evaluate_state(state, &mut accumulators);
+        let eval = stm.fold_wb(eval, -eval) as f32 / SCALE;
+        let flag = evaluation::evaluate_state_flag(state, &moves, 0);
+        let flag = stm.fold_wb(flag, flag.flip());
+
+        output!(
+            "info string eval {} ({:+.4}) draw {:.2}% flag {:?}",
+            eval_in_cp(eval),
+            eval,
+            draw as f32 / (SCALE / 100.),
+            flag
+        );
+
+        let policy = evaluation::evaluate_policy(state, &moves, &mut accumulators);
+        let mut priors: Vec<_> = moves.iter().zip(policy).collect();
+        priors.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        for (mov, prior) in priors {
+            output!("info string {:>6} P: {:>6.2}", to_uci(mov), prior * 100.);
+        }
+    }
 }
 
 pub struct AsyncSearchOwned {
     manager: Option<Box<Mcts>>,
     stop_signal: Arc<AtomicBool>,
-    threads: Vec<JoinHandle<()>>,
+    threads: Vec<JobHandle>,
+    time_management: TimeManagement,
 }
 
 impl AsyncSearchOwned {
@@ -159,7 +414,9 @@ impl AsyncSearchOwned {
     }
     pub fn halt(mut self) -> Mcts {
         self.stop_threads();
-        *self.manager.take().unwrap()
+        let mut manager = *self.manager.take().unwrap();
+        manager.finish_helpers();
+        manager
     }
     pub fn get_manager(&self) -> &Mcts {
         self.manager.as_ref().unwrap()
@@ -167,6 +424,10 @@ impl AsyncSearchOwned {
     pub fn num_threads(&self) -> usize {
         self.threads.len()
     }
+    /// The time management the main threads search with.
+    pub fn time_management(&self) -> TimeManagement {
+        self.time_management
+    }
 }
 
 impl Drop for AsyncSearchOwned {
@@ -182,19 +443,41 @@ impl From<Mcts> for AsyncSearchOwned {
             manager: Some(Box::new(m)),
             stop_signal: Arc::new(AtomicBool::new(false)),
             threads: Vec::new(),
+            time_management: TimeManagement::infinite(),
         }
     }
 }
 
-fn drain_join_unwrap(threads: &mut Vec<JoinHandle<()>>) {
-    let join_results: Vec<_> = threads.drain(..).map(JoinHandle::join).collect();
+fn drain_join_unwrap(threads: &mut Vec<JobHandle>) {
+    let join_results: Vec<_> = threads.drain(..).map(JobHandle::join).collect();
     for x in join_results {
         x.unwrap();
     }
 }
 
 // eval here is [-1.0, 1.0]
+#[allow(clippy::cast_possible_truncation)]
 pub fn eval_in_cp(eval: f32) -> String {
+    let eval = eval.clamp(-1., 1.);
+    let score = match get_score_type() {
+        ScoreType::Centipawn => eval_curve().map_or_else(|| eval_to_cp(eval), |c| c.to_cp(eval)),
+        ScoreType::WinPercentage => ((eval + 1.) * 5000.).round() as i64,
+        ScoreType::Q => (eval * 10000.).round() as i64,
+    };
+    format!("cp {score}")
+}
+
+// eval here is [-1.0, 1.0] and draw is [0.0, 1.0]
+pub fn eval_in_wdl(eval: f32, draw: f32) -> String {
+    let eval = eval.max(-1.).min(1.);
+    let draw = draw.max(0.).min(1. - eval.abs());
+    let win = ((1. - draw + eval) * 500.).round() as i64;
+    let loss = ((1. - draw - eval) * 500.).round() as i64;
+
+    format!("wdl {} {} {}", win, 1000 - win - loss, loss)
+}
+
+pub fn eval_to_cp(eval: f32) -> i64 {
     let cps = if eval > 0.5 {
         18. * (eval - 0.5) + 1.
     } else if eval < -0.5 {
@@ -203,5 +486,5 @@ pub fn eval_in_cp(eval: f32) -> String {
         2. * eval
     };
 
-    format!("cp {}", (cps * 100.).round().max(-1000.).min(1000.) as i64)
+    (cps * 100.).round().max(-1000.).min(1000.) as i64
 }
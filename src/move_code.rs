@@ -0,0 +1,125 @@
+use shakmaty::uci::Uci;
+use shakmaty::{File, Move, Role, Setup, Square};
+
+use crate::options::is_chess960;
+use crate::state::State;
+
+const SQUARE_MASK: u16 = 0x3f;
+const TO_SHIFT: u16 = 6;
+const PROMOTION_SHIFT: u16 = 12;
+const PROMOTION_MASK: u16 = 0x7;
+const CASTLE_BIT: u16 = 1 << 15;
+
+/// A move packed into 16 bits: the from and to squares, the promotion piece and
+/// whether it castles, with castling stored as the king taking its own rook.
+/// The moving and captured pieces are recovered from the position it is played in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MoveCode(u16);
+
+impl MoveCode {
+    pub const NONE: Self = Self(0);
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    fn pack(from: Square, to: Square, promotion: Option<Role>, castle: bool) -> Self {
+        let mut bits = from as u16 | (to as u16) << TO_SHIFT;
+        bits |= promotion.map_or(0, |r| r as u16) << PROMOTION_SHIFT;
+        if castle {
+            bits |= CASTLE_BIT;
+        }
+        Self(bits)
+    }
+
+    pub fn from_square(self) -> Square {
+        Square::new(u32::from(self.0 & SQUARE_MASK))
+    }
+
+    pub fn to_square(self) -> Square {
+        Square::new(u32::from(self.0 >> TO_SHIFT & SQUARE_MASK))
+    }
+
+    pub fn promotion(self) -> Option<Role> {
+        match self.0 >> PROMOTION_SHIFT & PROMOTION_MASK {
+            0 => None,
+            r => Some(Role::ALL[usize::from(r) - 1]),
+        }
+    }
+
+    pub fn is_castle(self) -> bool {
+        self.0 & CASTLE_BIT != 0
+    }
+
+    /// Rebuilds the full move in `state`, the position it is played from, or `None`
+    /// if the side to move has no piece on the from square.
+    pub fn to_move(self, state: &State) -> Option<Move> {
+        let from = self.from_square();
+        let to = self.to_square();
+
+        let board = state.board().board();
+        if board.color_at(from) != Some(state.side_to_move()) {
+            return None;
+        }
+        let role = board.role_at(from)?;
+
+        if self.is_castle() {
+            return (role == Role::King).then_some(Move::Castle {
+                king: from,
+                rook: to,
+            });
+        }
+
+        let capture = board.role_at(to);
+
+        if role == Role::Pawn && capture.is_none() && from.file() != to.file() {
+            Some(Move::EnPassant { from, to })
+        } else {
+            Some(Move::Normal {
+                role,
+                from,
+                capture,
+                to,
+                promotion: self.promotion(),
+            })
+        }
+    }
+
+    /// The move in UCI notation, castling the way `UCI_Chess960` asks for.
+    pub fn to_uci(self) -> String {
+        let from = self.from_square();
+        let mut to = self.to_square();
+
+        if self.is_castle() && !is_chess960() {
+            let file = if to.file() > from.file() {
+                File::G
+            } else {
+                File::C
+            };
+            to = Square::from_coords(file, from.rank());
+        }
+
+        Uci::Normal {
+            from,
+            to,
+            promotion: self.promotion(),
+        }
+        .to_string()
+    }
+}
+
+impl From<&Move> for MoveCode {
+    fn from(mov: &Move) -> Self {
+        match *mov {
+            Move::Normal {
+                from,
+                to,
+                promotion,
+                ..
+            } => Self::pack(from, to, promotion, false),
+            Move::EnPassant { from, to } => Self::pack(from, to, None, false),
+            Move::Castle { king, rook } => Self::pack(king, rook, None, true),
+            Move::Put { .. } => Self::NONE,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Spreading search threads over NUMA nodes, so the memory each thread allocates is
+//! local to it. Only Linux exposes the topology this needs; elsewhere threads aren't
+//! pinned.
+
+#[cfg(target_os = "linux")]
+pub use self::linux::{bind_thread, unbind_thread};
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_thread(_index: usize) {}
+
+#[cfg(not(target_os = "linux"))]
+pub fn unbind_thread() {}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use once_cell::sync::Lazy;
+    use std::cell::Cell;
+    use std::fs;
+    use std::mem;
+
+    // The CPUs of each NUMA node
+    static NODES: Lazy<Vec<Vec<usize>>> = Lazy::new(read_nodes);
+
+    // The CPUs threads could run on before any was pinned
+    static UNPINNED: Lazy<Option<libc::cpu_set_t>> = Lazy::new(|| unsafe {
+        let mut set = mem::zeroed();
+        let size = mem::size_of::<libc::cpu_set_t>();
+        (libc::sched_getaffinity(0, size, &mut set) == 0).then_some(set)
+    });
+
+    thread_local! {
+        static PINNED: Cell<bool> = Cell::new(false);
+    }
+
+    fn read_nodes() -> Vec<Vec<usize>> {
+        let mut nodes = Vec::new();
+
+        for node in 0.. {
+            match fs::read_to_string(format!("/sys/devices/system/node/node{node}/cpulist")) {
+                Ok(list) => nodes.push(parse_cpu_list(list.trim())),
+                Err(_) => break,
+            }
+        }
+
+        nodes.retain(|cpus| !cpus.is_empty());
+        nodes
+    }
+
+    // Lists look like "0-3,8-11"
+    fn parse_cpu_list(list: &str) -> Vec<usize> {
+        list.split(',')
+            .filter_map(|range| match range.split_once('-') {
+                Some((lo, hi)) => Some(lo.parse().ok()?..=hi.parse().ok()?),
+                None => range.parse().ok().map(|cpu| cpu..=cpu),
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// Pins the calling thread, the `index`th of a search, to a CPU of a node picked
+    /// round robin. Does nothing on single node machines.
+    pub fn bind_thread(index: usize) {
+        let nodes = NODES.len();
+
+        if nodes < 2 {
+            return;
+        }
+
+        let cpus = &NODES[index % nodes];
+        let cpu = cpus[index / nodes % cpus.len()];
+
+        // Read before pinning, which any thread of the pool would inherit otherwise
+        Lazy::force(&UNPINNED);
+
+        if core_affinity::set_for_current(core_affinity::CoreId { id: cpu }) {
+            PINNED.with(|pinned| pinned.set(true));
+        } else {
+            warn!("Unable to pin search thread {} to cpu {}", index, cpu);
+        }
+    }
+
+    /// Undoes `bind_thread` for the calling thread. Pool threads outlive the search
+    /// that pinned them, so they're unpinned once NUMA is turned off.
+    pub fn unbind_thread() {
+        if !PINNED.with(Cell::get) {
+            return;
+        }
+
+        let Some(set) = UNPINNED.as_ref() else {
+            return;
+        };
+
+        let size = mem::size_of::<libc::cpu_set_t>();
+        if unsafe { libc::sched_setaffinity(0, size, set) } == 0 {
+            PINNED.with(|pinned| pinned.set(false));
+        } else {
+            warn!("Unable to unpin search thread");
+        }
+    }
+}
@@ -1,22 +1,100 @@
 use once_cell::sync::Lazy;
 use std::cmp::max;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::RwLock;
 
 static NUM_THREADS: AtomicUsize = AtomicUsize::new(1);
+static NUM_THREAD_GROUPS: AtomicUsize = AtomicUsize::new(1);
 static HASH_SIZE_MB: AtomicUsize = AtomicUsize::new(16);
+static SYZYGY_PROBE_LIMIT: AtomicUsize = AtomicUsize::new(7);
+static SYZYGY_PROBE_DEPTH: AtomicUsize = AtomicUsize::new(256);
+static BATCH_SIZE: AtomicUsize = AtomicUsize::new(1);
+static MAX_PLAYOUT_LENGTH: AtomicUsize = AtomicUsize::new(256);
+static MOVE_OVERHEAD: AtomicUsize = AtomicUsize::new(50);
+static INFO_INTERVAL: AtomicUsize = AtomicUsize::new(1000);
+static INFO_VERBOSITY: AtomicUsize = AtomicUsize::new(1);
+static ELO: AtomicUsize = AtomicUsize::new(3000);
 
 static CPUCT: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.85));
+static CPUCT_BASE: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(8192.));
+static CPUCT_FACTOR: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.85));
+static MOVES_LEFT_SLOPE: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.));
 static CVISITS_SELECTION: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.01));
+static FPU_REDUCTION: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.));
+static VIRTUAL_LOSS: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.));
+static POLICY_TEMPERATURE: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.));
+static POLICY_ENTROPY_SCALE: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.));
+static TREE_REUSE_WEIGHT: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.));
+static POLICY_CHECK_BOOST: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.));
+static POLICY_CAPTURE_BOOST: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.));
+static POLICY_PROMOTION_BOOST: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(1.));
+static MOVE_TIME_FRACTION: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(20.));
+static HARD_LIMIT_FACTOR: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(3.));
+static ROOT_NOISE_EPSILON: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.));
+static ROOT_NOISE_ALPHA: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.3));
+static HUMAN_LIKE_RATE: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.1));
+static MAX_BACKUP_WEIGHT: Lazy<RwLock<f32>> = Lazy::new(|| RwLock::new(0.));
 
 static CHESS960: AtomicBool = AtomicBool::new(false);
+static LENIENT_FEN: AtomicBool = AtomicBool::new(false);
+static BOOK_BEST_MOVE: AtomicBool = AtomicBool::new(false);
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+static VERBOSE_MOVE_STATS: AtomicBool = AtomicBool::new(false);
+static SEARCH_STATS: AtomicBool = AtomicBool::new(false);
+static SHOW_WDL: AtomicBool = AtomicBool::new(false);
+static DAG_BACKUP: AtomicBool = AtomicBool::new(false);
+static REPETITION_HASHING: AtomicBool = AtomicBool::new(false);
+static HISTORY_GUARD: AtomicBool = AtomicBool::new(false);
+static CPUCT_SCHEDULE: AtomicBool = AtomicBool::new(false);
+static NUMA: AtomicBool = AtomicBool::new(false);
+static LIMIT_STRENGTH: AtomicBool = AtomicBool::new(false);
+static HUMAN_LIKE: AtomicBool = AtomicBool::new(false);
+
+static SCORE_TYPE: Lazy<RwLock<ScoreType>> = Lazy::new(|| RwLock::new(ScoreType::Centipawn));
+
+/// How scores are reported in `info` lines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScoreType {
+    /// Centipawns, from the eval net's curve when it has one
+    Centipawn,
+    /// The expected score from 0 to 1, as 0 to 10000
+    WinPercentage,
+    /// The eval from -1 to 1, as -10000 to 10000
+    Q,
+}
+
+impl FromStr for ScoreType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "centipawn" => Ok(Self::Centipawn),
+            "win_percentage" => Ok(Self::WinPercentage),
+            "q" => Ok(Self::Q),
+            _ => Err(()),
+        }
+    }
+}
 
 pub fn set_num_threads(threads: usize) {
     NUM_THREADS.store(threads, Ordering::Relaxed);
 }
 
 pub fn get_num_threads() -> usize {
-    max(1, NUM_THREADS.load(Ordering::Relaxed))
+    if is_deterministic() {
+        1
+    } else {
+        max(1, NUM_THREADS.load(Ordering::Relaxed))
+    }
+}
+
+pub fn set_num_thread_groups(groups: usize) {
+    NUM_THREAD_GROUPS.store(groups, Ordering::Relaxed);
+}
+
+pub fn get_num_thread_groups() -> usize {
+    max(1, NUM_THREAD_GROUPS.load(Ordering::Relaxed))
 }
 
 pub fn set_hash_size_mb(hs: usize) {
@@ -27,6 +105,62 @@ pub fn get_hash_size_mb() -> usize {
     max(1, HASH_SIZE_MB.load(Ordering::Relaxed))
 }
 
+pub fn set_batch_size(size: usize) {
+    BATCH_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn get_batch_size() -> usize {
+    max(1, BATCH_SIZE.load(Ordering::Relaxed))
+}
+
+pub fn set_max_playout_length(length: usize) {
+    MAX_PLAYOUT_LENGTH.store(length, Ordering::Relaxed);
+}
+
+pub fn get_max_playout_length() -> usize {
+    MAX_PLAYOUT_LENGTH.load(Ordering::Relaxed)
+}
+
+pub fn set_move_overhead(ms: usize) {
+    MOVE_OVERHEAD.store(ms, Ordering::Relaxed);
+}
+
+pub fn get_move_overhead() -> usize {
+    MOVE_OVERHEAD.load(Ordering::Relaxed)
+}
+
+pub fn set_info_interval(ms: usize) {
+    INFO_INTERVAL.store(ms, Ordering::Relaxed);
+}
+
+pub fn get_info_interval() -> usize {
+    INFO_INTERVAL.load(Ordering::Relaxed)
+}
+
+pub fn set_info_verbosity(level: usize) {
+    INFO_VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn get_info_verbosity() -> usize {
+    INFO_VERBOSITY.load(Ordering::Relaxed)
+}
+
+pub fn set_syzygy_probe_limit(pieces: usize) {
+    SYZYGY_PROBE_LIMIT.store(pieces, Ordering::Relaxed);
+}
+
+pub fn get_syzygy_probe_limit() -> usize {
+    SYZYGY_PROBE_LIMIT.load(Ordering::Relaxed)
+}
+
+pub fn set_syzygy_probe_depth(depth: usize) {
+    SYZYGY_PROBE_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+pub fn get_syzygy_probe_depth() -> usize {
+    SYZYGY_PROBE_DEPTH.load(Ordering::Relaxed)
+}
+
 pub fn set_cpuct(c: f32) {
     let mut cp = CPUCT.write().unwrap();
     *cp = c;
@@ -37,6 +171,34 @@ pub fn get_cpuct() -> f32 {
     *cp
 }
 
+pub fn set_cpuct_base(b: f32) {
+    let mut base = CPUCT_BASE.write().unwrap();
+    *base = b;
+}
+
+pub fn get_cpuct_base() -> f32 {
+    let base = CPUCT_BASE.read().unwrap();
+    base.max(1.)
+}
+
+pub fn set_cpuct_schedule(s: bool) {
+    CPUCT_SCHEDULE.store(s, Ordering::Relaxed);
+}
+
+pub fn is_cpuct_schedule() -> bool {
+    CPUCT_SCHEDULE.load(Ordering::Relaxed)
+}
+
+pub fn set_cpuct_factor(f: f32) {
+    let mut factor = CPUCT_FACTOR.write().unwrap();
+    *factor = f;
+}
+
+pub fn get_cpuct_factor() -> f32 {
+    let factor = CPUCT_FACTOR.read().unwrap();
+    *factor
+}
+
 pub fn set_cvisits_selection(c: f32) {
     let mut cv = CVISITS_SELECTION.write().unwrap();
     *cv = c;
@@ -47,6 +209,136 @@ pub fn get_cvisits_selection() -> f32 {
     *cv
 }
 
+pub fn set_fpu_reduction(r: f32) {
+    let mut fpu = FPU_REDUCTION.write().unwrap();
+    *fpu = r;
+}
+
+pub fn get_fpu_reduction() -> f32 {
+    let fpu = FPU_REDUCTION.read().unwrap();
+    *fpu
+}
+
+pub fn set_moves_left_slope(s: f32) {
+    let mut slope = MOVES_LEFT_SLOPE.write().unwrap();
+    *slope = s;
+}
+
+pub fn get_moves_left_slope() -> f32 {
+    let slope = MOVES_LEFT_SLOPE.read().unwrap();
+    *slope
+}
+
+pub fn set_virtual_loss(v: f32) {
+    let mut vl = VIRTUAL_LOSS.write().unwrap();
+    *vl = v;
+}
+
+pub fn get_virtual_loss() -> f32 {
+    let vl = VIRTUAL_LOSS.read().unwrap();
+    vl.max(0.)
+}
+
+pub fn set_policy_temperature(t: f32) {
+    let mut temp = POLICY_TEMPERATURE.write().unwrap();
+    *temp = t;
+}
+
+pub fn get_policy_temperature() -> f32 {
+    let temp = POLICY_TEMPERATURE.read().unwrap();
+    temp.max(0.01)
+}
+
+pub fn set_policy_entropy_scale(s: f32) {
+    let mut scale = POLICY_ENTROPY_SCALE.write().unwrap();
+    *scale = s;
+}
+
+pub fn get_policy_entropy_scale() -> f32 {
+    let scale = POLICY_ENTROPY_SCALE.read().unwrap();
+    scale.max(0.)
+}
+
+pub fn set_tree_reuse_weight(w: f32) {
+    let mut weight = TREE_REUSE_WEIGHT.write().unwrap();
+    *weight = w;
+}
+
+pub fn get_tree_reuse_weight() -> f32 {
+    let weight = TREE_REUSE_WEIGHT.read().unwrap();
+    weight.clamp(0., 1.)
+}
+
+pub fn set_policy_check_boost(b: f32) {
+    let mut boost = POLICY_CHECK_BOOST.write().unwrap();
+    *boost = b;
+}
+
+pub fn get_policy_check_boost() -> f32 {
+    let boost = POLICY_CHECK_BOOST.read().unwrap();
+    boost.max(1.)
+}
+
+pub fn set_policy_capture_boost(b: f32) {
+    let mut boost = POLICY_CAPTURE_BOOST.write().unwrap();
+    *boost = b;
+}
+
+pub fn get_policy_capture_boost() -> f32 {
+    let boost = POLICY_CAPTURE_BOOST.read().unwrap();
+    boost.max(1.)
+}
+
+pub fn set_policy_promotion_boost(b: f32) {
+    let mut boost = POLICY_PROMOTION_BOOST.write().unwrap();
+    *boost = b;
+}
+
+pub fn get_policy_promotion_boost() -> f32 {
+    let boost = POLICY_PROMOTION_BOOST.read().unwrap();
+    boost.max(1.)
+}
+
+pub fn set_move_time_fraction(f: f32) {
+    let mut fraction = MOVE_TIME_FRACTION.write().unwrap();
+    *fraction = f;
+}
+
+pub fn get_move_time_fraction() -> f32 {
+    let fraction = MOVE_TIME_FRACTION.read().unwrap();
+    fraction.max(1.)
+}
+
+pub fn set_hard_limit_factor(f: f32) {
+    let mut factor = HARD_LIMIT_FACTOR.write().unwrap();
+    *factor = f;
+}
+
+pub fn get_hard_limit_factor() -> f32 {
+    let factor = HARD_LIMIT_FACTOR.read().unwrap();
+    factor.max(1.)
+}
+
+pub fn set_root_noise_epsilon(e: f32) {
+    let mut eps = ROOT_NOISE_EPSILON.write().unwrap();
+    *eps = e;
+}
+
+pub fn get_root_noise_epsilon() -> f32 {
+    let eps = ROOT_NOISE_EPSILON.read().unwrap();
+    *eps
+}
+
+pub fn set_root_noise_alpha(a: f32) {
+    let mut alpha = ROOT_NOISE_ALPHA.write().unwrap();
+    *alpha = a;
+}
+
+pub fn get_root_noise_alpha() -> f32 {
+    let alpha = ROOT_NOISE_ALPHA.read().unwrap();
+    *alpha
+}
+
 pub fn set_chess960(c: bool) {
     CHESS960.store(c, Ordering::Relaxed);
 }
@@ -54,3 +346,289 @@ pub fn set_chess960(c: bool) {
 pub fn is_chess960() -> bool {
     CHESS960.load(Ordering::Relaxed)
 }
+
+pub fn set_lenient_fen(l: bool) {
+    LENIENT_FEN.store(l, Ordering::Relaxed);
+}
+
+pub fn is_lenient_fen() -> bool {
+    LENIENT_FEN.load(Ordering::Relaxed)
+}
+
+pub fn set_deterministic(d: bool) {
+    DETERMINISTIC.store(d, Ordering::Relaxed);
+}
+
+pub fn is_deterministic() -> bool {
+    DETERMINISTIC.load(Ordering::Relaxed)
+}
+
+pub fn set_verbose_move_stats(v: bool) {
+    VERBOSE_MOVE_STATS.store(v, Ordering::Relaxed);
+}
+
+pub fn is_verbose_move_stats() -> bool {
+    VERBOSE_MOVE_STATS.load(Ordering::Relaxed)
+}
+
+pub fn set_search_stats(s: bool) {
+    SEARCH_STATS.store(s, Ordering::Relaxed);
+}
+
+pub fn is_search_stats() -> bool {
+    SEARCH_STATS.load(Ordering::Relaxed)
+}
+
+pub fn set_limit_strength(l: bool) {
+    LIMIT_STRENGTH.store(l, Ordering::Relaxed);
+}
+
+pub fn is_limit_strength() -> bool {
+    LIMIT_STRENGTH.load(Ordering::Relaxed)
+}
+
+pub fn set_human_like(h: bool) {
+    HUMAN_LIKE.store(h, Ordering::Relaxed);
+}
+
+pub fn is_human_like() -> bool {
+    HUMAN_LIKE.load(Ordering::Relaxed)
+}
+
+pub fn set_human_like_rate(r: f32) {
+    let mut rate = HUMAN_LIKE_RATE.write().unwrap();
+    *rate = r;
+}
+
+pub fn get_human_like_rate() -> f32 {
+    let rate = HUMAN_LIKE_RATE.read().unwrap();
+    rate.clamp(0., 1.)
+}
+
+pub fn set_elo(elo: usize) {
+    ELO.store(elo, Ordering::Relaxed);
+}
+
+pub fn get_elo() -> usize {
+    ELO.load(Ordering::Relaxed)
+}
+
+pub fn set_score_type(score_type: ScoreType) {
+    *SCORE_TYPE.write().unwrap() = score_type;
+}
+
+pub fn get_score_type() -> ScoreType {
+    *SCORE_TYPE.read().unwrap()
+}
+
+pub fn set_book_best_move(b: bool) {
+    BOOK_BEST_MOVE.store(b, Ordering::Relaxed);
+}
+
+pub fn is_book_best_move() -> bool {
+    BOOK_BEST_MOVE.load(Ordering::Relaxed)
+}
+
+pub fn set_show_wdl(w: bool) {
+    SHOW_WDL.store(w, Ordering::Relaxed);
+}
+
+pub fn is_show_wdl() -> bool {
+    SHOW_WDL.load(Ordering::Relaxed)
+}
+
+pub fn set_max_backup_weight(w: f32) {
+    let mut weight = MAX_BACKUP_WEIGHT.write().unwrap();
+    *weight = w;
+}
+
+pub fn get_max_backup_weight() -> f32 {
+    let weight = MAX_BACKUP_WEIGHT.read().unwrap();
+    weight.clamp(0., 1.)
+}
+
+pub fn set_dag_backup(d: bool) {
+    DAG_BACKUP.store(d, Ordering::Relaxed);
+}
+
+pub fn is_dag_backup() -> bool {
+    DAG_BACKUP.load(Ordering::Relaxed)
+}
+
+pub fn set_repetition_hashing(r: bool) {
+    REPETITION_HASHING.store(r, Ordering::Relaxed);
+}
+
+pub fn is_repetition_hashing() -> bool {
+    REPETITION_HASHING.load(Ordering::Relaxed)
+}
+
+pub fn set_history_guard(h: bool) {
+    HISTORY_GUARD.store(h, Ordering::Relaxed);
+}
+
+pub fn is_history_guard() -> bool {
+    HISTORY_GUARD.load(Ordering::Relaxed)
+}
+
+pub fn set_numa(n: bool) {
+    NUMA.store(n, Ordering::Relaxed);
+}
+
+pub fn is_numa() -> bool {
+    NUMA.load(Ordering::Relaxed)
+}
+
+/// A search constant that can be set with `setoption` and tuned with SPSA.
+pub struct Tunable {
+    pub name: &'static str,
+    pub default: f32,
+    pub min: f32,
+    pub max: f32,
+    // The SPSA perturbation at the end of tuning
+    pub step: f32,
+    pub get: fn() -> f32,
+    pub set: fn(f32),
+}
+
+pub static TUNABLES: &[Tunable] = &[
+    Tunable {
+        name: "CPuct",
+        default: 1.85,
+        min: 0.1,
+        max: 5.,
+        step: 0.1,
+        get: get_cpuct,
+        set: set_cpuct,
+    },
+    Tunable {
+        name: "CPuctBase",
+        default: 8192.,
+        min: 256.,
+        max: 65536.,
+        step: 512.,
+        get: get_cpuct_base,
+        set: set_cpuct_base,
+    },
+    Tunable {
+        name: "CPuctFactor",
+        default: 1.85,
+        min: 0.,
+        max: 5.,
+        step: 0.1,
+        get: get_cpuct_factor,
+        set: set_cpuct_factor,
+    },
+    Tunable {
+        name: "CVisitsSelection",
+        default: 0.01,
+        min: 0.,
+        max: 0.1,
+        step: 0.002,
+        get: get_cvisits_selection,
+        set: set_cvisits_selection,
+    },
+    Tunable {
+        name: "FPUReduction",
+        default: 0.,
+        min: 0.,
+        max: 1.,
+        step: 0.02,
+        get: get_fpu_reduction,
+        set: set_fpu_reduction,
+    },
+    Tunable {
+        name: "MovesLeftSlope",
+        default: 0.,
+        min: 0.,
+        max: 0.02,
+        step: 0.001,
+        get: get_moves_left_slope,
+        set: set_moves_left_slope,
+    },
+    Tunable {
+        name: "VirtualLoss",
+        default: 1.,
+        min: 0.,
+        max: 5.,
+        step: 0.1,
+        get: get_virtual_loss,
+        set: set_virtual_loss,
+    },
+    Tunable {
+        name: "PolicyTemperature",
+        default: 1.,
+        min: 0.5,
+        max: 2.,
+        step: 0.05,
+        get: get_policy_temperature,
+        set: set_policy_temperature,
+    },
+    Tunable {
+        name: "PolicyEntropyScale",
+        default: 0.,
+        min: 0.,
+        max: 2.,
+        step: 0.1,
+        get: get_policy_entropy_scale,
+        set: set_policy_entropy_scale,
+    },
+    Tunable {
+        name: "PolicyCheckBoost",
+        default: 1.,
+        min: 1.,
+        max: 4.,
+        step: 0.25,
+        get: get_policy_check_boost,
+        set: set_policy_check_boost,
+    },
+    Tunable {
+        name: "PolicyCaptureBoost",
+        default: 1.,
+        min: 1.,
+        max: 4.,
+        step: 0.25,
+        get: get_policy_capture_boost,
+        set: set_policy_capture_boost,
+    },
+    Tunable {
+        name: "PolicyPromotionBoost",
+        default: 1.,
+        min: 1.,
+        max: 4.,
+        step: 0.25,
+        get: get_policy_promotion_boost,
+        set: set_policy_promotion_boost,
+    },
+    Tunable {
+        name: "MoveTimeFraction",
+        default: 20.,
+        min: 5.,
+        max: 60.,
+        step: 1.,
+        get: get_move_time_fraction,
+        set: set_move_time_fraction,
+    },
+    Tunable {
+        name: "HardLimitFactor",
+        default: 3.,
+        min: 1.,
+        max: 6.,
+        step: 0.2,
+        get: get_hard_limit_factor,
+        set: set_hard_limit_factor,
+    },
+    Tunable {
+        name: "TreeReuseWeight",
+        default: 1.,
+        min: 0.,
+        max: 1.,
+        step: 0.05,
+        get: get_tree_reuse_weight,
+        set: set_tree_reuse_weight,
+    },
+];
+
+pub fn find_tunable(name: &str) -> Option<&'static Tunable> {
+    TUNABLES.iter().find(|t| t.name.eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,94 @@
+//! UCI output, written to stdout by a thread of its own so that a GUI slow to read
+//! it never holds up the search threads.
+
+#[cfg(not(target_arch = "wasm32"))]
+use once_cell::sync::Lazy;
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::{self, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::mpsc::{sync_channel, SyncSender};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+
+// Lines queued before the output thread catches up. Past this, info lines from the
+// search are dropped and everything else waits.
+#[cfg(not(target_arch = "wasm32"))]
+const CAPACITY: usize = 1024;
+
+#[cfg(not(target_arch = "wasm32"))]
+enum Message {
+    Line(String),
+    Flush(SyncSender<()>),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static OUTPUT: Lazy<SyncSender<Message>> = Lazy::new(|| {
+    let (sender, receiver) = sync_channel(CAPACITY);
+
+    thread::spawn(move || {
+        let stdout = io::stdout();
+
+        for message in receiver {
+            match message {
+                Message::Line(line) => {
+                    let mut out = stdout.lock();
+                    // Nothing to be done if the GUI has gone away
+                    let _ = writeln!(out, "{line}").and_then(|()| out.flush());
+                }
+                Message::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+
+    sender
+});
+
+/// Queues a line of output, waiting for room if the queue is full.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn line(line: String) {
+    let _ = OUTPUT.send(Message::Line(line));
+}
+
+/// Queues a line of search info, dropping it if the queue is full so the search
+/// carries on regardless.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn info(line: String) {
+    let _ = OUTPUT.try_send(Message::Line(line));
+}
+
+/// Waits until everything queued so far has been written.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn flush() {
+    let (done, wait) = sync_channel(0);
+
+    if OUTPUT.send(Message::Flush(done)).is_ok() {
+        let _ = wait.recv();
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(clippy::needless_pass_by_value)]
+pub fn line(line: String) {
+    println!("{line}");
+}
+
+#[cfg(target_arch = "wasm32")]
+#[allow(clippy::needless_pass_by_value)]
+pub fn info(line: String) {
+    println!("{line}");
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn flush() {}
+
+/// Like `println!`, but through the output thread.
+macro_rules! output {
+    () => {
+        $crate::output::line(String::new())
+    };
+    ($($arg:tt)*) => {
+        $crate::output::line(format!($($arg)*))
+    };
+}
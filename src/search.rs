@@ -1,18 +1,26 @@
-use shakmaty::{CastlingMode, Color, Move};
+use instant::Instant;
+use shakmaty::fen::Fen;
+use shakmaty::uci::Uci;
+use shakmaty::{CastlingMode, Color, Move, Piece, Setup, Square};
+use std::path::Path;
 use std::sync::mpsc::Sender;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-use crate::mcts::{AsyncSearchOwned, Mcts};
-use crate::options::{get_num_threads, is_chess960};
+use crate::book::probe_book;
+use crate::mcts::{AsyncSearchOwned, Mcts, SearchTree};
+use crate::options::{
+    get_hard_limit_factor, get_hash_size_mb, get_info_verbosity, get_move_overhead,
+    get_move_time_fraction, get_num_threads, get_root_noise_alpha, get_root_noise_epsilon,
+    is_chess960, is_human_like, is_limit_strength, is_search_stats, is_verbose_move_stats,
+};
 use crate::state::State;
+use crate::strength;
 use crate::tablebase::probe_tablebase_best_move;
-use crate::transposition_table::TranspositionTable;
+use crate::telemetry::{is_recording, record_search};
+use crate::transposition_table::{table_key, TranspositionTable};
 use crate::uci::Tokens;
 
 const DEFAULT_MOVE_TIME_SECS: u64 = 10;
-const DEFAULT_MOVE_TIME_FRACTION: u32 = 20;
-
-const MOVE_OVERHEAD: Duration = Duration::from_millis(50);
 
 pub const SCALE: f32 = 255. * 255.;
 
@@ -20,6 +28,11 @@ pub const SCALE: f32 = 255. * 255.;
 pub struct TimeManagement {
     start: Instant,
     end: Option<Instant>,
+    // Once past this, the search stops as soon as the best move is stable
+    soft_end: Option<Instant>,
+    node_limit: Option<usize>,
+    depth_limit: Option<usize>,
+    mate_limit: Option<i32>,
 }
 
 impl Default for TimeManagement {
@@ -33,16 +46,97 @@ impl TimeManagement {
         let start = Instant::now();
         let end = Some(start + d);
 
-        Self { start, end }
+        Self {
+            start,
+            end,
+            soft_end: None,
+            node_limit: None,
+            depth_limit: None,
+            mate_limit: None,
+        }
+    }
+
+    /// Allots time for a move from the side to move's clock and increment, and the
+    /// number of moves until the next time control if there is one. Time lost to
+    /// communication with the GUI is taken off the clock first.
+    pub fn from_clock(remaining: Duration, increment: Duration, movestogo: Option<u32>) -> Self {
+        let remaining = remaining.saturating_sub(move_overhead());
+
+        if movestogo.is_none() && increment.is_zero() && remaining < Duration::from_secs(60) {
+            return Self::from_duration(remaining / 60);
+        }
+
+        let move_time_fraction = match movestogo {
+            // plus 2 because we want / 3 to be the max_think_time
+            Some(m) => (m as f32 + 2.).min(get_move_time_fraction()),
+            None => get_move_time_fraction(),
+        };
+
+        let ideal_think_time = (remaining + 20 * increment).div_f32(move_time_fraction);
+        let max_think_time = remaining / 3;
+
+        // How far past its ideal time the search may go while it hasn't settled
+        let hard_think_time = ideal_think_time.mul_f32(get_hard_limit_factor());
+
+        Self::from_durations(
+            ideal_think_time.min(max_think_time),
+            hard_think_time.min(max_think_time),
+        )
+    }
+
+    /// Aims to use `soft`, but keeps going up to `hard` while the search is unstable.
+    pub fn from_durations(soft: Duration, hard: Duration) -> Self {
+        let tm = Self::from_duration(hard);
+
+        Self {
+            soft_end: Some(tm.start + soft.min(hard)),
+            ..tm
+        }
     }
 
     pub fn infinite() -> Self {
         Self {
             start: Instant::now(),
             end: None,
+            soft_end: None,
+            node_limit: None,
+            depth_limit: None,
+            mate_limit: None,
+        }
+    }
+
+    pub fn with_node_limit(self, node_limit: Option<usize>) -> Self {
+        Self { node_limit, ..self }
+    }
+
+    pub fn with_depth_limit(self, depth_limit: Option<usize>) -> Self {
+        Self {
+            depth_limit,
+            ..self
         }
     }
 
+    pub fn with_mate_limit(self, mate_limit: Option<i32>) -> Self {
+        Self { mate_limit, ..self }
+    }
+
+    /// Without an end time or any limits the search only ends on "stop".
+    pub fn is_infinite(&self) -> bool {
+        self.end.is_none()
+            && self.node_limit.is_none()
+            && self.depth_limit.is_none()
+            && self.mate_limit.is_none()
+    }
+
+    pub fn mate_limit(&self) -> Option<i32> {
+        self.mate_limit
+    }
+
+    pub fn is_past_limits(&self, nodes: usize, depth: usize) -> bool {
+        self.node_limit.map_or(false, |n| nodes >= n)
+            || self.depth_limit.map_or(false, |d| depth >= d)
+    }
+
     pub fn is_after_end(&self) -> bool {
         if let Some(end) = self.end {
             Instant::now() > end
@@ -51,13 +145,47 @@ impl TimeManagement {
         }
     }
 
+    /// Time left until the hard end, if there is one.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.end
+            .map(|end| end.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_after_soft_end(&self) -> bool {
+        self.soft_end.map_or(false, |end| Instant::now() > end)
+    }
+
     pub fn elapsed(&self) -> Duration {
         self.start.elapsed()
     }
+
+    /// The time the search aims to take, or the most it may take without a target.
+    pub fn allocated(&self) -> Option<Duration> {
+        self.soft_end.or(self.end).map(|end| end - self.start)
+    }
+
+    /// Same allotment of time, but counted from now.
+    pub fn restarted(&self) -> Self {
+        let start = Instant::now();
+        let end = self.end.map(|e| start + (e - self.start));
+        let soft_end = self.soft_end.map(|e| start + (e - self.start));
+
+        Self {
+            start,
+            end,
+            soft_end,
+            ..*self
+        }
+    }
+}
+
+fn move_overhead() -> Duration {
+    Duration::from_millis(get_move_overhead() as u64)
 }
 
 pub struct Search {
     search: AsyncSearchOwned,
+    ponder_time: Option<TimeManagement>,
 }
 
 impl Search {
@@ -67,20 +195,58 @@ impl Search {
 
     pub fn new(state: State, prev_table: TranspositionTable) -> Self {
         let search = Self::create_manager(state, prev_table).into();
-        Self { search }
+        Self {
+            search,
+            ponder_time: None,
+        }
+    }
+
+    /// Stops any search and clears the tables and tree for a new game at the start
+    /// position. Weakened play restarts its random choices from `SkillSeed`.
+    pub fn new_game(self) -> Self {
+        let manager = self.stop_and_print_m();
+        strength::restart_rng();
+        Self {
+            search: manager.new_game(State::default()).into(),
+            ponder_time: None,
+        }
     }
 
-    pub fn table(self) -> TranspositionTable {
+    /// Switches to `state`, reusing the existing tree when `state` is a near descendant.
+    pub fn set_position(self, state: State) -> Self {
         let manager = self.stop_and_print_m();
-        manager.table()
+        Self {
+            search: manager.reroot(state).into(),
+            ponder_time: None,
+        }
     }
+
     fn stop_and_print_m(self) -> Mcts {
         if self.search.num_threads() == 0 {
             return self.search.halt();
         }
+        let time_management = self.search.time_management();
         let manager = self.search.halt();
-        if let Some(mov) = manager.best_move() {
-            println!("bestmove {}", to_uci(&mov));
+        record_search(manager.tree());
+        if get_info_verbosity() >= 2 {
+            manager.tree().lr_table().print_stats();
+        }
+        if is_search_stats() {
+            manager.tree().print_search_stats(&time_management);
+        }
+        if is_verbose_move_stats() {
+            manager.tree().print_root_stats(false);
+        }
+        // A weakened move has no meaningful reply to ponder on
+        let pv = if is_limit_strength() || is_human_like() {
+            manager.weakened_move().into_iter().collect()
+        } else {
+            manager.principal_variation(2)
+        };
+        match pv.as_slice() {
+            [mov, ponder, ..] => output!("bestmove {} ponder {}", to_uci(mov), to_uci(ponder)),
+            [mov] => output!("bestmove {}", to_uci(mov)),
+            [] => (),
         }
         manager
     }
@@ -88,10 +254,28 @@ impl Search {
     pub fn stop_and_print(self) -> Self {
         Self {
             search: self.stop_and_print_m().into(),
+            ponder_time: None,
         }
     }
 
-    fn parse_ms(tokens: &mut Tokens) -> Option<Duration> {
+    pub fn ponderhit(self, sender: &Sender<String>) -> Self {
+        match self.ponder_time {
+            Some(think_time) => {
+                let manager = self.search.halt();
+                Self {
+                    search: manager.into_playout_parallel_async(
+                        get_num_threads(),
+                        think_time.restarted(),
+                        sender,
+                    ),
+                    ponder_time: None,
+                }
+            }
+            None => self,
+        }
+    }
+
+    fn parse_ms<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<Duration> {
         tokens
             .next()
             .unwrap_or("")
@@ -100,35 +284,29 @@ impl Search {
             .map(Duration::from_millis)
     }
 
-    pub fn go(self, mut tokens: Tokens, sender: &Sender<String>) -> Self {
-        let manager = self.stop_and_print_m();
+    pub fn go(self, tokens: Tokens, sender: &Sender<String>) -> Self {
+        let mut manager = self.stop_and_print_m();
+        manager.tree().reset_search_stats();
+        // Only counted when something reports them
+        manager
+            .tree()
+            .lr_table()
+            .set_counting(get_info_verbosity() >= 2 || is_recording());
+        let mut tokens = tokens.peekable();
 
         let state = manager.tree().root_state();
         let stm = state.side_to_move();
 
-        let mvs = state.available_moves();
-
-        if mvs.len() == 1 {
-            let uci_mv = to_uci(&mvs[0]);
-            println!("info depth 1 seldepth 1 nodes 1 nps 1 tbhits 0 time 1 pv {uci_mv}");
-            println!("bestmove {uci_mv}");
-            return Self {
-                search: manager.into(),
-            };
-        } else if let Some(mv) = probe_tablebase_best_move(state.board()) {
-            let uci_mv = to_uci(&mv);
-            println!("info depth 1 seldepth 1 nodes 1 nps 1 tbhits 1 time 1 pv {uci_mv}");
-            println!("bestmove {uci_mv}");
-            return Self {
-                search: manager.into(),
-            };
-        }
-
         let mut move_time = None;
         let mut increment = Duration::ZERO;
         let mut infinite = false;
+        let mut ponder = false;
         let mut remaining = None;
         let mut movestogo: Option<u32> = None;
+        let mut searchmoves = Vec::new();
+        let mut node_limit = None;
+        let mut depth_limit = None;
+        let mut mate_limit = None;
 
         while let Some(s) = tokens.next() {
             match s {
@@ -154,46 +332,238 @@ impl Search {
                     }
                 }
                 "infinite" => infinite = true,
+                "nodes" => node_limit = tokens.next().unwrap_or("").parse().ok(),
+                "depth" => depth_limit = tokens.next().unwrap_or("").parse().ok(),
+                "mate" => mate_limit = tokens.next().unwrap_or("").parse().ok(),
+                "ponder" => ponder = true,
                 "movestogo" => {
                     movestogo = tokens.next().unwrap_or("").parse().ok();
                 }
+                "searchmoves" => {
+                    while let Some(mv) = tokens
+                        .peek()
+                        .and_then(|t| t.parse::<Uci>().ok())
+                        .and_then(|uci| uci.to_move(state.board()).ok())
+                    {
+                        tokens.next();
+                        searchmoves.push(mv);
+                    }
+                }
                 _ => (),
             }
         }
 
+        // A finished game has nothing to search however the search was asked for, and
+        // playouts from a root without moves would go nowhere
+        if state.available_moves().is_empty() {
+            return Self {
+                search: manager.into(),
+                ponder_time: None,
+            };
+        }
+
+        // While pondering or analysing we must not send a bestmove before "stop" or "ponderhit"
+        if !ponder && !infinite && searchmoves.is_empty() {
+            let mvs = state.available_moves();
+
+            if mvs.len() == 1 {
+                let uci_mv = to_uci(&mvs[0]);
+                output!("info depth 1 seldepth 1 nodes 1 nps 1 tbhits 0 time 1 pv {uci_mv}");
+                output!("bestmove {uci_mv}");
+                return Self {
+                    search: manager.into(),
+                    ponder_time: None,
+                };
+            } else if let Some(mv) = probe_book(state) {
+                let uci_mv = to_uci(&mv);
+                if get_info_verbosity() >= 1 {
+                    output!("info string book move {uci_mv}");
+                }
+                output!("bestmove {uci_mv}");
+                return Self {
+                    search: manager.into(),
+                    ponder_time: None,
+                };
+            } else if let Some(mv) = probe_tablebase_best_move(state.board()) {
+                let uci_mv = to_uci(&mv);
+                output!("info depth 1 seldepth 1 nodes 1 nps 1 tbhits 1 time 1 pv {uci_mv}");
+                output!("bestmove {uci_mv}");
+                return Self {
+                    search: manager.into(),
+                    ponder_time: None,
+                };
+            }
+        }
+
         let mut think_time = TimeManagement::default();
 
-        if infinite {
+        let only_limits = move_time.is_none()
+            && remaining.is_none()
+            && (node_limit.is_some() || depth_limit.is_some() || mate_limit.is_some());
+
+        if infinite || only_limits {
             think_time = TimeManagement::infinite();
         } else if let Some(mt) = move_time {
-            think_time = TimeManagement::from_duration(mt);
+            think_time = TimeManagement::from_duration(mt.saturating_sub(move_overhead()));
         } else if let Some(r) = remaining {
-            think_time =
-                if movestogo.is_none() && increment.is_zero() && r < Duration::from_millis(60000) {
-                    TimeManagement::from_duration(r / 60)
-                } else {
-                    let move_time_fraction = match movestogo {
-                        // plus 2 because we want / 3 to be the max_think_time
-                        Some(m) => (m + 2).min(DEFAULT_MOVE_TIME_FRACTION),
-                        None => DEFAULT_MOVE_TIME_FRACTION,
-                    };
-
-                    let ideal_think_time =
-                        (r + 20 * increment - MOVE_OVERHEAD) / move_time_fraction;
-                    let max_think_time = r / 3;
-
-                    TimeManagement::from_duration(ideal_think_time.min(max_think_time))
-                }
+            think_time = TimeManagement::from_clock(r, increment, movestogo);
+        }
+
+        if is_limit_strength() && !infinite {
+            let cap = strength::node_limit();
+            node_limit = Some(node_limit.map_or(cap, |n: usize| n.min(cap)));
+        }
+
+        let think_time = think_time
+            .with_node_limit(node_limit)
+            .with_depth_limit(depth_limit)
+            .with_mate_limit(mate_limit);
+
+        // Always called, so moves left out by an earlier `searchmoves` come back
+        manager.restrict_root_moves(&searchmoves);
+
+        manager.apply_root_noise(get_root_noise_epsilon(), get_root_noise_alpha());
+
+        if ponder {
+            Self {
+                search: manager.into_playout_parallel_async(
+                    get_num_threads(),
+                    TimeManagement::infinite(),
+                    sender,
+                ),
+                ponder_time: Some(think_time),
+            }
+        } else {
+            Self {
+                search: manager.into_playout_parallel_async(get_num_threads(), think_time, sender),
+                ponder_time: None,
+            }
+        }
+    }
+
+    /// Applies the current `Hash` option to the existing tree.
+    pub fn resize_table(&self) {
+        self.search.get_manager().resize_table(get_hash_size_mb());
+    }
+
+    pub fn save_tree(self, path: &str) -> Self {
+        let manager = self.stop_and_print_m();
+
+        match manager.tree().save(Path::new(path)) {
+            Ok(()) => info!("Saved tree to {}", path),
+            Err(e) => error!("Unable to save tree to {}: {:?}", path, e),
         }
 
         Self {
-            search: manager.into_playout_parallel_async(get_num_threads(), think_time, sender),
+            search: manager.into(),
+            ponder_time: None,
         }
     }
 
+    pub fn export_tree(&self, path: &str, depth_limit: usize, min_visits: u32) {
+        let tree = self.search.get_manager().tree();
+
+        match tree.export(Path::new(path), depth_limit, min_visits) {
+            Ok(()) => info!("Exported tree to {}", path),
+            Err(e) => error!("Unable to export tree to {}: {:?}", path, e),
+        }
+    }
+
+    pub fn load_tree(self, path: &str) -> Self {
+        let manager = self.stop_and_print_m();
+
+        let manager = match SearchTree::load(Path::new(path)) {
+            Ok(tree) => {
+                info!("Loaded tree from {}", path);
+                Mcts::from_tree(tree)
+            }
+            Err(e) => {
+                error!("Unable to load tree from {}: {:?}", path, e);
+                manager
+            }
+        };
+
+        Self {
+            search: manager.into(),
+            ponder_time: None,
+        }
+    }
+
+    /// Prints the perft count of the current position, split by root move with `divide`.
+    pub fn perft(&self, depth: usize, divide: bool) {
+        let state = self.search.get_manager().tree().root_state();
+        let start = Instant::now();
+
+        let nodes = if divide && depth > 0 {
+            let mut nodes = 0;
+            for mv in &state.available_moves() {
+                let mut child = state.clone();
+                child.make_move(mv);
+                let n = child.perft(depth - 1);
+                output!("{}: {}", to_uci(mv), n);
+                nodes += n;
+            }
+            nodes
+        } else {
+            state.perft(depth)
+        };
+
+        let elapsed_ms = start.elapsed().as_millis().max(1) as u64;
+
+        output!(
+            "Nodes searched: {nodes} ({} nps)",
+            nodes * 1000 / elapsed_ms
+        );
+    }
+
+    pub fn print_tree(&self, children: bool) {
+        self.search.get_manager().tree().print_root_stats(children);
+    }
+
     pub fn print_move_list(&self) {
         self.search.get_manager().print_move_list();
     }
+
+    pub fn print_eval(&self) {
+        self.search.get_manager().print_eval();
+    }
+
+    /// Prints the root position as a diagram, its FEN and the key it has in the
+    /// transposition table, laid out the way Stockfish's `d` does.
+    pub fn print_board(&self) {
+        let state = self.search.get_manager().tree().root_state();
+        let board = state.board().board();
+        let separator = " +---+---+---+---+---+---+---+---+";
+
+        output!();
+        output!("{separator}");
+        for rank in (0..8).rev() {
+            let pieces: String = (0..8)
+                .map(|file| {
+                    let piece = board.piece_at(Square::new(rank * 8 + file));
+                    format!(" | {}", piece.map_or(' ', Piece::char))
+                })
+                .collect();
+            output!("{pieces} | {}", rank + 1);
+            output!("{separator}");
+        }
+        output!("   a   b   c   d   e   f   g   h");
+        output!();
+
+        let fen = Fen::from_setup(state.board()).to_string();
+        let castling = fen.split_whitespace().nth(2).unwrap_or("-");
+
+        output!("Fen: {fen}");
+        output!(
+            "Side to move: {}",
+            state.side_to_move().fold_wb("white", "black")
+        );
+        output!("Castling: {castling}");
+        output!("Halfmove clock: {}", state.halfmove_counter());
+        output!("Repetitions: {}", state.repetition_count());
+        output!("Zobrist key: {:016X}", state.zobrist_key());
+        output!("Key: {:016X}", table_key(state));
+    }
 }
 
 pub fn to_uci(mov: &Move) -> String {
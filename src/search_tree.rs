@@ -1,22 +1,56 @@
 use arrayvec::ArrayVec;
+use rand::rngs::SmallRng;
+use shakmaty::fen::Fen;
 use shakmaty::{Color, Position};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::mem;
-use std::ptr::null_mut;
-use std::sync::atomic::{AtomicI64, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
-
-use crate::arena::Error as ArenaError;
-use crate::evaluation::{self, Flag};
+use std::path::Path;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::str;
+use std::sync::atomic::{
+    AtomicI64, AtomicU16, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+use std::sync::Mutex;
+
+use crate::arena::{Error as ArenaError, Handle};
+use crate::evaluation::{self, Accumulators, Flag};
 use crate::math;
-use crate::mcts::{eval_in_cp, ThreadData};
-use crate::options::{get_cpuct, get_cvisits_selection};
-use crate::search::{to_uci, TimeManagement, SCALE};
-use crate::state::State;
-use crate::transposition_table::{LRAllocator, LRTable, TranspositionTable};
+use crate::mcts::{eval_in_cp, eval_in_wdl, ThreadData};
+use crate::move_code::MoveCode;
+use crate::options::{
+    get_cvisits_selection, get_info_interval, get_info_verbosity, get_tree_reuse_weight,
+    is_show_wdl,
+};
+use crate::output;
+use crate::search::{TimeManagement, SCALE};
+use crate::state::{Builder as StateBuilder, State};
+use crate::tablebase::probe_tablebase_root_moves;
+use crate::transposition_table::{table_key, LRAllocator, LRTable, TranspositionTable};
 use crate::tree_policy;
 
-const MAX_PLAYOUT_LENGTH: usize = 256;
+// Capacity of a playout's path; the MaxPlayoutLength option can only lower it
+pub const MAX_PLAYOUT_LENGTH: usize = 256;
+
+// Playouts between checks of the time and limits at most. Closer to the end the
+// interval shrinks so that about CHECKS_BEFORE_END checks fit in the time left.
+const MAX_CHECK_INTERVAL: usize = 128;
+const CHECKS_BEFORE_END: u64 = 8;
+
+const EVAL_HISTOGRAM_BUCKETS: usize = 10;
+
+// Tablebase results that can't be trusted yet (the halfmove clock isn't zero) only
+// bound the evaluation: wins score at least this, draws at most this far from zero.
+const TABLEBASE_WIN_BOUND: i64 = (SCALE * 0.5) as i64;
+const TABLEBASE_DRAW_BOUND: i64 = (SCALE * 0.1) as i64;
 
-const VIRTUAL_LOSS: i64 = SCALE as i64;
+const POLICY_MASK: u16 = 0x7fff;
+const PRUNED_BIT: u16 = 0x8000;
+
+// Fractions of a ply the average plies of a move are kept in
+const PLIES_SCALE: u64 = 256;
 
 /// You're not intended to use this class (use an `MctsManager` instead),
 /// but you can use it if you want to manage the threads yourself.
@@ -24,92 +58,364 @@ pub struct SearchTree {
     root_node: SearchNode,
     root_state: State,
 
-    cpuct: f32,
-
     #[allow(dead_code)]
     root_table: TranspositionTable,
     ttable: LRTable,
 
+    // Every step of every playout, so a node is counted each time it's visited
     num_nodes: AtomicUsize,
+    // Nodes added to the tree by this search
+    nodes_created: AtomicUsize,
+    // Playouts cut short by the maximum playout length
+    capped_playouts: AtomicUsize,
     playouts: AtomicUsize,
+    // Plies of every playout, for the average depth, and the longest playout
+    depth_sum: AtomicUsize,
     max_depth: AtomicUsize,
     tb_hits: AtomicUsize,
-    next_info: AtomicU64,
+    // Counts of leaf evaluations by value, from a loss to a win for White
+    eval_histogram: [AtomicUsize; EVAL_HISTOGRAM_BUCKETS],
+    // Search time in ms of the latest periodic info line
+    last_info: AtomicU64,
+    // Playouts between checks of the limits, and the search time in µs of the latest
+    check_interval: AtomicUsize,
+    last_check: AtomicU64,
+    // The root move the latest playout went through
+    last_root_move: AtomicU16,
+    // Helper trees of other thread groups leave the info lines to the main tree
+    is_helper: bool,
+    stability: Mutex<Stability>,
+    speed: Mutex<Speed>,
+    // Visits the root moves had when the search started
+    start_visits: Mutex<Vec<(MoveCode, u32)>>,
+
+    // Root policy before any noise was mixed in
+    root_priors: Option<Vec<f32>>,
+    // Number of root moves before `searchmoves` restricted them, and the policy the
+    // kept moves had between them
+    root_restriction: Option<(usize, f32)>,
+    rng: SmallRng,
 }
 
 pub struct HotMoveInfo {
     sum_evaluations: AtomicI64,
+    // Summed draw probabilities, scaled like the evaluations
+    sum_draws: AtomicI64,
     visits: AtomicU32,
-    policy: f32,
-    mov: shakmaty::Move,
-    child: AtomicPtr<SearchNode>,
+    // Average plies from this move to the end of the playouts through it, in
+    // 1/PLIES_SCALE plies, so it fits in 32 bits. Only kept up with for a moves left
+    // slope.
+    avg_plies: AtomicU32,
+    // The policy quantized to 15 bits, with the top bit marking a root move proven
+    // to lose, which is only searched if every move loses
+    policy: AtomicU16,
+    mov: MoveCode,
+    child: AtomicU32,
+}
+
+/// The moves of a node, in memory owned by an arena.
+///
+/// `ptr` points to `len` initialized moves that outlive the node, or dangles when
+/// `len` is zero. Each slice belongs to a single node and is only changed through
+/// the moves' atomics, except by the node's owner holding it mutably.
+struct HotSlice {
+    ptr: NonNull<HotMoveInfo>,
+    len: usize,
+}
+
+impl HotSlice {
+    const EMPTY: Self = Self {
+        ptr: NonNull::dangling(),
+        len: 0,
+    };
+
+    fn new(hots: &mut [HotMoveInfo]) -> Self {
+        Self {
+            len: hots.len(),
+            ptr: NonNull::from(hots).cast(),
+        }
+    }
+
+    fn as_slice(&self) -> &[HotMoveInfo] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [HotMoveInfo] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Takes back moves dropped by `truncate`, which are left in place behind the
+    /// kept ones.
+    ///
+    /// # Safety
+    ///
+    /// `len` must be at most the length the slice was created with.
+    unsafe fn untruncate(&mut self, len: usize) {
+        self.len = len;
+    }
 }
 
 pub struct SearchNode {
-    hots: *const [HotMoveInfo],
-    flag: Flag,
+    hots: HotSlice,
+    flag: AtomicU8,
+    plies_to_mate: AtomicU16,
+    // Backups through any parent, from the perspective of the player who moved here
+    visits: AtomicU32,
+    sum_evaluations: AtomicI64,
+    // The full table key of the position, as the table's entries only keep half of it
+    key: u64,
 }
 
+// Shared nodes only touch their moves through atomics
 unsafe impl Sync for SearchNode {}
 
-static DRAW_NODE: SearchNode = SearchNode::new(&[], Flag::TerminalDraw);
-static UNEXPANDED_NODE: SearchNode = SearchNode::new(&[], Flag::Standard);
+static DRAW_NODE: SearchNode = SearchNode::with_hots(HotSlice::EMPTY, Flag::TerminalDraw);
+static UNEXPANDED_NODE: SearchNode = SearchNode::with_hots(HotSlice::EMPTY, Flag::Standard);
 
 impl SearchNode {
-    const fn new(hots: &[HotMoveInfo], flag: Flag) -> Self {
-        Self { hots, flag }
+    fn new(hots: &mut [HotMoveInfo], flag: Flag, state: &State) -> Self {
+        Self {
+            key: table_key(state),
+            ..Self::with_hots(HotSlice::new(hots), flag)
+        }
+    }
+
+    const fn with_hots(hots: HotSlice, flag: Flag) -> Self {
+        Self {
+            hots,
+            flag: AtomicU8::new(flag.to_u8()),
+            plies_to_mate: AtomicU16::new(0),
+            visits: AtomicU32::new(0),
+            sum_evaluations: AtomicI64::new(0),
+            key: 0,
+        }
+    }
+
+    pub fn key(&self) -> u64 {
+        self.key
     }
 
     pub fn flag(&self) -> Flag {
-        self.flag
+        Flag::from_u8(self.flag.load(Ordering::Relaxed))
+    }
+
+    pub fn plies_to_mate(&self) -> u16 {
+        self.plies_to_mate.load(Ordering::Relaxed)
+    }
+
+    fn set_proven(&self, flag: Flag, plies_to_mate: u16) {
+        self.plies_to_mate.store(plies_to_mate, Ordering::Relaxed);
+        self.flag.store(flag.to_u8(), Ordering::Relaxed);
     }
 
-    pub fn set_flag(&mut self, flag: Flag) {
-        self.flag = flag;
+    pub fn copy_flag_from(&self, other: &SearchNode) {
+        self.set_proven(other.flag(), other.plies_to_mate());
     }
 
     pub fn is_terminal(&self) -> bool {
-        self.flag.is_terminal()
+        self.flag().is_terminal()
+    }
+
+    /// Mixes `evaln`, backed up through an edge into this node, with the value of the
+    /// node's best move so far, so a strong reply found below pulls the edge's average
+    /// towards it faster than plain averaging. `weight` is the share of the best move.
+    #[allow(clippy::cast_possible_truncation)]
+    fn max_mix(&self, evaln: i64, weight: f32) -> i64 {
+        // The moves' rewards are for the side to move here, the edge's for the other
+        match self
+            .hots()
+            .iter()
+            .filter_map(HotMoveInfo::average_reward)
+            .reduce(f32::max)
+        {
+            Some(best) => ((1. - weight) * evaln as f32 - weight * best) as i64,
+            None => evaln,
+        }
+    }
+
+    /// Records `evaln` for the node and returns the value to back up through `edge`.
+    /// When the node has been reached through other parents as well, the value is
+    /// corrected so that the edge's average moves to the node's average.
+    fn dag_backup(&self, edge: &HotMoveInfo, evaln: i64, virtual_loss: i64) -> i64 {
+        let visits = i64::from(self.visits.fetch_add(1, Ordering::Relaxed)) + 1;
+        let sum = self.sum_evaluations.fetch_add(evaln, Ordering::Relaxed) + evaln;
+
+        let edge_visits = i64::from(edge.visits());
+
+        if visits <= edge_visits {
+            return evaln;
+        }
+
+        // The edge's sum still carries this playout's virtual loss
+        let edge_sum = edge.sum_rewards() + virtual_loss;
+        let target = sum / visits * edge_visits - edge_sum;
+
+        target.clamp(-SCALE as i64, SCALE as i64)
     }
 
     pub fn is_tablebase(&self) -> bool {
-        self.flag.is_tablebase()
+        self.flag().is_tablebase()
     }
 
-    pub fn hots(&self) -> &[HotMoveInfo] {
-        unsafe { &*(self.hots as *const [HotMoveInfo]) }
+    /// Marks the node as a proven win if any child is a win for `stm`,
+    /// or as a proven loss if every child is a loss for `stm`.
+    /// Returns whether the node is now proven.
+    fn try_prove(&self, stm: Color) -> bool {
+        let win = stm.fold_wb(Flag::TerminalWin, Flag::TerminalLoss);
+        let loss = win.flip();
+
+        let hots = self.hots();
+
+        let mut shortest_win = None;
+        let mut longest_loss = 0;
+        let mut all_lost = !hots.is_empty();
+
+        for hot in hots {
+            match hot.child() {
+                Some(child) if child.flag() == win => {
+                    let plies = child.plies_to_mate();
+                    shortest_win = Some(shortest_win.map_or(plies, |p: u16| p.min(plies)));
+                }
+                Some(child) if child.flag() == loss => {
+                    longest_loss = longest_loss.max(child.plies_to_mate());
+                }
+                _ => all_lost = false,
+            }
+        }
+
+        if let Some(plies) = shortest_win {
+            self.set_proven(win, plies + 1);
+            true
+        } else if all_lost {
+            self.set_proven(loss, longest_loss + 1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Moves to mate from the perspective of `stm`, if the node is proven.
+    pub fn mate_in(&self, stm: Color) -> Option<i32> {
+        let flag = self.flag();
+        let plies = i32::from(self.plies_to_mate());
+
+        if !flag.is_decisive() {
+            None
+        } else if flag == stm.fold_wb(Flag::TerminalWin, Flag::TerminalLoss) {
+            Some((plies + 1) / 2)
+        } else {
+            Some(-plies / 2)
+        }
     }
 
-    fn update_policy(&mut self, evals: &[f32]) {
-        let hots = unsafe { &mut *(self.hots as *mut [HotMoveInfo]) };
+    pub fn hots(&self) -> &[HotMoveInfo] {
+        self.hots.as_slice()
+    }
 
-        for i in 0..hots.len().min(evals.len()) {
-            hots[i].policy = evals[i];
+    fn update_policy(&self, evals: &[f32]) {
+        for (hot, &policy) in self.hots().iter().zip(evals) {
+            hot.set_policy(policy);
         }
     }
 
-    pub fn clear_children_links(&self) {
-        let hots = unsafe { &*(self.hots as *mut [HotMoveInfo]) };
+    /// Shrinks the node to only the given moves, renormalizing the policy over them,
+    /// and returns the policy they had between them. Leaves the node untouched and
+    /// returns `None` if none of the moves are present.
+    fn restrict_to(&mut self, moves: &[MoveCode]) -> Option<f32> {
+        let hots = self.hots.as_mut_slice();
+
+        let mut kept = 0;
+        for i in 0..hots.len() {
+            if moves.contains(&hots[i].get_move()) {
+                hots.swap(kept, i);
+                kept += 1;
+            }
+        }
+
+        if kept == 0 {
+            return None;
+        }
+
+        let total_policy: f32 = hots[..kept].iter().map(HotMoveInfo::policy).sum();
+
+        if total_policy <= 0. {
+            self.hots.truncate(kept);
+            return Some(1.);
+        }
+
+        for h in &hots[..kept] {
+            h.set_policy(h.policy() / total_policy);
+        }
+
+        self.hots.truncate(kept);
+        Some(total_policy)
+    }
 
-        for h in hots {
-            h.child.store(null_mut(), Ordering::SeqCst);
+    /// Undoes `restrict_to`, given the number of moves before it and what it returned.
+    fn unrestrict(&mut self, len: usize, total_policy: f32) {
+        for h in self.hots() {
+            h.set_policy(h.policy() * total_policy);
         }
+
+        // The dropped moves were only swapped past the end of the slice
+        unsafe { self.hots.untruncate(len) };
     }
 }
 
+#[allow(clippy::cast_sign_loss)]
+fn quantize_policy(policy: f32) -> u16 {
+    (policy.clamp(0., 1.) * f32::from(POLICY_MASK)).round() as u16
+}
+
+// Nodes are only linked while their table is alive, which a playout guarantees
+fn resolve<'a>(handle: Handle) -> &'a SearchNode {
+    unsafe { handle.get() }.expect("null node handle")
+}
+
 impl HotMoveInfo {
-    fn new(policy: f32, mov: shakmaty::Move) -> Self {
+    fn new(policy: f32, mov: &shakmaty::Move) -> Self {
         Self {
-            policy,
+            policy: AtomicU16::new(quantize_policy(policy)),
             sum_evaluations: AtomicI64::default(),
+            sum_draws: AtomicI64::default(),
             visits: AtomicU32::default(),
-            mov,
-            child: AtomicPtr::default(),
+            avg_plies: AtomicU32::default(),
+            mov: MoveCode::from(mov),
+            child: AtomicU32::default(),
         }
     }
 
-    pub fn get_move(&self) -> &shakmaty::Move {
-        &self.mov
+    pub fn get_move(&self) -> MoveCode {
+        self.mov
+    }
+
+    fn child(&self) -> Option<&SearchNode> {
+        unsafe { self.child_handle().get() }
+    }
+
+    fn child_handle(&self) -> Handle {
+        Handle::from_bits(self.child.load(Ordering::Relaxed))
+    }
+
+    fn set_child(&self, child: Handle) {
+        self.child.store(child.bits(), Ordering::Relaxed);
+    }
+
+    /// Links `child` unless another thread linked a child first, returning that one.
+    fn link_child(&self, child: Handle) -> Result<(), Handle> {
+        self.child
+            .compare_exchange(
+                Handle::NULL.bits(),
+                child.bits(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .map(|_| ())
+            .map_err(Handle::from_bits)
     }
 
     pub fn visits(&self) -> u32 {
@@ -120,8 +426,30 @@ impl HotMoveInfo {
         self.sum_evaluations.load(Ordering::Relaxed)
     }
 
+    pub fn is_pruned(&self) -> bool {
+        self.policy.load(Ordering::Relaxed) & PRUNED_BIT != 0
+    }
+
+    fn set_pruned(&self) {
+        self.policy.fetch_or(PRUNED_BIT, Ordering::Relaxed);
+    }
+
+    pub fn sum_draws(&self) -> i64 {
+        self.sum_draws.load(Ordering::Relaxed)
+    }
+
+    pub fn sum_plies(&self) -> u64 {
+        u64::from(self.avg_plies.load(Ordering::Relaxed)) * u64::from(self.visits()) / PLIES_SCALE
+    }
+
     pub fn policy(&self) -> f32 {
+        f32::from(self.policy.load(Ordering::Relaxed) & POLICY_MASK) / f32::from(POLICY_MASK)
+    }
+
+    fn set_policy(&self, policy: f32) {
+        let pruned = self.policy.load(Ordering::Relaxed) & PRUNED_BIT;
         self.policy
+            .store(pruned | quantize_policy(policy), Ordering::Relaxed);
     }
 
     pub fn average_reward(&self) -> Option<f32> {
@@ -131,15 +459,74 @@ impl HotMoveInfo {
         }
     }
 
-    pub fn down(&self) {
+    pub fn average_draw(&self) -> Option<f32> {
+        match self.visits() {
+            0 => None,
+            x => Some(self.sum_draws() as f32 / x as f32),
+        }
+    }
+
+    pub fn average_plies(&self) -> Option<f32> {
+        match self.visits() {
+            0 => None,
+            _ => Some(self.avg_plies.load(Ordering::Relaxed) as f32 / PLIES_SCALE as f32),
+        }
+    }
+
+    pub fn down(&self, virtual_loss: i64) {
         self.sum_evaluations
-            .fetch_sub(VIRTUAL_LOSS, Ordering::Relaxed);
+            .fetch_sub(virtual_loss, Ordering::Relaxed);
         self.visits.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn up(&self, evaln: i64) {
-        let delta = evaln + VIRTUAL_LOSS;
+    /// Undoes `down` for a playout that was abandoned.
+    fn revert_down(&self, virtual_loss: i64) {
+        self.sum_evaluations
+            .fetch_add(virtual_loss, Ordering::Relaxed);
+        self.visits.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn up(&self, evaln: i64, draw: i64, virtual_loss: i64) {
+        let delta = evaln + virtual_loss;
         self.sum_evaluations.fetch_add(delta, Ordering::Relaxed);
+        self.sum_draws.fetch_add(draw, Ordering::Relaxed);
+    }
+
+    /// Folds `sum_plies` over the last `count` visits into the average. Those visits
+    /// must already be counted.
+    fn add_plies(&self, sum_plies: u64, count: u32) {
+        let visits = u64::from(self.visits().max(count));
+        if visits == 0 {
+            return;
+        }
+        let earlier = visits - u64::from(count);
+
+        let _ = self
+            .avg_plies
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |avg| {
+                let total = u64::from(avg) * earlier + sum_plies * PLIES_SCALE;
+                Some((total / visits).min(u64::from(u32::MAX)) as u32)
+            });
+    }
+
+    fn add_stats(&self, visits: u32, sum_evaluations: i64, sum_draws: i64, sum_plies: u64) {
+        self.visits.fetch_add(visits, Ordering::Relaxed);
+        self.sum_evaluations
+            .fetch_add(sum_evaluations, Ordering::Relaxed);
+        self.sum_draws.fetch_add(sum_draws, Ordering::Relaxed);
+        self.add_plies(sum_plies, visits);
+    }
+
+    fn duplicate(&self) -> Self {
+        Self {
+            sum_evaluations: AtomicI64::new(self.sum_rewards()),
+            sum_draws: AtomicI64::new(self.sum_draws()),
+            visits: AtomicU32::new(self.visits()),
+            avg_plies: AtomicU32::new(self.avg_plies.load(Ordering::Relaxed)),
+            policy: AtomicU16::new(self.policy.load(Ordering::Relaxed)),
+            mov: self.mov,
+            child: AtomicU32::new(self.child.load(Ordering::Relaxed)),
+        }
     }
 
     pub fn replace(&self, other: &HotMoveInfo) {
@@ -149,12 +536,76 @@ impl HotMoveInfo {
             other.sum_evaluations.load(Ordering::Relaxed),
             Ordering::Relaxed,
         );
+        self.sum_draws
+            .store(other.sum_draws.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.avg_plies
+            .store(other.avg_plies.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+// Playouts since the stability snapshot needed to judge it, and after which it's renewed
+const STABILITY_MIN_PLAYOUTS: usize = 1024;
+const STABILITY_WINDOW: usize = 16384;
+// Share of recent playouts the best move needs, and how far its eval may drop
+const STABLE_VISIT_FRACTION: f32 = 0.5;
+const STABLE_EVAL_DROP: f32 = 0.05;
+
+/// The best root move, its visits and the eval when the search's stability was last
+/// measured.
+#[derive(Default)]
+struct Stability {
+    playouts: usize,
+    best: Option<usize>,
+    best_visits: u32,
+    eval: f32,
+}
+
+// Weight of the latest info interval in the smoothed nodes per second
+const NPS_SMOOTHING: f32 = 0.25;
+
+/// The search time and node count at the latest info line, and the exponentially
+/// smoothed nodes per second up to it.
+#[derive(Default)]
+struct Speed {
+    time_ms: u128,
+    nodes: usize,
+    smoothed_nps: Option<f32>,
+}
+
+/// The moves a playout made, and where it ended.
+struct Playout<'a> {
+    path: ArrayVec<&'a HotMoveInfo, MAX_PLAYOUT_LENGTH>,
+    parents: ArrayVec<(&'a SearchNode, Color), MAX_PLAYOUT_LENGTH>,
+    state: State,
+    // Set below a tablebase node that can't be trusted yet
+    tablebase_bound: Option<Flag>,
+    // Taken from each move on the path until the playout is backed up or reverted
+    virtual_loss: i64,
+}
+
+impl Playout<'_> {
+    /// Undoes the virtual loss of a playout that won't be backed up.
+    fn revert(&self) {
+        for choice in &self.path {
+            choice.revert_down(self.virtual_loss);
+        }
     }
 }
 
+enum Descent<'a> {
+    // A position not evaluated yet
+    Leaf(Playout<'a>),
+    // A node whose result is already known
+    Known(Playout<'a>, &'a SearchNode),
+    Stopped,
+    Full,
+}
+
 fn create_node<'a, F>(
     state: &State,
+    depth: usize,
     tb_hits: &AtomicUsize,
+    accumulators: &mut Accumulators,
     alloc_slice: F,
 ) -> Result<SearchNode, ArenaError>
 where
@@ -162,8 +613,8 @@ where
 {
     let moves = state.available_moves();
 
-    let state_flag = evaluation::evaluate_state_flag(state, &moves);
-    let move_eval = evaluation::evaluate_policy(state, &moves);
+    let state_flag = evaluation::evaluate_state_flag(state, &moves, depth);
+    let move_eval = evaluation::evaluate_policy(state, &moves, accumulators);
 
     if state_flag.is_tablebase() {
         tb_hits.fetch_add(1, Ordering::Relaxed);
@@ -171,9 +622,18 @@ where
 
     let hots = alloc_slice(move_eval.len())?;
     for (i, x) in hots.iter_mut().enumerate() {
-        *x = HotMoveInfo::new(move_eval[i], moves[i].clone());
+        *x = HotMoveInfo::new(move_eval[i], &moves[i]);
+    }
+    Ok(SearchNode::new(hots, state_flag, state))
+}
+
+// Drops the root moves that throw away the position's tablebase result, so a won
+// position is never searched into a draw or loss
+fn restrict_to_tablebase_moves(root_node: &mut SearchNode, state: &State) {
+    if let Some(moves) = probe_tablebase_root_moves(state.board()) {
+        let codes: Vec<MoveCode> = moves.iter().map(MoveCode::from).collect();
+        root_node.restrict_to(&codes);
     }
-    Ok(SearchNode::new(hots, state_flag))
 }
 
 impl SearchTree {
@@ -186,218 +646,795 @@ impl SearchTree {
 
         let root_table = TranspositionTable::for_root();
 
-        let mut root_node = create_node(&state, &tb_hits, |sz| {
+        let mut accumulators = Accumulators::default();
+        let mut root_node = create_node(&state, 0, &tb_hits, &mut accumulators, |sz| {
             root_table.arena().allocator().alloc_slice(sz)
         })
         .expect("Unable to create root node");
 
         previous_table.lookup_into(&state, &mut root_node);
-
-        let mut avg_rewards: Vec<f32> = root_node
-            .hots()
-            .iter()
-            .map(|m| m.average_reward().unwrap_or(-SCALE) / SCALE)
-            .collect();
-
-        math::softmax(&mut avg_rewards);
-
-        root_node.update_policy(&avg_rewards);
+        carry_over_root_policy(&root_node);
+        restrict_to_tablebase_moves(&mut root_node, &state);
 
         Self {
             root_state: state,
             root_node,
-            cpuct: get_cpuct(),
             root_table,
             ttable: LRTable::new(current_table, previous_table),
             num_nodes: 1.into(),
+            nodes_created: 0.into(),
+            capped_playouts: 0.into(),
             playouts: 0.into(),
+            depth_sum: 0.into(),
             max_depth: 0.into(),
             tb_hits,
-            next_info: 0.into(),
+            eval_histogram: Default::default(),
+            last_info: 0.into(),
+            check_interval: MAX_CHECK_INTERVAL.into(),
+            last_check: 0.into(),
+            last_root_move: 0.into(),
+            is_helper: false,
+            stability: Mutex::default(),
+            speed: Mutex::default(),
+            start_visits: Mutex::default(),
+            root_priors: None,
+            root_restriction: None,
+            rng: math::rng(),
+        }
+    }
+
+    /// Visits, summed rewards, draws and plies of each root move, in root order.
+    pub fn root_stats(&self) -> Vec<(u32, i64, i64, u64)> {
+        self.root_node
+            .hots()
+            .iter()
+            .map(|h| (h.visits(), h.sum_rewards(), h.sum_draws(), h.sum_plies()))
+            .collect()
+    }
+
+    /// Adds statistics gathered for `mov` by another tree over the same root.
+    pub fn add_root_stats(
+        &self,
+        mov: MoveCode,
+        visits: u32,
+        sum_evaluations: i64,
+        sum_draws: i64,
+        sum_plies: u64,
+    ) {
+        if let Some(hot) = self.root_node.hots().iter().find(|h| h.get_move() == mov) {
+            hot.add_stats(visits, sum_evaluations, sum_draws, sum_plies);
+        }
+    }
+
+    /// Moves the root down to the node reached by one or two plies that lead to `state`,
+    /// keeping the whole subtree below it. Falls back to a fresh tree seeded from the
+    /// table when no such node exists.
+    pub fn reroot(self, state: State) -> Self {
+        let root_table = TranspositionTable::for_root();
+
+        let root_node = self.find_descendant(&state).and_then(|node| {
+            let hots = root_table
+                .arena()
+                .allocator()
+                .alloc_slice(node.hots().len())
+                .ok()?;
+            for (dst, src) in hots.iter_mut().zip(node.hots()) {
+                *dst = src.duplicate();
+            }
+            let root = SearchNode::new(hots, node.flag(), &state);
+            root.copy_flag_from(node);
+            Some(root)
+        });
+
+        self.ttable.reset_counters();
+
+        match root_node {
+            Some(mut root_node) => {
+                restrict_to_tablebase_moves(&mut root_node, &state);
+
+                Self {
+                    root_state: state,
+                    root_node,
+                    root_table,
+                    ttable: self.ttable,
+                    num_nodes: 1.into(),
+                    nodes_created: 0.into(),
+                    capped_playouts: 0.into(),
+                    playouts: 0.into(),
+                    depth_sum: 0.into(),
+                    max_depth: 0.into(),
+                    tb_hits: 0.into(),
+                    eval_histogram: Default::default(),
+                    last_info: 0.into(),
+                    check_interval: MAX_CHECK_INTERVAL.into(),
+                    last_check: 0.into(),
+                    last_root_move: 0.into(),
+                    is_helper: false,
+                    stability: Mutex::default(),
+                    speed: Mutex::default(),
+                    start_visits: Mutex::default(),
+                    root_priors: None,
+                    root_restriction: None,
+                    rng: self.rng,
+                }
+            }
+            None => Self::new(state, TranspositionTable::empty(), self.table()),
+        }
+    }
+
+    /// Limits the root to `moves`, or to every move again if there are none.
+    pub fn restrict_root_moves(&mut self, moves: &[shakmaty::Move]) {
+        if let Some(priors) = self.root_priors.take() {
+            self.root_node.update_policy(&priors);
         }
+        if let Some((len, total_policy)) = self.root_restriction.take() {
+            self.root_node.unrestrict(len, total_policy);
+        }
+        if moves.is_empty() {
+            return;
+        }
+
+        let len = self.root_node.hots().len();
+        let codes: Vec<MoveCode> = moves.iter().map(MoveCode::from).collect();
+        self.root_restriction = self
+            .root_node
+            .restrict_to(&codes)
+            .map(|total_policy| (len, total_policy));
     }
 
-    fn flip_tables(&self) {
-        self.ttable.flip_tables();
+    /// Marks the tree as a helper of another thread group, which prints no info lines.
+    pub fn set_helper(&mut self) {
+        self.is_helper = true;
+    }
+
+    /// Mixes fresh Dirichlet noise into the root policy. Always starts from the
+    /// noiseless priors, so noise does not compound when the root is searched again.
+    pub fn apply_root_noise(&mut self, epsilon: f32, alpha: f32) {
+        let num_moves = self.root_node.hots().len();
+
+        if epsilon <= 0. || num_moves < 2 {
+            return;
+        }
+
+        let priors = self.root_priors.get_or_insert_with(|| {
+            self.root_node
+                .hots()
+                .iter()
+                .map(HotMoveInfo::policy)
+                .collect()
+        });
+
+        let noise = math::dirichlet(&mut self.rng, alpha, num_moves);
+
+        let noised: Vec<f32> = priors
+            .iter()
+            .zip(noise)
+            .map(|(p, n)| (1. - epsilon) * p + epsilon * n)
+            .collect();
+
+        self.root_node.update_policy(&noised);
+    }
+
+    fn find_descendant(&self, state: &State) -> Option<&SearchNode> {
+        let expected_moves = state.available_moves().len();
+        let is_match = |s: &State, node: &SearchNode| {
+            s.zobrist_key() == state.zobrist_key() && node.hots().len() == expected_moves
+        };
+
+        for hot in self.root_node.hots() {
+            let Some(child) = hot.child() else {
+                continue;
+            };
+            let Some(mov) = hot.get_move().to_move(&self.root_state) else {
+                continue;
+            };
+
+            let mut child_state = self.root_state.clone();
+            child_state.make_move(&mov);
+
+            if is_match(&child_state, child) {
+                return Some(child);
+            }
+
+            for grandchild_hot in child.hots() {
+                let Some(grandchild) = grandchild_hot.child() else {
+                    continue;
+                };
+                let Some(mov) = grandchild_hot.get_move().to_move(&child_state) else {
+                    continue;
+                };
+
+                let mut grandchild_state = child_state.clone();
+                grandchild_state.make_move(&mov);
+
+                if is_match(&grandchild_state, grandchild) {
+                    return Some(grandchild);
+                }
+            }
+        }
+
+        None
     }
 
     pub fn table(self) -> TranspositionTable {
         self.ttable.table()
     }
 
+    /// A fresh tree at `state` over the same tables, emptied so nothing carries over.
+    pub fn new_game(self, state: State) -> Self {
+        self.ttable.clear();
+        let (current, previous) = self.ttable.into_tables();
+        Self::new(state, current, previous)
+    }
+
+    /// Resizes the tree's tables to fit in `hash_size_mb` between them.
+    pub fn resize_table(&self, hash_size_mb: usize) {
+        self.ttable.set_size_mb(hash_size_mb / 2);
+    }
+
     pub fn num_nodes(&self) -> usize {
         self.num_nodes.load(Ordering::Relaxed)
     }
 
+    pub fn nodes_created(&self) -> usize {
+        self.nodes_created.load(Ordering::Relaxed)
+    }
+
+    pub fn capped_playouts(&self) -> usize {
+        self.capped_playouts.load(Ordering::Relaxed)
+    }
+
     pub fn playouts(&self) -> usize {
         self.playouts.load(Ordering::Relaxed)
     }
 
+    pub fn depth_sum(&self) -> usize {
+        self.depth_sum.load(Ordering::Relaxed)
+    }
+
+    /// The average length of the playouts this search, rounded.
+    pub fn avg_depth(&self) -> usize {
+        let playouts = self.playouts().max(1);
+        (self.depth_sum() + playouts / 2) / playouts
+    }
+
     pub fn max_depth(&self) -> usize {
         self.max_depth.load(Ordering::Relaxed)
     }
 
+    /// Starts the counters reported for a search over, so a search of a tree kept
+    /// from the last one reports only its own work.
+    pub fn reset_search_stats(&self) {
+        self.num_nodes.store(1, Ordering::Relaxed);
+        self.nodes_created.store(0, Ordering::Relaxed);
+        self.capped_playouts.store(0, Ordering::Relaxed);
+        self.playouts.store(0, Ordering::Relaxed);
+        self.depth_sum.store(0, Ordering::Relaxed);
+        self.max_depth.store(0, Ordering::Relaxed);
+        self.tb_hits.store(0, Ordering::Relaxed);
+        for bucket in &self.eval_histogram {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.last_info.store(0, Ordering::Relaxed);
+        self.check_interval
+            .store(MAX_CHECK_INTERVAL, Ordering::Relaxed);
+        self.last_check.store(0, Ordering::Relaxed);
+        *self.stability.lock().unwrap() = Stability::default();
+        *self.speed.lock().unwrap() = Speed::default();
+        *self.start_visits.lock().unwrap() = self
+            .root_node
+            .hots()
+            .iter()
+            .map(|h| (h.get_move(), h.visits()))
+            .collect();
+        self.ttable.reset_counters();
+    }
+
     pub fn tb_hits(&self) -> usize {
         self.tb_hits.load(Ordering::Relaxed)
     }
 
+    /// Replaces the root's children, left in the previous table by a flip, with copies
+    /// in the current one so their statistics survive. Their own children are found
+    /// again through the table as they get searched.
+    fn relink_root_children<'a>(&'a self, allocator: &LRAllocator<'a>) {
+        for hot in self.root_node.hots() {
+            let copy = hot.child().and_then(|child| {
+                let mov = hot.get_move().to_move(&self.root_state)?;
+                let mut state = self.root_state.clone();
+                state.make_move(&mov);
+                self.copy_node(child, &state, allocator).ok()
+            });
+
+            hot.set_child(copy.unwrap_or(Handle::NULL));
+        }
+    }
+
+    fn copy_node<'a>(
+        &'a self,
+        node: &SearchNode,
+        state: &State,
+        allocator: &LRAllocator<'a>,
+    ) -> Result<Handle, ArenaError> {
+        let hots = allocator.alloc_move_info(node.hots().len())?;
+        for (dst, src) in hots.iter_mut().zip(node.hots()) {
+            *dst = src.duplicate();
+            dst.set_child(Handle::NULL);
+        }
+
+        let (handle, copy) = allocator.alloc_node()?;
+        *copy = SearchNode::new(hots, node.flag(), state);
+        copy.copy_flag_from(node);
+
+        Ok(self.ttable.insert(state, handle).unwrap_or(handle))
+    }
+
+    pub fn lr_table(&self) -> &LRTable {
+        &self.ttable
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    fn record_eval(&self, evaln: i64) {
+        let q = (evaln as f32 / SCALE).clamp(-1., 1.);
+        let bucket = ((q + 1.) / 2. * EVAL_HISTOGRAM_BUCKETS as f32) as usize;
+
+        self.eval_histogram[bucket.min(EVAL_HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn eval_histogram(&self) -> Vec<usize> {
+        self.eval_histogram
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect()
+    }
+
     pub fn allocator(&self) -> LRAllocator {
         self.ttable.allocator()
     }
 
+    /// Runs up to `tld.batch_size` playouts. The leaves reached are evaluated together
+    /// once they have all been selected, with virtual loss steering the selections apart.
     #[inline(never)]
     pub fn playout<'a: 'b, 'b>(
         &'a self,
         tld: &'b mut ThreadData<'a>,
         time_management: TimeManagement,
     ) -> bool {
-        let mut state = self.root_state.clone();
+        // Flip ahead of time rather than losing playouts to a full arena
+        if self.ttable.is_arena_past_watermark() {
+            self.ttable
+                .flip_when_quiescent(|| self.relink_root_children(&tld.allocator));
+        }
+
+        let playout_guard = self.ttable.enter_playout();
+
+        let mut leaves: Vec<Playout> = Vec::with_capacity(tld.batch_size);
+        let mut check_limits = false;
+        let check_interval = self.check_interval.load(Ordering::Relaxed);
+
+        let mut count_playout = |playouts: usize| {
+            check_limits |= playouts % check_interval == 0;
+        };
+
+        for _ in 0..tld.batch_size {
+            match self.select_leaf(tld) {
+                Descent::Leaf(leaf) => leaves.push(leaf),
+                Descent::Known(playout, node) => {
+                    count_playout(self.backup(&playout, node, 0, 0, tld));
+                }
+                Descent::Stopped => {
+                    leaves.iter().for_each(Playout::revert);
+                    return false;
+                }
+                Descent::Full => {
+                    leaves.iter().for_each(Playout::revert);
+                    drop(playout_guard);
+                    self.ttable
+                        .flip_when_quiescent(|| self.relink_root_children(&tld.allocator));
+                    return true;
+                }
+            }
+        }
+
+        let evals = evaluation::evaluate_states(
+            leaves.iter().map(|leaf| &leaf.state),
+            &mut tld.accumulators,
+        );
+
+        for (leaf, (evaln, draw)) in leaves.iter().zip(evals) {
+            self.record_eval(evaln);
+            count_playout(self.backup(leaf, &UNEXPANDED_NODE, evaln, draw, tld));
+        }
+
+        if check_limits {
+            self.update_check_interval(&time_management, check_interval);
+        }
+
+        if check_limits
+            && !time_management.is_infinite()
+            && (time_management.is_after_end()
+                || (time_management.is_after_soft_end() && self.is_stable())
+                || self.is_best_move_decided(&time_management)
+                || time_management.is_past_limits(self.num_nodes(), self.avg_depth())
+                || self.is_mate_found(&time_management))
+        {
+            self.print_info(&time_management);
+            return false;
+        }
+
+        let interval = get_info_interval() as u64;
+
+        if check_limits && interval > 0 {
+            let elapsed = time_management.elapsed().as_millis() as u64;
+            let last_info = self.last_info.load(Ordering::Relaxed);
+
+            if elapsed >= last_info + interval
+                && self
+                    .last_info
+                    .compare_exchange(last_info, elapsed, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                self.print_info(&time_management);
+            }
+        }
+
+        true
+    }
+
+    /// Sizes the next check interval from the speed since the last check, so the end
+    /// of the search time is noticed soon after it passes however fast playouts are.
+    fn update_check_interval(&self, time_management: &TimeManagement, interval: usize) {
+        let now = time_management.elapsed().as_micros() as u64;
+        let last = self.last_check.swap(now, Ordering::Relaxed);
+
+        let next = match time_management.remaining() {
+            Some(remaining) if now > last => {
+                let per_check = remaining.as_micros() as u64 / CHECKS_BEFORE_END;
+                (interval as u64 * per_check / (now - last)) as usize
+            }
+            _ => MAX_CHECK_INTERVAL,
+        };
+
+        self.check_interval
+            .store(next.clamp(1, MAX_CHECK_INTERVAL), Ordering::Relaxed);
+    }
+
+    /// Walks down the tree to a position that needs evaluating or whose result is known.
+    fn select_leaf<'a>(&'a self, tld: &mut ThreadData<'a>) -> Descent<'a> {
+        let mut playout = Playout {
+            path: ArrayVec::new(),
+            parents: ArrayVec::new(),
+            state: self.root_state.clone(),
+            tablebase_bound: None,
+            virtual_loss: tld.virtual_loss,
+        };
         let mut node = &self.root_node;
-        let mut path: ArrayVec<&HotMoveInfo, MAX_PLAYOUT_LENGTH> = ArrayVec::new();
-        let mut evaln = 0;
+        // A move of `node` the playout has to take, set by the history guard
+        let mut forced = None;
         loop {
-            {
-                let _lock = self.ttable.flip_lock().lock().unwrap();
+            if tld.is_stopped() {
+                playout.revert();
+                return Descent::Stopped;
             }
-            if node.is_terminal() {
+            // A proven root still needs its children searched to pick a move
+            if node.is_terminal() && !playout.path.is_empty() {
                 break;
             }
             if node.hots().is_empty() {
                 break;
             }
-            if node.is_tablebase() && state.halfmove_counter() == 0 {
-                break;
+            if node.is_tablebase() {
+                if playout.state.halfmove_counter() == 0 {
+                    break;
+                }
+                playout.tablebase_bound = Some(node.flag());
             }
-            if path.len() >= MAX_PLAYOUT_LENGTH {
-                break;
+            // Evaluate where the playout got to rather than back up a stale value
+            if playout.path.len() >= tld.max_playout_length {
+                self.capped_playouts.fetch_add(1, Ordering::Relaxed);
+                return Descent::Leaf(playout);
+            }
+            let choice = forced.take().unwrap_or_else(|| {
+                tree_policy::choose_child(
+                    node.hots(),
+                    tld.cpuct,
+                    tld.fpu_reduction,
+                    tld.moves_left_slope,
+                    playout.path.is_empty(),
+                )
+            });
+            // Moves that don't fit the position belong to a node linked in by mistake,
+            // so the playout stops and evaluates the position itself
+            let Some(mov) = choice.get_move().to_move(&playout.state) else {
+                return Descent::Leaf(playout);
+            };
+            choice.down(playout.virtual_loss);
+            if playout.path.is_empty() {
+                self.last_root_move
+                    .store(choice.get_move().bits(), Ordering::Relaxed);
             }
-            let choice = tree_policy::choose_child(node.hots(), self.cpuct, path.is_empty());
-            choice.down();
-            path.push(choice);
-            state.make_move(&choice.mov);
+            // Start loading the child while the move is made
+            choice.child_handle().prefetch();
+            playout.path.push(choice);
+            playout.parents.push((node, playout.state.side_to_move()));
+            playout.state.make_move(&mov);
 
             if choice.visits() == 1 {
-                evaln = evaluation::evaluate_state(&state);
-                node = &UNEXPANDED_NODE;
-                break;
+                return Descent::Leaf(playout);
+            }
+            if choice.child_handle().is_null() {
+                self.ttable.prefetch(&playout.state);
             }
 
-            let new_node = match self.descend(&state, choice, tld) {
+            (node, forced) = match self.descend(&playout.state, playout.path.len(), choice, tld) {
                 Ok(r) => r,
                 Err(ArenaError::Full) => {
-                    let _lock = self.ttable.flip_lock().lock().unwrap();
-                    if self.ttable.is_arena_full() {
-                        self.flip_tables();
-                        self.root_node.clear_children_links();
-                    }
-                    return true;
+                    playout.revert();
+                    return Descent::Full;
                 }
             };
+        }
 
-            node = new_node;
+        Descent::Known(playout, node)
+    }
+
+    /// Backs up a playout that ended at `node`, using `evaln` (White relative) and
+    /// `draw` if its result isn't known. Returns the number of playouts so far.
+    fn backup(
+        &self,
+        playout: &Playout,
+        node: &SearchNode,
+        evaln: i64,
+        draw: i64,
+        tld: &ThreadData,
+    ) -> usize {
+        if node.flag().is_decisive() {
+            for (parent, stm) in playout.parents.iter().rev() {
+                if !parent.try_prove(*stm) {
+                    break;
+                }
+            }
+
+            let root_loss = self
+                .root_state
+                .side_to_move()
+                .fold_wb(Flag::TerminalLoss, Flag::TerminalWin);
+
+            if let Some(root_move) = playout.path.first() {
+                if root_move.child().map_or(false, |c| c.flag() == root_loss) {
+                    root_move.set_pruned();
+                }
+            }
         }
 
-        evaln = match node.flag {
-            Flag::TerminalWin | Flag::TablebaseWin => SCALE as i64,
-            Flag::TerminalLoss | Flag::TablebaseLoss => -SCALE as i64,
-            Flag::TerminalDraw | Flag::TablebaseDraw => 0,
-            Flag::Standard => evaln,
+        let (mut evaln, draw) = match node.flag() {
+            Flag::TerminalWin | Flag::TablebaseWin => (SCALE as i64, 0),
+            Flag::TerminalLoss | Flag::TablebaseLoss => (-SCALE as i64, 0),
+            Flag::TerminalDraw | Flag::TablebaseDraw => (0, SCALE as i64),
+            Flag::Standard => match playout.tablebase_bound {
+                Some(Flag::TablebaseWin) => (evaln.max(TABLEBASE_WIN_BOUND), draw),
+                Some(Flag::TablebaseLoss) => (evaln.min(-TABLEBASE_WIN_BOUND), draw),
+                Some(Flag::TablebaseDraw) => (
+                    evaln.clamp(-TABLEBASE_DRAW_BOUND, TABLEBASE_DRAW_BOUND),
+                    draw,
+                ),
+                _ => (evaln, draw),
+            },
         };
 
-        let last_move_was_black = state.side_to_move() == Color::White;
+        let last_move_was_black = playout.state.side_to_move() == Color::White;
 
         if last_move_was_black {
             evaln = -evaln;
         };
 
-        Self::finish_playout(&path, evaln);
+        Self::finish_playout(playout, evaln, draw, tld);
 
+        let depth = playout.path.len();
         // -1 because we don't count the root node
-        let depth = path.len() - 1;
-        self.num_nodes.fetch_add(depth, Ordering::Relaxed);
+        self.num_nodes.fetch_add(depth.saturating_sub(1), Ordering::Relaxed);
+        self.depth_sum.fetch_add(depth, Ordering::Relaxed);
         self.max_depth.fetch_max(depth, Ordering::Relaxed);
-        let playouts = self.playouts.fetch_add(1, Ordering::Relaxed) + 1;
+        self.playouts.fetch_add(1, Ordering::Relaxed) + 1
+    }
 
-        if playouts % 128 == 0 && time_management.is_after_end() {
-            self.print_info(&time_management);
+    /// Whether the most visited root move can't be overtaken in the time left, going
+    /// by the playout rate so far.
+    fn is_best_move_decided(&self, time_management: &TimeManagement) -> bool {
+        let remaining = match time_management.remaining() {
+            Some(r) => r.as_secs_f32(),
+            None => return false,
+        };
+
+        let elapsed = time_management.elapsed().as_secs_f32();
+
+        if elapsed <= 0. {
             return false;
         }
 
-        if playouts % 65536 == 0 {
-            let elapsed = time_management.elapsed().as_secs();
+        // Visits kept from earlier searches say nothing about how this one is going
+        let start_visits = self.start_visits.lock().unwrap();
+        let gained = self.root_node.hots().iter().map(|h| {
+            let start = start_visits
+                .iter()
+                .find(|&&(mv, _)| mv == h.get_move())
+                .map_or(0, |&(_, visits)| visits);
+            h.visits().saturating_sub(start)
+        });
+
+        let (mut first, mut second) = (0, 0);
+        for visits in gained {
+            if visits > first {
+                second = first;
+                first = visits;
+            } else if visits > second {
+                second = visits;
+            }
+        }
 
-            let next_info = self.next_info.fetch_max(elapsed, Ordering::Relaxed);
+        let playouts_left = self.playouts() as f32 / elapsed * remaining;
 
-            if next_info < elapsed {
-                self.print_info(&time_management);
+        self.root_node.hots().len() > 1 && (first - second) as f32 > playouts_left
+    }
+
+    /// Whether the search has settled: the best move is unchanged since the last
+    /// snapshot, took most of the playouts since then and its eval isn't falling.
+    fn is_stable(&self) -> bool {
+        let hots = self.root_node.hots();
+        let best = select_child_after_search(hots, self.root_state.side_to_move());
+        let best_idx = hots.iter().position(|h| ptr::eq(h, best));
+        let playouts = self.playouts();
+        let eval = self.eval();
+
+        let mut snapshot = self.stability.lock().unwrap();
+
+        let recent = playouts.saturating_sub(snapshot.playouts);
+        let best_recent = best.visits().saturating_sub(snapshot.best_visits);
+
+        let stable = snapshot.best == best_idx
+            && recent >= STABILITY_MIN_PLAYOUTS
+            && best_recent as f32 >= STABLE_VISIT_FRACTION * recent as f32
+            && eval >= snapshot.eval - STABLE_EVAL_DROP;
+
+        if snapshot.best != best_idx || recent >= STABILITY_WINDOW {
+            *snapshot = Stability {
+                playouts,
+                best: best_idx,
+                best_visits: best.visits(),
+                eval,
+            };
+        }
+
+        stable
+    }
+
+    /// Prints each root move's visits, average reward from the root's point of view,
+    /// prior and proven result, most visited first. With `children`, the most visited
+    /// reply to each move is shown too, valued from the replying side's point of view.
+    pub fn print_root_stats(&self, children: bool) {
+        let mut hots: Vec<&HotMoveInfo> = self.root_node.hots().iter().collect();
+        hots.sort_by_key(|h| std::cmp::Reverse(h.visits()));
+
+        let q = |h: &HotMoveInfo| {
+            h.average_reward()
+                .map_or("n/a".to_string(), |r| format!("{:.4}", r / SCALE))
+        };
+
+        for hot in hots {
+            let flag = hot.child().map_or(Flag::Standard, SearchNode::flag);
+
+            let mut line = format!(
+                "info string {:>6} N: {:>9} Q: {:>7} P: {:>6.2}% {:?}",
+                hot.get_move().to_uci(),
+                hot.visits(),
+                q(hot),
+                hot.policy() * 100.,
+                flag,
+            );
+
+            let reply = hot
+                .child()
+                .and_then(|c| c.hots().iter().max_by_key(|h| h.visits()));
+
+            if let (true, Some(reply)) = (children, reply) {
+                line += &format!(
+                    " | {} N: {} Q: {}",
+                    reply.get_move().to_uci(),
+                    reply.visits(),
+                    q(reply),
+                );
             }
+
+            output!("{line}");
         }
+    }
 
-        true
+    pub fn root_mate_in(&self) -> Option<i32> {
+        self.root_node.mate_in(self.root_state.side_to_move())
+    }
+
+    fn is_mate_found(&self, time_management: &TimeManagement) -> bool {
+        match (time_management.mate_limit(), self.root_mate_in()) {
+            (Some(limit), Some(mate)) => mate > 0 && mate <= limit,
+            _ => false,
+        }
     }
 
+    /// The node `choice` leads to, along with a move the playout has to take from it
+    /// when the history guard finds one.
     fn descend<'a>(
         &'a self,
         state: &State,
+        depth: usize,
         choice: &HotMoveInfo,
         tld: &mut ThreadData<'a>,
-    ) -> Result<&'a SearchNode, ArenaError> {
+    ) -> Result<(&'a SearchNode, Option<&'a HotMoveInfo>), ArenaError> {
         if state.is_repetition()
             || state.drawn_by_fifty_move_rule()
             || state.board().is_insufficient_material()
         {
-            return Ok(&DRAW_NODE);
+            return Ok((&DRAW_NODE, None));
         }
 
-        let child = choice.child.load(Ordering::Relaxed) as *const SearchNode;
+        let child = choice.child_handle();
         if !child.is_null() {
-            return unsafe { Ok(&*child) };
+            return Ok((resolve(child), None));
         }
 
         if let Some(node) = self.ttable.lookup(state) {
-            return match choice.child.compare_exchange(
-                null_mut(),
-                node as *const _ as *mut _,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => Ok(node),
-                Err(_) => unsafe { Ok(&*child) },
+            let linked = resolve(choice.link_child(node).err().unwrap_or(node));
+            let repeating = if tld.history_guard {
+                repeating_move(state, linked)
+            } else {
+                None
             };
+            return Ok((linked, repeating));
         }
 
         let mut created_here =
-            create_node(state, &self.tb_hits, |sz| tld.allocator.alloc_move_info(sz))?;
+            create_node(state, depth, &self.tb_hits, &mut tld.accumulators, |sz| {
+                tld.allocator.alloc_move_info(sz)
+            })?;
 
         self.ttable.lookup_into(state, &mut created_here);
 
-        let created = tld.allocator.alloc_node()?;
+        let (handle, created) = tld.allocator.alloc_node()?;
 
         *created = created_here;
-        let other_child = choice.child.compare_exchange(
-            null_mut(),
-            created as *mut _,
-            Ordering::Relaxed,
-            Ordering::Relaxed,
-        );
-        if let Err(v) = other_child {
-            unsafe {
-                return Ok(&*v);
-            }
+        if let Err(other) = choice.link_child(handle) {
+            return Ok((resolve(other), None));
         }
 
-        if let Some(existing) = self.ttable.insert(state, created) {
-            let existing_ptr = existing as *const _ as *mut _;
-            choice.child.store(existing_ptr, Ordering::Relaxed);
-            return Ok(existing);
+        if let Some(existing) = self.ttable.insert(state, handle) {
+            choice.set_child(existing);
+            return Ok((resolve(existing), None));
         }
-        Ok(created)
+        self.nodes_created.fetch_add(1, Ordering::Relaxed);
+        Ok((created, None))
     }
 
-    fn finish_playout(path: &[&HotMoveInfo], evaln: i64) {
+    /// Backs up `evaln`, flipping sides each ply, and `draw`, which is the same for both.
+    /// With a `max_backup_weight` the value leaving each node is mixed with the value of
+    /// its best move rather than passed up unchanged. Plies to the end of the playout
+    /// are only kept up with for a moves left slope.
+    fn finish_playout(playout: &Playout, evaln: i64, draw: i64, tld: &ThreadData) {
+        let max_backup_weight = tld.max_backup_weight;
+        let track_plies = tld.moves_left_slope != 0.;
+        let virtual_loss = playout.virtual_loss;
         let mut evaln_value = evaln;
-        for move_info in path.iter().rev() {
-            move_info.up(evaln_value);
+        for (plies, move_info) in (1..).zip(playout.path.iter().rev()) {
+            if let Some(child) = move_info.child() {
+                if max_backup_weight > 0. {
+                    evaln_value = child.max_mix(evaln_value, max_backup_weight);
+                }
+                if tld.dag_backup {
+                    evaln_value = child.dag_backup(move_info, evaln_value, virtual_loss);
+                }
+            }
+            move_info.up(evaln_value, draw, virtual_loss);
+            if track_plies {
+                move_info.add_plies(plies, 1);
+            }
             evaln_value = -evaln_value;
         }
     }
@@ -413,15 +1450,14 @@ impl SearchTree {
     pub fn principal_variation(&self, num_moves: usize) -> Vec<&HotMoveInfo> {
         let mut result = Vec::new();
         let mut crnt = &self.root_node;
+        let mut stm = self.root_state.side_to_move();
         while !crnt.hots().is_empty() && result.len() < num_moves {
-            let choice = select_child_after_search(crnt.hots());
+            let choice = select_child_after_search(crnt.hots(), stm);
             result.push(choice);
-            let child = choice.child.load(Ordering::SeqCst) as *const SearchNode;
-            if child.is_null() {
-                break;
-            }
-            unsafe {
-                crnt = &*child;
+            stm = !stm;
+            match choice.child() {
+                Some(child) => crnt = child,
+                None => break,
             }
         }
         result
@@ -430,33 +1466,112 @@ impl SearchTree {
     fn print_info(&self, time_management: &TimeManagement) {
         let search_time_ms = time_management.elapsed().as_millis();
 
-        if search_time_ms == 0 {
+        if search_time_ms == 0 || self.is_helper {
             return;
         }
 
         let nodes = self.num_nodes();
-        let depth = nodes / self.playouts();
+        let depth = self.avg_depth();
         let sel_depth = self.max_depth();
         let pv = self.principal_variation(depth.max(2));
         let pv_string: String = pv
             .into_iter()
-            .map(|x| format!(" {}", to_uci(x.get_move())))
+            .map(|x| format!(" {}", x.get_move().to_uci()))
             .collect();
 
         let nps = nodes * 1000 / search_time_ms as usize;
+        let smoothed_nps = self.smoothed_nps(search_time_ms, nodes);
+
+        let verbosity = get_info_verbosity();
+
+        let last_root_move = self.last_root_move.load(Ordering::Relaxed);
+        let curr = self
+            .root_node
+            .hots()
+            .iter()
+            .enumerate()
+            .find(|(_, h)| h.get_move().bits() == last_root_move);
+        if let Some((curr_idx, curr)) = curr.filter(|_| verbosity >= 1) {
+            output::info(format!(
+                "info currmove {} currmovenumber {}",
+                curr.get_move().to_uci(),
+                curr_idx + 1
+            ));
+        }
+
+        if verbosity >= 2 {
+            self.ttable.print_stats();
+
+            let playouts = self.playouts();
+            let created = self.nodes_created();
+            output::info(format!(
+                "info string playouts {} pps {} created {} cps {} nps smoothed {}",
+                playouts,
+                playouts * 1000 / search_time_ms as usize,
+                created,
+                created * 1000 / search_time_ms as usize,
+                smoothed_nps
+            ));
+        }
 
         let info_str = format!(
-            "info depth {} seldepth {} nodes {} nps {} tbhits {} score {} time {} pv{}",
+            "info depth {} seldepth {} nodes {} nps {} hashfull {} tbhits {} score {} time {} pv{}",
             depth.max(1),
             sel_depth.max(1),
             nodes,
             nps,
+            self.ttable.hashfull(),
             self.tb_hits(),
-            self.eval_in_cp(),
+            self.score(),
             search_time_ms,
             pv_string,
         );
-        println!("{info_str}");
+        output::info(info_str);
+    }
+
+    /// Prints a summary of the search that just ended, for tuning options.
+    pub fn print_search_stats(&self, time_management: &TimeManagement) {
+        let playouts = self.playouts();
+        let root_moves = self.root_node.hots();
+        let searched = root_moves.iter().filter(|h| h.visits() > 0).count();
+
+        output!(
+            "info string stats playouts {} created {} capped {} flips {} depth {:.1} rootmoves {}/{} time {} allocated {}",
+            playouts,
+            self.nodes_created(),
+            self.capped_playouts(),
+            self.ttable.flips(),
+            self.depth_sum() as f32 / playouts.max(1) as f32,
+            searched,
+            root_moves.len(),
+            time_management.elapsed().as_millis(),
+            time_management
+                .allocated()
+                .map_or("none".to_string(), |d| d.as_millis().to_string()),
+        );
+    }
+
+    /// Folds the nodes searched since the last info line into the smoothed nodes per
+    /// second, which follows changes in speed without the swings of short intervals.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn smoothed_nps(&self, time_ms: u128, nodes: usize) -> usize {
+        let mut speed = self.speed.lock().unwrap();
+
+        if time_ms > speed.time_ms {
+            let recent =
+                nodes.saturating_sub(speed.nodes) as f32 * 1000. / (time_ms - speed.time_ms) as f32;
+            let smoothed = speed.smoothed_nps.map_or(recent, |s| {
+                NPS_SMOOTHING * recent + (1. - NPS_SMOOTHING) * s
+            });
+
+            *speed = Speed {
+                time_ms,
+                nodes,
+                smoothed_nps: Some(smoothed),
+            };
+        }
+
+        speed.smoothed_nps.unwrap_or(0.) as usize
     }
 
     pub fn eval(&self) -> f32 {
@@ -465,15 +1580,106 @@ impl SearchTree {
             .map_or(0., |x| x.average_reward().unwrap_or(-SCALE) / SCALE)
     }
 
+    /// Draw probability of the best move, in [0, 1].
+    pub fn draw(&self) -> f32 {
+        self.principal_variation(1)
+            .get(0)
+            .map_or(0., |x| x.average_draw().unwrap_or(0.) / SCALE)
+    }
+
     fn eval_in_cp(&self) -> String {
         eval_in_cp(self.eval())
     }
+
+    fn score(&self) -> String {
+        let score = match self.root_mate_in() {
+            Some(mate) => format!("mate {mate}"),
+            None => self.eval_in_cp(),
+        };
+
+        if !is_show_wdl() {
+            return score;
+        }
+
+        let wdl = match self.root_mate_in() {
+            Some(mate) if mate > 0 => eval_in_wdl(1., 0.),
+            Some(_) => eval_in_wdl(-1., 0.),
+            None => eval_in_wdl(self.eval(), self.draw()),
+        };
+
+        format!("{score} {wdl}")
+    }
+}
+
+/// Blends the root policy with a softmax over the values the previous search found
+/// for each move, giving them a share of `TreeReuseWeight`. The policy is left alone
+/// without previous visits.
+fn carry_over_root_policy(root_node: &SearchNode) {
+    let hots = root_node.hots();
+    let visits: u32 = hots.iter().map(HotMoveInfo::visits).sum();
+    let weight = get_tree_reuse_weight();
+
+    if visits == 0 || weight <= 0. {
+        return;
+    }
+
+    let mut avg_rewards: Vec<f32> = hots
+        .iter()
+        .map(|m| m.average_reward().unwrap_or(-SCALE) / SCALE)
+        .collect();
+
+    math::softmax(&mut avg_rewards);
+
+    let blended: Vec<f32> = hots
+        .iter()
+        .zip(avg_rewards)
+        .map(|(h, q)| (1. - weight) * h.policy() + weight * q)
+        .collect();
+
+    root_node.update_policy(&blended);
+}
+
+// Plies without a zeroing move needed to repeat a position
+const MIN_REPETITION_PLIES: usize = 4;
+
+/// Guards against graph history interaction. A node found in the transposition table
+/// holds values from every path into it, so when its most visited move repeats a
+/// position of this playout, that move is a draw here whatever its subtree says. The
+/// move is returned so the playout goes down it and backs up the draw.
+fn repeating_move<'a>(state: &State, node: &'a SearchNode) -> Option<&'a HotMoveInfo> {
+    if state.halfmove_counter() + 1 < MIN_REPETITION_PLIES {
+        return None;
+    }
+
+    node.hots()
+        .iter()
+        .filter(|h| h.visits() > 0)
+        .max_by_key(|h| h.visits())
+        .filter(|best| {
+            best.get_move()
+                .to_move(state)
+                .map_or(false, |mov| state.repeats_after(&mov))
+        })
 }
 
-fn select_child_after_search(children: &[HotMoveInfo]) -> &HotMoveInfo {
+fn select_child_after_search(children: &[HotMoveInfo], stm: Color) -> &HotMoveInfo {
     let k = get_cvisits_selection();
+    let win = stm.fold_wb(Flag::TerminalWin, Flag::TerminalLoss);
 
     let reward = |child: &HotMoveInfo| {
+        // Proven results trump statistics: take the fastest win or the slowest loss
+        if let Some(node) = child.child() {
+            let flag = node.flag();
+            if flag.is_decisive() {
+                let plies = f32::from(node.plies_to_mate());
+                return if flag == win {
+                    2. * SCALE - plies
+                } else {
+                    -2. * SCALE + plies
+                };
+            }
+        }
+
         let visits = child.visits();
 
         if visits == 0 {
@@ -500,9 +1706,459 @@ fn select_child_after_search(children: &[HotMoveInfo]) -> &HotMoveInfo {
 }
 
 pub fn print_size_list() {
-    println!(
+    output!(
         "info string SearchNode {} HotMoveInfo {}",
         mem::size_of::<SearchNode>(),
         mem::size_of::<HotMoveInfo>(),
     );
 }
+
+// Tree files: magic, version, root FEN, then the nodes in preorder. Each node is
+// its flag, plies to mate and move count, followed by its moves. Each move is followed
+// by its child: none, a new node inline, or the index of a node written earlier.
+const TREE_FILE_MAGIC: &[u8; 4] = b"PTRE";
+const TREE_FILE_VERSION: u32 = 4;
+
+const NO_CHILD: u8 = 0;
+const NEW_CHILD: u8 = 1;
+const SEEN_CHILD: u8 = 2;
+
+fn invalid_tree(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_bytes<R: Read, const N: usize>(r: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_node<W: Write>(
+    w: &mut W,
+    node: &SearchNode,
+    ids: &mut HashMap<Handle, u32>,
+) -> io::Result<()> {
+    let hots = node.hots();
+
+    w.write_all(&[node.flag().to_u8()])?;
+    w.write_all(&node.plies_to_mate().to_le_bytes())?;
+    w.write_all(&(hots.len() as u16).to_le_bytes())?;
+
+    for hot in hots {
+        w.write_all(&hot.get_move().bits().to_le_bytes())?;
+        w.write_all(&hot.policy().to_le_bytes())?;
+        w.write_all(&hot.visits().to_le_bytes())?;
+        w.write_all(&hot.sum_rewards().to_le_bytes())?;
+        w.write_all(&hot.sum_draws().to_le_bytes())?;
+        w.write_all(&hot.sum_plies().to_le_bytes())?;
+
+        match hot.child() {
+            None => w.write_all(&[NO_CHILD])?,
+            Some(child) => {
+                let handle = hot.child_handle();
+                if let Some(id) = ids.get(&handle) {
+                    w.write_all(&[SEEN_CHILD])?;
+                    w.write_all(&id.to_le_bytes())?;
+                } else {
+                    ids.insert(handle, ids.len() as u32);
+                    w.write_all(&[NEW_CHILD])?;
+                    write_node(w, child, ids)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct TreeLoader<'a> {
+    allocator: LRAllocator<'a>,
+    ttable: &'a LRTable,
+    nodes: Vec<Handle>,
+}
+
+impl<'a> TreeLoader<'a> {
+    fn read_header<R: Read>(r: &mut R) -> io::Result<(Flag, u16, usize)> {
+        let [flag] = read_bytes::<_, 1>(r)?;
+        let plies_to_mate = u16::from_le_bytes(read_bytes(r)?);
+        let num_hots = u16::from_le_bytes(read_bytes(r)?);
+        Ok((Flag::from_u8(flag), plies_to_mate, usize::from(num_hots)))
+    }
+
+    fn read_node<R: Read>(&mut self, r: &mut R, state: &State) -> io::Result<Handle> {
+        let too_big = |_| io::Error::new(io::ErrorKind::Other, "tree does not fit in the hash");
+
+        let (flag, plies_to_mate, num_hots) = Self::read_header(r)?;
+
+        // Registered before the children are read, as they may link back to it
+        let (handle, node) = self.allocator.alloc_node().map_err(too_big)?;
+        self.nodes.push(handle);
+
+        let hots = self.allocator.alloc_move_info(num_hots).map_err(too_big)?;
+        self.read_hots(r, state, hots)?;
+
+        *node = SearchNode::new(hots, flag, state);
+        node.set_proven(flag, plies_to_mate);
+
+        self.ttable.insert(state, handle);
+        Ok(handle)
+    }
+
+    fn read_hots<R: Read>(
+        &mut self,
+        r: &mut R,
+        state: &State,
+        hots: &mut [HotMoveInfo],
+    ) -> io::Result<()> {
+        let moves = state.available_moves();
+
+        for hot in hots {
+            let code = u16::from_le_bytes(read_bytes(r)?);
+            let policy = f32::from_le_bytes(read_bytes(r)?);
+            let visits = u32::from_le_bytes(read_bytes(r)?);
+            let sum_evaluations = i64::from_le_bytes(read_bytes(r)?);
+            let sum_draws = i64::from_le_bytes(read_bytes(r)?);
+            let sum_plies = u64::from_le_bytes(read_bytes(r)?);
+
+            let mov = moves
+                .iter()
+                .find(|m| MoveCode::from(*m).bits() == code)
+                .ok_or_else(|| invalid_tree("illegal move in tree"))?;
+
+            *hot = HotMoveInfo::new(policy, mov);
+            hot.add_stats(visits, sum_evaluations, sum_draws, sum_plies);
+
+            let child = match read_bytes::<_, 1>(r)? {
+                [NO_CHILD] => continue,
+                [NEW_CHILD] => {
+                    let mut child_state = state.clone();
+                    child_state.make_move(mov);
+                    self.read_node(r, &child_state)?
+                }
+                [SEEN_CHILD] => {
+                    let id = u32::from_le_bytes(read_bytes(r)?) as usize;
+                    *self
+                        .nodes
+                        .get(id)
+                        .ok_or_else(|| invalid_tree("unknown node in tree"))?
+                }
+                _ => return Err(invalid_tree("bad child in tree")),
+            };
+
+            hot.set_child(child);
+        }
+
+        Ok(())
+    }
+}
+
+impl SearchTree {
+    /// Writes the tree to `path`. Must not be called while searching.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+
+        let fen = Fen::from_setup(self.root_state.board()).to_string();
+
+        w.write_all(TREE_FILE_MAGIC)?;
+        w.write_all(&TREE_FILE_VERSION.to_le_bytes())?;
+        w.write_all(&(fen.len() as u16).to_le_bytes())?;
+        w.write_all(fen.as_bytes())?;
+
+        write_node(&mut w, &self.root_node, &mut HashMap::new())?;
+
+        w.flush()
+    }
+
+    /// Reads a tree written by `save`. The root's game history isn't stored, so
+    /// repetitions before the root are not known.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        if &read_bytes::<_, 4>(&mut r)? != TREE_FILE_MAGIC
+            || u32::from_le_bytes(read_bytes(&mut r)?) != TREE_FILE_VERSION
+        {
+            return Err(invalid_tree("not a tree file"));
+        }
+
+        let mut fen = vec![0; usize::from(u16::from_le_bytes(read_bytes(&mut r)?))];
+        r.read_exact(&mut fen)?;
+
+        let state: State = str::from_utf8(&fen)
+            .ok()
+            .and_then(StateBuilder::from_fen)
+            .ok_or_else(|| invalid_tree("bad root position"))?
+            .into();
+
+        let mut tree = Self::new(
+            state.clone(),
+            TranspositionTable::empty(),
+            TranspositionTable::zero(),
+        );
+
+        let root_node = {
+            let mut loader = TreeLoader {
+                allocator: tree.ttable.allocator(),
+                ttable: &tree.ttable,
+                nodes: Vec::new(),
+            };
+
+            let (flag, plies_to_mate, num_hots) = TreeLoader::read_header(&mut r)?;
+            let hots = tree
+                .root_table
+                .arena()
+                .allocator()
+                .alloc_slice(num_hots)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "root does not fit"))?;
+            loader.read_hots(&mut r, &state, hots)?;
+
+            let root_node = SearchNode::new(hots, flag, &state);
+            root_node.set_proven(flag, plies_to_mate);
+            root_node
+        };
+
+        tree.root_node = root_node;
+
+        Ok(tree)
+    }
+}
+
+/// A move in an exported tree, between the indices of its nodes.
+struct ExportEdge {
+    from: usize,
+    to: usize,
+    mov: String,
+    visits: u32,
+    q: f32,
+    policy: f32,
+}
+
+impl SearchTree {
+    /// Writes the top of the tree to `path` for inspection, as JSON if the path ends in
+    /// `.json` and as Graphviz DOT otherwise. Moves with fewer than `min_visits` visits
+    /// aren't followed, nor is anything deeper than `depth_limit` plies. Transposed
+    /// positions are written once, with every move leading to them.
+    pub fn export(&self, path: &Path, depth_limit: usize, min_visits: u32) -> io::Result<()> {
+        let root: *const SearchNode = &self.root_node;
+        let mut ids = HashMap::from([(root, 0)]);
+        let mut nodes = vec![self.root_node.flag()];
+        let mut edges = Vec::new();
+        let mut stack = vec![(&self.root_node, 0, 0)];
+
+        while let Some((node, id, depth)) = stack.pop() {
+            if depth >= depth_limit {
+                continue;
+            }
+
+            for hot in node.hots() {
+                let child = match hot.child() {
+                    Some(child) if hot.visits() >= min_visits => child,
+                    _ => continue,
+                };
+
+                let ptr: *const SearchNode = child;
+                let child_id = match ids.get(&ptr) {
+                    Some(&child_id) => child_id,
+                    None => {
+                        let child_id = nodes.len();
+                        ids.insert(ptr, child_id);
+                        nodes.push(child.flag());
+                        stack.push((child, child_id, depth + 1));
+                        child_id
+                    }
+                };
+
+                edges.push(ExportEdge {
+                    from: id,
+                    to: child_id,
+                    mov: hot.get_move().to_uci(),
+                    visits: hot.visits(),
+                    q: hot.average_reward().unwrap_or(0.) / SCALE,
+                    policy: hot.policy(),
+                });
+            }
+        }
+
+        let mut w = BufWriter::new(File::create(path)?);
+
+        if path.extension().map_or(false, |e| e == "json") {
+            Self::write_json(&mut w, &nodes, &edges)?;
+        } else {
+            Self::write_dot(&mut w, &nodes, &edges)?;
+        }
+
+        w.flush()
+    }
+
+    fn write_dot<W: Write>(w: &mut W, nodes: &[Flag], edges: &[ExportEdge]) -> io::Result<()> {
+        writeln!(w, "digraph tree {{")?;
+
+        for (id, flag) in nodes.iter().enumerate() {
+            writeln!(w, "  n{id} [label=\"{flag:?}\"];")?;
+        }
+
+        for e in edges {
+            writeln!(
+                w,
+                "  n{} -> n{} [label=\"{}\\nN: {} Q: {:.3} P: {:.3}\"];",
+                e.from, e.to, e.mov, e.visits, e.q, e.policy
+            )?;
+        }
+
+        writeln!(w, "}}")
+    }
+
+    fn write_json<W: Write>(w: &mut W, nodes: &[Flag], edges: &[ExportEdge]) -> io::Result<()> {
+        let nodes: Vec<String> = nodes
+            .iter()
+            .enumerate()
+            .map(|(id, flag)| format!("{{\"id\":{id},\"flag\":\"{flag:?}\"}}"))
+            .collect();
+
+        let edges: Vec<String> = edges
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"move\":\"{}\",\"visits\":{},\"q\":{},\"policy\":{}}}",
+                    e.from, e.to, e.mov, e.visits, e.q, e.policy
+                )
+            })
+            .collect();
+
+        writeln!(
+            w,
+            "{{\"nodes\":[{}],\"edges\":[{}]}}",
+            nodes.join(","),
+            edges.join(",")
+        )
+    }
+}
+
+// These touch the arenas through raw handles, so they're meant to be run under
+// `cargo miri test` too. Nodes are only reached through their handles once written,
+// as the search does.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables() -> LRTable {
+        LRTable::new(
+            TranspositionTable::with_size_mb(2),
+            TranspositionTable::with_size_mb(2),
+        )
+    }
+
+    fn new_node(allocator: &LRAllocator, state: &State) -> SearchNode {
+        let moves = state.available_moves();
+        let hots = allocator.alloc_move_info(moves.len()).unwrap();
+        for (hot, mov) in hots.iter_mut().zip(moves.iter()) {
+            *hot = HotMoveInfo::new(1. / moves.len() as f32, mov);
+        }
+
+        SearchNode::new(hots, Flag::Standard, state)
+    }
+
+    fn alloc_node(allocator: &LRAllocator, state: &State) -> Handle {
+        let node = new_node(allocator, state);
+        let (handle, slot) = allocator.alloc_node().unwrap();
+        *slot = node;
+        handle
+    }
+
+    // Allocates move slices until the current arena has no chunks left
+    fn fill(allocator: &LRAllocator) {
+        while allocator.alloc_move_info(1024).is_ok() {}
+    }
+
+    fn after(uci: &str) -> State {
+        State::from_fen_and_moves(None, &[uci]).unwrap()
+    }
+
+    #[test]
+    fn handles_resolve_to_their_nodes() {
+        let table = tables();
+        let _guard = table.enter_playout();
+        let allocator = table.allocator();
+
+        let root = State::default();
+        let child = after("e2e4");
+        let root_handle = alloc_node(&allocator, &root);
+        let child_handle = alloc_node(&allocator, &child);
+
+        assert!(!root_handle.is_null());
+        assert_ne!(root_handle, child_handle);
+        assert!(unsafe { Handle::NULL.get::<SearchNode>() }.is_none());
+
+        let root_node = resolve(root_handle);
+        assert_eq!(root_node.key(), table_key(&root));
+        assert_eq!(root_node.hots().len(), 20);
+        assert_eq!(resolve(child_handle).key(), table_key(&child));
+
+        let edge = &root_node.hots()[0];
+        assert!(edge.child().is_none());
+        assert_eq!(edge.link_child(child_handle), Ok(()));
+        assert_eq!(edge.link_child(root_handle), Err(child_handle));
+        assert!(ptr::eq(edge.child().unwrap(), resolve(child_handle)));
+    }
+
+    #[test]
+    fn table_finds_inserted_nodes() {
+        let table = tables();
+        let _guard = table.enter_playout();
+        let allocator = table.allocator();
+
+        let state = after("d2d4");
+        let handle = alloc_node(&allocator, &state);
+        let other = alloc_node(&allocator, &state);
+
+        assert_eq!(table.insert(&state, handle), Some(handle));
+        assert_eq!(table.insert(&state, other), Some(handle));
+        assert_eq!(table.lookup(&state), Some(handle));
+        assert_eq!(table.lookup(&after("g1f3")), None);
+    }
+
+    #[test]
+    fn flips_keep_the_previous_table_until_the_next_flip() {
+        let table = tables();
+        let state = after("c2c4");
+
+        {
+            let _guard = table.enter_playout();
+            let allocator = table.allocator();
+            let handle = alloc_node(&allocator, &state);
+            let edge = &resolve(handle).hots()[0];
+            edge.down(0);
+            edge.up(100, 0, 0);
+            table.insert(&state, handle);
+            fill(&allocator);
+        }
+
+        assert!(table.is_arena_full());
+        table.flip_when_quiescent(|| ());
+        assert!(!table.is_left_current());
+
+        // The old node is only reachable through the previous table now
+        {
+            let _guard = table.enter_playout();
+            assert_eq!(table.lookup(&state), None);
+
+            let allocator = table.allocator();
+            let mut node = new_node(&allocator, &state);
+            table.lookup_into(&state, &mut node);
+            assert_eq!(node.hots()[0].visits(), 1);
+            assert_eq!(node.hots()[0].sum_rewards(), 100);
+            fill(&allocator);
+        }
+
+        table.flip_when_quiescent(|| ());
+        assert!(table.is_left_current());
+
+        // Flipping back cleared the left table, whose chunks are handed out again
+        let _guard = table.enter_playout();
+        assert_eq!(table.lookup(&state), None);
+
+        let allocator = table.allocator();
+        let handle = alloc_node(&allocator, &state);
+        assert_eq!(resolve(handle).key(), table_key(&state));
+        assert_eq!(table.insert(&state, handle), Some(handle));
+    }
+}
@@ -3,12 +3,12 @@ use shakmaty::fen::Fen;
 use shakmaty::uci::Uci;
 use shakmaty::zobrist::{ZobristHash, ZobristValue};
 use shakmaty::{
-    self, CastlingMode, CastlingSide, Chess, Color, File, Move, MoveList, Piece, Position, Role,
-    Setup,
+    self, CastlingMode, CastlingSide, Chess, Color, File, Move, MoveList, Piece, Position,
+    PositionError, Role, Setup,
 };
 use std::convert::Into;
 
-use crate::options::is_chess960;
+use crate::options::{is_chess960, is_lenient_fen};
 use crate::uci::Tokens;
 
 const NF_PIECES: usize = 2 * 6 * 64; // color * roles * squares
@@ -21,6 +21,56 @@ const OFFSET_THREATS: usize = NF_PIECES + NF_LAST_CAPTURE;
 pub const NUMBER_FEATURES: usize = NF_PIECES + NF_LAST_CAPTURE + NF_THREATS;
 pub const NUMBER_MOVE_IDX: usize = 384;
 
+// The extended feature set adds these after the standard features
+const NF_PAWN_STRUCTURE: usize = 3 * 2 * 64; // passed, isolated, doubled * color * squares
+const NF_MOBILITY: usize = 2 * 4 * MOBILITY_BUCKETS; // color * roles (knight to queen) * buckets
+
+// Squares a side's pieces of one role attack, in pairs, up to the last bucket
+const MOBILITY_BUCKETS: usize = 16;
+
+const OFFSET_PAWN_STRUCTURE: usize = NUMBER_FEATURES;
+const OFFSET_MOBILITY: usize = OFFSET_PAWN_STRUCTURE + NF_PAWN_STRUCTURE;
+
+pub const NUMBER_EXTENDED_FEATURES: usize = NUMBER_FEATURES + NF_PAWN_STRUCTURE + NF_MOBILITY;
+
+/// The inputs of an eval net. The built in net and nets trained before the
+/// extended set read the standard features only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureSet {
+    Standard,
+    // Also pawn structure and mobility
+    Extended,
+}
+
+impl FeatureSet {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "standard" => Some(FeatureSet::Standard),
+            "extended" => Some(FeatureSet::Extended),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn inputs(self) -> usize {
+        match self {
+            FeatureSet::Standard => NUMBER_FEATURES,
+            FeatureSet::Extended => NUMBER_EXTENDED_FEATURES,
+        }
+    }
+}
+
+fn role_value(role: Role) -> u8 {
+    match role {
+        Role::King => 0,
+        Role::Pawn => 1,
+        Role::Knight | Role::Bishop => 3,
+        Role::Rook => 5,
+        Role::Queen => 9,
+    }
+}
+
 pub struct Builder {
     initial_state: Chess,
     crnt_state: Chess,
@@ -38,42 +88,57 @@ impl Builder {
     }
 
     pub fn from_fen(fen: &str) -> Option<Self> {
-        Some(
-            fen.parse::<Fen>()
-                .ok()?
-                .position::<Chess>(CastlingMode::from_chess960(is_chess960()))
-                .ok()?
-                .into(),
-        )
-    }
-
-    pub fn from_tokens(mut tokens: Tokens) -> Option<Self> {
-        let mut result = match tokens.next()? {
-            "startpos" => Self::default(),
-            "fen" => {
-                let mut s = String::new();
-                for i in 0..6 {
-                    if i != 0 {
-                        s.push(' ');
-                    }
-                    s.push_str(tokens.next()?);
-                }
-                Self::from_fen(&s)?
-            }
-            _ => return None,
+        Self::parse_fen(fen).ok()
+    }
+
+    /// Parses `fen`, or says why it can't be played from. With `LenientFen` the
+    /// faults the search copes with are let through: castling rights or an en
+    /// passant square that don't fit the position, more material than a game can
+    /// reach and checks no move could have given.
+    pub fn parse_fen(fen: &str) -> Result<Self, String> {
+        let fen: Fen = fen.parse().map_err(|e| format!("bad fen: {e}"))?;
+
+        let position = match fen.position::<Chess>(CastlingMode::from_chess960(is_chess960())) {
+            Ok(position) => position,
+            Err(e) if is_lenient_fen() => e
+                .ignore_invalid_castling_rights()
+                .or_else(PositionError::ignore_invalid_ep_square)
+                .or_else(PositionError::ignore_impossible_material)
+                .or_else(PositionError::ignore_impossible_check)
+                .map_err(|e| format!("illegal position: {e}"))?,
+            Err(e) => return Err(format!("illegal position: {e} (LenientFen may accept it)")),
         };
-        match tokens.next() {
-            Some("moves") | None => (),
-            Some(_) => return None,
+
+        Ok(position.into())
+    }
+
+    pub fn from_fen_and_moves(fen: Option<&str>, moves: &[&str]) -> Option<Self> {
+        let mut result = match fen {
+            Some(fen) => Self::from_fen(fen)?,
+            None => Self::default(),
         };
-        for mov_str in tokens {
-            let uci = mov_str.parse::<Uci>().ok()?;
-            let mov = uci.to_move(result.chess()).ok()?;
+        for mov_str in moves {
+            let mov = result.parse_move(mov_str)?;
             result.make_move(mov);
         }
         Some(result)
     }
 
+    /// The legal move `uci` stands for in the current position. Castling is taken
+    /// both as the king's two square move and as the king taking its rook, whatever
+    /// `UCI_Chess960` says, and promotions may be in capitals.
+    pub fn parse_move(&self, uci: &str) -> Option<Move> {
+        let uci: Uci = uci.to_ascii_lowercase().parse().ok()?;
+
+        uci.to_move(&self.crnt_state).ok().or_else(|| {
+            self.crnt_state.legal_moves().into_iter().find(|m| {
+                m.is_castle()
+                    && (m.to_uci(CastlingMode::Standard) == uci
+                        || m.to_uci(CastlingMode::Chess960) == uci)
+            })
+        })
+    }
+
     pub fn extract(&self) -> (State, Vec<Move>) {
         let state = Self::from(self.initial_state.clone()).into();
         let moves = self.moves.clone();
@@ -95,8 +160,36 @@ pub struct State {
     hash: u64,
 }
 impl State {
-    pub fn from_tokens(tokens: Tokens) -> Option<Self> {
-        Builder::from_tokens(tokens).map(Into::into)
+    /// Parses the arguments of a UCI `position` command. The moves are played up
+    /// to the first illegal one, which is reported.
+    pub fn from_tokens(mut tokens: Tokens) -> Result<Self, String> {
+        let mut builder = match tokens.next() {
+            Some("startpos") => Builder::default(),
+            Some("fen") => {
+                let fen: Vec<&str> = tokens.by_ref().take(6).collect();
+                Builder::parse_fen(&fen.join(" "))?
+            }
+            _ => return Err("expected startpos or fen".into()),
+        };
+        match tokens.next() {
+            Some("moves") | None => (),
+            Some(token) => return Err(format!("expected moves, got {token}")),
+        };
+        for (ply, mov_str) in tokens.enumerate() {
+            let Some(mov) = builder.parse_move(mov_str) else {
+                output!("info string illegal move {mov_str} at ply {}", ply + 1);
+                break;
+            };
+            builder.make_move(mov);
+        }
+        Ok(builder.into())
+    }
+
+    /// The position after playing `moves` from `fen`, or from the start position if
+    /// there is no `fen`. Every position along the way is kept in the history, so
+    /// repetitions of positions before the search root are detected.
+    pub fn from_fen_and_moves(fen: Option<&str>, moves: &[&str]) -> Option<Self> {
+        Builder::from_fen_and_moves(fen, moves).map(Into::into)
     }
 
     pub fn board(&self) -> &Chess {
@@ -107,10 +200,29 @@ impl State {
         self.board.turn()
     }
 
-    pub fn hash(&self) -> u64 {
+    /// The Polyglot compatible Zobrist key of the position, as used by the
+    /// transposition table and opening books.
+    pub fn zobrist_key(&self) -> u64 {
         self.hash
     }
 
+    /// The Zobrist key extended with the last capture, which the eval features
+    /// include, so positions with the same key always evaluate the same.
+    pub fn eval_key(&self) -> u64 {
+        match self.prev_capture_sq.zip(self.prev_capture) {
+            Some((sq, role)) => {
+                let capture = sq as u64 * 8 + role as u64;
+                self.hash ^ capture.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            }
+            None => self.hash,
+        }
+    }
+
+    /// Keys of the earlier positions that could still be repeated, oldest first.
+    pub fn history_keys(&self) -> &[u64] {
+        &self.prev_state_hashes
+    }
+
     pub fn available_moves(&self) -> MoveList {
         self.board.legal_moves()
     }
@@ -123,8 +235,11 @@ impl State {
 
         if is_pawn_move || self.prev_capture.is_some() {
             self.prev_state_hashes.clear();
+        } else if self.prev_state_hashes.is_full() {
+            // Past the fifty move rule, only recent positions matter for repetitions
+            self.prev_state_hashes.remove(0);
         }
-        self.prev_state_hashes.push(self.hash());
+        self.prev_state_hashes.push(self.hash);
 
         self.update_hash_pre();
         self.board.play_unchecked(mov);
@@ -197,7 +312,7 @@ impl State {
     }
 
     fn check_for_repetition(&mut self) {
-        let crnt_hash = self.hash();
+        let crnt_hash = self.hash;
         self.repetitions = self
             .prev_state_hashes
             .iter()
@@ -206,7 +321,7 @@ impl State {
     }
 
     pub fn halfmove_counter(&self) -> usize {
-        self.prev_state_hashes.len() - 1
+        self.prev_state_hashes.len().saturating_sub(1)
     }
 
     pub fn drawn_by_fifty_move_rule(&self) -> bool {
@@ -217,6 +332,74 @@ impl State {
         self.repetitions > 0
     }
 
+    /// How many times the position occurred before.
+    pub fn repetition_count(&self) -> usize {
+        self.repetitions
+    }
+
+    /// Whether `mov` puts the opponent in check. This plays the move on a copy of the
+    /// board, so it's slower than the other move queries.
+    pub fn gives_check(&self, mov: &Move) -> bool {
+        let mut after = self.board.clone();
+        after.play_unchecked(mov);
+        after.is_check()
+    }
+
+    /// Whether `mov` captures a piece worth more than the one capturing. Kings only
+    /// capture what is undefended, so their captures always count.
+    pub fn is_winning_capture(mov: &Move) -> bool {
+        mov.capture()
+            .map_or(false, |c| role_value(c) > role_value(mov.role()))
+    }
+
+    pub fn is_queen_promotion(mov: &Move) -> bool {
+        mov.promotion() == Some(Role::Queen)
+    }
+
+    /// Whether playing `mov` would repeat a position seen since the last zeroing move.
+    /// The key after the move is worked out without playing it.
+    pub fn repeats_after(&self, mov: &Move) -> bool {
+        // Zeroing moves clear the history, and a move that loses castling rights
+        // reaches a position no earlier one can match
+        let Move::Normal {
+            role,
+            from,
+            to,
+            capture: None,
+            promotion: None,
+        } = *mov
+        else {
+            return false;
+        };
+        if role == Role::Pawn {
+            return false;
+        }
+
+        let color = self.side_to_move();
+        let castles = self.board.castles();
+        let loses_rights = if role == Role::King {
+            CastlingSide::ALL
+                .iter()
+                .any(|&side| castles.has(color, side))
+        } else {
+            castles.castling_rights().contains(from)
+        };
+        if loses_rights {
+            return false;
+        }
+
+        let pc = Piece { color, role };
+        let mut hash = self.hash
+            ^ u64::zobrist_for_piece(from, pc)
+            ^ u64::zobrist_for_piece(to, pc)
+            ^ u64::zobrist_for_white_turn();
+        if let Some(ep_sq) = self.board.ep_square() {
+            hash ^= u64::zobrist_for_en_passant_file(ep_sq.file());
+        }
+
+        self.prev_state_hashes.contains(&hash)
+    }
+
     fn feature_flip(&self) -> (bool, bool) {
         let stm = self.side_to_move();
         let b = self.board.board();
@@ -229,6 +412,26 @@ impl State {
         (flip_vertical, flip_horizontal)
     }
 
+    /// The side to move's king square after the feature flips, so always on files E-H.
+    pub fn relative_king_square(&self) -> usize {
+        let b = self.board.board();
+        let ksq = b.king_of(self.side_to_move()).unwrap();
+
+        match self.feature_flip() {
+            (true, true) => ksq.flip_vertical().flip_horizontal() as usize,
+            (true, false) => ksq.flip_vertical() as usize,
+            (false, true) => ksq.flip_horizontal() as usize,
+            (false, false) => ksq as usize,
+        }
+    }
+
+    /// Which of the four feature perspectives (side to move, king side) is in use.
+    /// Positions in different buckets share no feature encoding.
+    pub fn feature_bucket(&self) -> usize {
+        let (flip_vertical, flip_horizontal) = self.feature_flip();
+        usize::from(flip_vertical) * 2 + usize::from(flip_horizontal)
+    }
+
     pub fn features_map<F>(&self, mut f: F)
     where
         F: FnMut(usize),
@@ -272,8 +475,107 @@ impl State {
         }
     }
 
+    /// The features of `set`, each index below its `inputs()`.
+    pub fn feature_set_map<F>(&self, set: FeatureSet, mut f: F)
+    where
+        F: FnMut(usize),
+    {
+        self.features_map(&mut f);
+
+        if set == FeatureSet::Extended {
+            self.extended_features_map(f);
+        }
+    }
+
+    // Pawns that are passed, isolated or doubled, and how many squares each side's
+    // knights, bishops, rooks and queens attack that aren't their own pieces'
+    fn extended_features_map<F>(&self, mut f: F)
+    where
+        F: FnMut(usize),
+    {
+        let stm = self.side_to_move();
+        let b = self.board.board();
+
+        let (flip_vertical, flip_horizontal) = self.feature_flip();
+
+        let flip_square = |sq: shakmaty::Square| match (flip_vertical, flip_horizontal) {
+            (true, true) => sq.flip_vertical().flip_horizontal(),
+            (true, false) => sq.flip_vertical(),
+            (false, true) => sq.flip_horizontal(),
+            (false, false) => sq,
+        };
+
+        let file_distance =
+            |x: shakmaty::Square, y: shakmaty::Square| (x.file() as i32 - y.file() as i32).abs();
+
+        for color in [stm, !stm] {
+            let side_idx = usize::from(color != stm);
+            let own_pawns = b.by_role(Role::Pawn) & b.by_color(color);
+            let their_pawns = b.by_role(Role::Pawn) & b.by_color(!color);
+
+            for sq in own_pawns {
+                let ahead = |other: shakmaty::Square| {
+                    color.fold_wb(other.rank() > sq.rank(), other.rank() < sq.rank())
+                };
+
+                let passed = !their_pawns
+                    .into_iter()
+                    .any(|p| file_distance(p, sq) <= 1 && ahead(p));
+                let isolated = !own_pawns.into_iter().any(|p| file_distance(p, sq) == 1);
+                let doubled = own_pawns
+                    .into_iter()
+                    .any(|p| p != sq && p.file() == sq.file());
+
+                for (kind, is_kind) in [passed, isolated, doubled].into_iter().enumerate() {
+                    if is_kind {
+                        let sq_idx = flip_square(sq) as usize;
+                        f(OFFSET_PAWN_STRUCTURE + (kind * 2 + side_idx) * 64 + sq_idx);
+                    }
+                }
+            }
+
+            for role in [Role::Knight, Role::Bishop, Role::Rook, Role::Queen] {
+                let pieces = b.by_role(role) & b.by_color(color);
+
+                if pieces.is_empty() {
+                    continue;
+                }
+
+                let squares: usize = pieces
+                    .into_iter()
+                    .map(|sq| (b.attacks_from(sq) & !b.by_color(color)).count())
+                    .sum();
+                let bucket = (squares / 2).min(MOBILITY_BUCKETS - 1);
+                let role_idx = role as usize - 2;
+
+                f(OFFSET_MOBILITY + (side_idx * 4 + role_idx) * MOBILITY_BUCKETS + bucket);
+            }
+        }
+    }
+
+    // Castling is encoded as king takes rook. Index it as if the rook started in
+    // the corner, so Chess960 castles share the policy of standard ones.
+    fn policy_square(mv: &Move) -> shakmaty::Square {
+        match *mv {
+            Move::Castle { king, rook } => {
+                let file = if rook.file() > king.file() {
+                    File::H
+                } else {
+                    File::A
+                };
+                shakmaty::Square::from_coords(file, king.rank())
+            }
+            _ => mv.to(),
+        }
+    }
+
+    /// The move's output in the policy net: the role moved and where to. Moves of
+    /// the same role to the same square share it, so two knights or rooks that can
+    /// reach a square do, as do a promotion and the underpromotions to the same
+    /// square. Castling can't collide with a king move, as the king is never next to
+    /// the corner its rook castles from without the rook being in it.
     pub fn move_to_index(&self, mv: &Move) -> usize {
-        let to_sq = mv.to();
+        let to_sq = Self::policy_square(mv);
 
         let (flip_vertical, flip_horizontal) = self.feature_flip();
 
@@ -289,6 +591,62 @@ impl State {
 
         role_idx * 64 + to_idx
     }
+
+    /// Pairs of legal moves sharing a policy index.
+    pub fn move_index_collisions(&self) -> Vec<(Move, Move)> {
+        let moves = self.available_moves();
+        let indexes: Vec<usize> = moves.iter().map(|m| self.move_to_index(m)).collect();
+
+        let mut collisions = Vec::new();
+        for (i, a) in moves.iter().enumerate() {
+            for (j, b) in moves.iter().enumerate().skip(i + 1) {
+                if indexes[i] == indexes[j] {
+                    collisions.push((a.clone(), b.clone()));
+                }
+            }
+        }
+
+        collisions
+    }
+
+    /// Counts the positions `depth` plies down. Every move goes through `make_move`, and
+    /// debug builds check its incremental hash and the features along the way, and
+    /// that only moves of the same role to the same square share a policy index.
+    pub fn perft(&self, depth: usize) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+
+        let mut nodes = 0;
+
+        if cfg!(debug_assertions) {
+            for (a, b) in self.move_index_collisions() {
+                assert!(
+                    a.role() == b.role() && Self::policy_square(&a) == Self::policy_square(&b),
+                    "policy index collision between {a} and {b}"
+                );
+            }
+        }
+
+        for mv in &self.available_moves() {
+            debug_assert!(self.move_to_index(mv) < NUMBER_MOVE_IDX);
+
+            let mut child = self.clone();
+            child.make_move(mv);
+
+            if cfg!(debug_assertions) {
+                let hash: u64 = child.board.zobrist_hash();
+                assert_eq!(child.zobrist_key(), hash, "hash mismatch after {mv}");
+                child.feature_set_map(FeatureSet::Extended, |idx| {
+                    assert!(idx < NUMBER_EXTENDED_FEATURES);
+                });
+            }
+
+            nodes += child.perft(depth - 1);
+        }
+
+        nodes
+    }
 }
 
 impl Default for Builder {
@@ -333,3 +691,150 @@ impl From<Builder> for State {
         state
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shakmaty::Square;
+
+    fn builder(fen: &str, mode: CastlingMode) -> Builder {
+        let fen: Fen = fen.parse().unwrap();
+        fen.position::<Chess>(mode).unwrap().into()
+    }
+
+    fn castle(king: Square, rook: Square) -> Option<Move> {
+        Some(Move::Castle { king, rook })
+    }
+
+    #[test]
+    fn parse_move_takes_both_castling_notations() {
+        let builder = builder(
+            "r3k2r/pppppppp/8/8/8/8/PPPPPPPP/R3K2R w KQkq - 0 1",
+            CastlingMode::Standard,
+        );
+
+        let short = castle(Square::E1, Square::H1);
+        let long = castle(Square::E1, Square::A1);
+
+        assert_eq!(builder.parse_move("e1g1"), short);
+        assert_eq!(builder.parse_move("e1h1"), short);
+        assert_eq!(builder.parse_move("e1c1"), long);
+        assert_eq!(builder.parse_move("e1a1"), long);
+    }
+
+    #[test]
+    fn parse_move_takes_both_castling_notations_in_chess960() {
+        let builder = builder(
+            "1r2k2r/pppppppp/8/8/8/8/PPPPPPPP/1R2K2R w BHbh - 0 1",
+            CastlingMode::Chess960,
+        );
+
+        let long = castle(Square::E1, Square::B1);
+
+        assert_eq!(builder.parse_move("e1b1"), long);
+        assert_eq!(builder.parse_move("e1c1"), long);
+        assert_eq!(builder.parse_move("e1h1"), castle(Square::E1, Square::H1));
+    }
+
+    #[test]
+    fn parse_move_takes_promotions_in_either_case() {
+        let builder = builder("8/P7/8/8/8/8/8/k6K w - - 0 1", CastlingMode::Standard);
+
+        let promotion = |role| {
+            Some(Move::Normal {
+                role: Role::Pawn,
+                from: Square::A7,
+                capture: None,
+                to: Square::A8,
+                promotion: Some(role),
+            })
+        };
+
+        assert_eq!(builder.parse_move("a7a8q"), promotion(Role::Queen));
+        assert_eq!(builder.parse_move("a7a8Q"), promotion(Role::Queen));
+        assert_eq!(builder.parse_move("a7a8N"), promotion(Role::Knight));
+        assert_eq!(builder.parse_move("a7a8"), None);
+    }
+
+    #[test]
+    fn parse_move_rejects_illegal_moves() {
+        let builder = Builder::default();
+
+        assert_eq!(builder.parse_move("e2e5"), None);
+        assert_eq!(builder.parse_move("e1g1"), None);
+        assert_eq!(builder.parse_move("nonsense"), None);
+    }
+
+    #[test]
+    fn from_tokens_stops_at_the_first_illegal_move() {
+        let tokens = "startpos moves e2e4 e7e5 e1e3 g1f3".split_whitespace();
+        let state = State::from_tokens(tokens).unwrap();
+        let expected = State::from_fen_and_moves(None, &["e2e4", "e7e5"]).unwrap();
+
+        assert_eq!(state.zobrist_key(), expected.zobrist_key());
+        assert_eq!(state.history_keys(), expected.history_keys());
+    }
+
+    #[test]
+    fn from_tokens_rejects_a_missing_position() {
+        assert!(State::from_tokens("moves e2e4".split_whitespace()).is_err());
+        assert!(State::from_tokens("startpos e2e4".split_whitespace()).is_err());
+    }
+
+    // Calls `f` on every position up to `depth` plies from `state`
+    fn walk(state: &State, depth: usize, f: &mut impl FnMut(&State)) {
+        f(state);
+
+        if depth > 0 {
+            for mv in &state.available_moves() {
+                let mut child = state.clone();
+                child.make_move(mv);
+                walk(&child, depth - 1, f);
+            }
+        }
+    }
+
+    #[test]
+    fn move_indexes_only_collide_for_the_same_role_and_square() {
+        // Castling both ways, promotions, en passant and pins
+        let fens = [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+        ];
+
+        for fen in fens {
+            let state = State::from_fen_and_moves(Some(fen), &[]).unwrap();
+
+            walk(&state, 2, &mut |state| {
+                for mv in &state.available_moves() {
+                    assert!(state.move_to_index(mv) < NUMBER_MOVE_IDX);
+                }
+
+                for (a, b) in state.move_index_collisions() {
+                    assert_eq!(a.role(), b.role(), "{a} and {b} in {fen}");
+                    assert_eq!(State::policy_square(&a), State::policy_square(&b));
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn move_indexes_collide_as_documented() {
+        let collides = |fen: &str, a: &str, b: &str| {
+            let builder = builder(fen, CastlingMode::Standard);
+            let a = builder.parse_move(a).unwrap();
+            let b = builder.parse_move(b).unwrap();
+            let state: State = builder.into();
+            state.move_to_index(&a) == state.move_to_index(&b)
+        };
+
+        // Two knights reaching the same square
+        assert!(collides("4k3/8/8/8/8/8/8/1N2KN2 w - - 0 1", "b1d2", "f1d2"));
+        // A promotion and an underpromotion to the same square
+        assert!(collides("4k3/P7/8/8/8/8/8/4K3 w - - 0 1", "a7a8q", "a7a8n"));
+        // But not castling and the king's step towards it
+        assert!(!collides("4k3/8/8/8/8/8/8/4K2R w K - 0 1", "e1g1", "e1f1"));
+    }
+}
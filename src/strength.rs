@@ -0,0 +1,126 @@
+use once_cell::sync::Lazy;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::math;
+use crate::options::{get_elo, get_human_like_rate};
+use crate::search::SCALE;
+use crate::search_tree::HotMoveInfo;
+
+pub const MIN_ELO: usize = 500;
+pub const MAX_ELO: usize = 3000;
+
+// A rough model: a single node is worth this much, and each doubling of the nodes
+// searched adds ELO_PER_DOUBLING. Below SAMPLING_ELO the move is no longer the most
+// visited one, but sampled from the visits at a temperature rising as the Elo drops.
+const SINGLE_NODE_ELO: usize = 1000;
+const ELO_PER_DOUBLING: f32 = 100.;
+const SAMPLING_ELO: usize = 2000;
+const ELO_PER_TEMPERATURE: f32 = 1000.;
+
+// Alternatives to the most visited move considered in human-like play, and the
+// loss in expected score (on a scale where a win is 1) over which the chance of
+// playing one falls by a factor of e
+const HUMAN_LIKE_CANDIDATES: usize = 3;
+const HUMAN_LIKE_GAP_SCALE: f32 = 0.05;
+
+// Kept across moves, so a fixed seed gives a repeatable game rather than the same
+// choice every move
+static RNG: Lazy<Mutex<SmallRng>> = Lazy::new(|| Mutex::new(math::rng()));
+static SKILL_SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Reseeds the generator behind weakened move choices, from entropy for 0.
+pub fn set_skill_seed(seed: u64) {
+    SKILL_SEED.store(seed, Ordering::Relaxed);
+    restart_rng();
+}
+
+/// Reseeds the generator from the last `SkillSeed`, so each game replays the same way.
+pub fn restart_rng() {
+    let seed = SKILL_SEED.load(Ordering::Relaxed);
+    *RNG.lock().unwrap() = if seed == 0 {
+        SmallRng::from_entropy()
+    } else {
+        SmallRng::seed_from_u64(seed)
+    };
+}
+
+fn elo() -> usize {
+    get_elo().clamp(MIN_ELO, MAX_ELO)
+}
+
+/// The most nodes to search per move at the configured `UCI_Elo`.
+#[allow(clippy::cast_sign_loss)]
+pub fn node_limit() -> usize {
+    let doublings = elo().saturating_sub(SINGLE_NODE_ELO) as f32 / ELO_PER_DOUBLING;
+    2f32.powf(doublings) as usize
+}
+
+/// The temperature for picking among root moves by visits, 0 for the most visited.
+pub fn move_temperature() -> f32 {
+    SAMPLING_ELO.saturating_sub(elo()) as f32 / ELO_PER_TEMPERATURE
+}
+
+/// Picks a root move with probability proportional to its visits to the power of
+/// 1 / `temperature`, or `None` at zero temperature or with nothing searched. Moves
+/// proven to lose are never picked.
+pub fn sample_move(hots: &[HotMoveInfo], temperature: f32) -> Option<&HotMoveInfo> {
+    if temperature <= 0. {
+        return None;
+    }
+
+    let weights: Vec<f32> = hots
+        .iter()
+        .map(|h| {
+            if h.is_pruned() {
+                0.
+            } else {
+                (h.visits() as f32).powf(1. / temperature)
+            }
+        })
+        .collect();
+    let total: f32 = weights.iter().sum();
+
+    if total <= 0. || !total.is_finite() {
+        return None;
+    }
+
+    let mut pick = RNG.lock().unwrap().gen_range(0., total);
+    for (hot, weight) in hots.iter().zip(weights) {
+        if pick < weight {
+            return Some(hot);
+        }
+        pick -= weight;
+    }
+
+    hots.iter().rev().find(|h| h.visits() > 0 && !h.is_pruned())
+}
+
+/// Sometimes passes over the most visited root move for the second or third most
+/// visited, each with a chance of `HumanLikeRate` that shrinks as its value drops
+/// below the best move's. `None` means the most visited move should be played.
+pub fn human_like_move(hots: &[HotMoveInfo]) -> Option<&HotMoveInfo> {
+    let mut candidates: Vec<&HotMoveInfo> = hots
+        .iter()
+        .filter(|h| h.visits() > 0 && !h.is_pruned())
+        .collect();
+    candidates.sort_by_key(|h| Reverse(h.visits()));
+
+    let value = |h: &HotMoveInfo| h.average_reward().unwrap_or(-SCALE) / SCALE;
+    let (best, alternatives) = candidates.split_first()?;
+    let best_value = value(best);
+    let rate = get_human_like_rate();
+
+    let mut rng = RNG.lock().unwrap();
+    alternatives
+        .iter()
+        .take(HUMAN_LIKE_CANDIDATES - 1)
+        .find(|h| {
+            let gap = (best_value - value(h)).max(0.) / 2.;
+            rng.gen::<f32>() < rate * (-gap / HUMAN_LIKE_GAP_SCALE).exp()
+        })
+        .copied()
+}
@@ -1,38 +1,182 @@
-use arc_swap::ArcSwap;
-use log::debug;
-use once_cell::sync::Lazy;
-use shakmaty::{Chess, Move, Setup};
-use shakmaty_syzygy::{Tablebase, Wdl};
-use std::path::Path;
-use std::sync::Arc;
-
-static TABLEBASE: Lazy<ArcSwap<Tablebase<Chess>>> =
-    Lazy::new(|| ArcSwap::from_pointee(Tablebase::new()));
-
-pub fn set_tablebase_directory<P: AsRef<Path>>(path: P) {
-    let mut tb = Tablebase::new();
-    let cnt = tb.add_directory(path).unwrap();
-    debug!("Added {} files to tablebase.", cnt);
-    TABLEBASE.store(Arc::new(tb));
+#[cfg(feature = "syzygy")]
+pub use self::syzygy::*;
+
+#[cfg(not(feature = "syzygy"))]
+pub use self::disabled::*;
+
+#[cfg(feature = "syzygy")]
+mod syzygy {
+    use arc_swap::ArcSwap;
+    use once_cell::sync::Lazy;
+    use shakmaty::{Chess, Move, Position, Setup};
+    use shakmaty_syzygy::Tablebase;
+    pub use shakmaty_syzygy::Wdl;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::sync::Arc;
+
+    use crate::options::get_syzygy_probe_limit;
+
+    const FIFTY_MOVE_PLIES: u32 = 100;
+
+    static TABLEBASE: Lazy<ArcSwap<Tablebase<Chess>>> =
+        Lazy::new(|| ArcSwap::from_pointee(Tablebase::new()));
+
+    /// Replaces the tablebase with the files in `paths`, a `;` separated list of
+    /// directories, reporting how many were found. `<empty>` clears it.
+    pub fn set_tablebase_directory(paths: &str) {
+        let mut tb = Tablebase::new();
+        let (mut wdl, mut dtz) = (0, 0);
+
+        for dir in paths
+            .split(';')
+            .map(str::trim)
+            .filter(|d| !d.is_empty() && *d != "<empty>")
+        {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    error!("Unable to read tablebase directory {}: {:?}", dir, e);
+                    continue;
+                }
+            };
+
+            for path in entries.filter_map(Result::ok).map(|e| e.path()) {
+                if tb.add_file(&path).is_ok() {
+                    match path.extension().and_then(OsStr::to_str) {
+                        Some("rtbw") => wdl += 1,
+                        Some("rtbz") => dtz += 1,
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        output!("info string found {wdl} WDL and {dtz} DTZ tablebase files");
+        TABLEBASE.store(Arc::new(tb));
+    }
+
+    fn is_probeable(tb: &Tablebase<Chess>, pos: &Chess) -> bool {
+        pos.board().occupied().count() <= tb.max_pieces().min(get_syzygy_probe_limit())
+    }
+
+    pub fn probe_tablebase_wdl(pos: &Chess) -> Option<Wdl> {
+        let tb = TABLEBASE.load();
+        if !is_probeable(&tb, pos) {
+            None
+        } else {
+            tb.probe_wdl_after_zeroing(pos).ok()
+        }
+    }
+
+    /// Distance to zeroing the halfmove clock with best play, negative when losing.
+    pub fn probe_tablebase_dtz(pos: &Chess) -> Option<i32> {
+        let tb = TABLEBASE.load();
+        if !is_probeable(&tb, pos) {
+            None
+        } else {
+            tb.probe_dtz(pos).ok().map(|dtz| dtz.ignore_rounding().0)
+        }
+    }
+
+    /// The result of playing `m` in `pos`, for the side playing it. Wins and losses
+    /// the fifty-move rule would cut short before the next zeroing move, going by
+    /// DTZ, count as cursed wins and blessed losses.
+    fn root_move_result(tb: &Tablebase<Chess>, pos: &Chess, m: &Move) -> Option<Wdl> {
+        let mut after = pos.clone();
+        after.play_unchecked(m);
+
+        if after.is_checkmate() {
+            return Some(Wdl::Win);
+        }
+
+        if after.halfmoves() == 0 {
+            return tb.probe_wdl_after_zeroing(&after).ok().map(|wdl| -wdl);
+        }
+
+        // From the point of view of the side to move after `m`
+        let dtz = tb.probe_dtz(&after).ok()?.ignore_rounding().0;
+        let in_time = after.halfmoves() + dtz.unsigned_abs() <= FIFTY_MOVE_PLIES;
+
+        Some(match (dtz.signum(), in_time) {
+            (-1, true) => Wdl::Win,
+            (-1, false) => Wdl::CursedWin,
+            (1, true) => Wdl::Loss,
+            (1, false) => Wdl::BlessedLoss,
+            _ => Wdl::Draw,
+        })
+    }
+
+    /// The legal moves of `pos` that keep its best tablebase result under the
+    /// fifty-move rule, or `None` if any of them can't be probed.
+    pub fn probe_tablebase_root_moves(pos: &Chess) -> Option<Vec<Move>> {
+        let tb = TABLEBASE.load();
+        if !is_probeable(&tb, pos) {
+            return None;
+        }
+
+        let results = pos
+            .legal_moves()
+            .into_iter()
+            .map(|m| root_move_result(&tb, pos, &m).map(|wdl| (m, wdl)))
+            .collect::<Option<Vec<_>>>()?;
+
+        let best = results.iter().map(|(_, wdl)| *wdl).max()?;
+
+        Some(
+            results
+                .into_iter()
+                .filter(|(_, wdl)| *wdl == best)
+                .map(|(m, _)| m)
+                .collect(),
+        )
+    }
+
+    pub fn probe_tablebase_best_move(pos: &Chess) -> Option<Move> {
+        let tb = TABLEBASE.load();
+        if !is_probeable(&tb, pos) {
+            None
+        } else {
+            match tb.best_move(pos) {
+                Ok(Some((m, _))) => Some(m),
+                _ => None,
+            }
+        }
+    }
 }
 
-pub fn probe_tablebase_wdl(pos: &Chess) -> Option<Wdl> {
-    let tb = TABLEBASE.load();
-    if pos.board().occupied().count() > tb.max_pieces() {
+// Without the syzygy feature, for targets that can't read the tablebase files
+#[cfg(not(feature = "syzygy"))]
+mod disabled {
+    use shakmaty::{Chess, Move};
+
+    #[allow(dead_code)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Wdl {
+        Loss,
+        BlessedLoss,
+        Draw,
+        CursedWin,
+        Win,
+    }
+
+    pub fn set_tablebase_directory(_paths: &str) {
+        warn!("Built without Syzygy support");
+    }
+
+    pub fn probe_tablebase_wdl(_pos: &Chess) -> Option<Wdl> {
         None
-    } else {
-        tb.probe_wdl_after_zeroing(pos).ok()
     }
-}
 
-pub fn probe_tablebase_best_move(pos: &Chess) -> Option<Move> {
-    let tb = TABLEBASE.load();
-    if pos.board().occupied().count() > tb.max_pieces() {
+    pub fn probe_tablebase_dtz(_pos: &Chess) -> Option<i32> {
+        None
+    }
+
+    pub fn probe_tablebase_root_moves(_pos: &Chess) -> Option<Vec<Move>> {
+        None
+    }
+
+    pub fn probe_tablebase_best_move(_pos: &Chess) -> Option<Move> {
         None
-    } else {
-        match tb.best_move(pos) {
-            Ok(Some((m, _))) => Some(m),
-            _ => None,
-        }
     }
 }
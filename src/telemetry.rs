@@ -0,0 +1,62 @@
+use once_cell::sync::Lazy;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::search_tree::SearchTree;
+
+static TELEMETRY_FILE: Lazy<Mutex<Option<File>>> = Lazy::new(Mutex::default);
+
+/// Appends a line of search metrics to `path` after every search from now on.
+pub fn set_telemetry_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *TELEMETRY_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+pub fn clear_telemetry_file() {
+    *TELEMETRY_FILE.lock().unwrap() = None;
+}
+
+pub fn is_recording() -> bool {
+    TELEMETRY_FILE.lock().unwrap().is_some()
+}
+
+/// Writes the metrics of the search that just ended in `tree` as a JSON line, if
+/// there's a telemetry file.
+pub fn record_search(tree: &SearchTree) {
+    let mut file = TELEMETRY_FILE.lock().unwrap();
+
+    if let Some(f) = file.as_mut() {
+        if let Err(e) = writeln!(f, "{}", search_metrics(tree)) {
+            error!("Unable to write telemetry: {:?}", e);
+        }
+    }
+}
+
+fn search_metrics(tree: &SearchTree) -> String {
+    let table = tree.lr_table();
+    let playouts = tree.playouts();
+    let histogram: Vec<String> = tree
+        .eval_histogram()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    format!(
+        "{{\"playouts\":{},\"nodes\":{},\"nodes_created\":{},\"capped_playouts\":{},\"avg_depth\":{},\"max_depth\":{},\"tb_hits\":{},\"hashfull\":{},\"table_flips\":{},\"table_hit_rate\":{},\"arena_chunks\":{},\"eval_histogram\":[{}]}}",
+        playouts,
+        tree.num_nodes(),
+        tree.nodes_created(),
+        tree.capped_playouts(),
+        tree.depth_sum() as f32 / playouts.max(1) as f32,
+        tree.max_depth(),
+        tree.tb_hits(),
+        table.hashfull(),
+        table.flips(),
+        table.hit_rate(),
+        table.chunks_allocated(),
+        histogram.join(",")
+    )
+}
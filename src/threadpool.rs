@@ -0,0 +1,140 @@
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::hint;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvError};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::options::get_num_threads;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Checks of the queue an idle worker makes before parking, so a go right after the
+// last search doesn't wait for the OS to wake its threads
+const SPIN_ITERATIONS: usize = 1 << 16;
+
+static POOL: Lazy<Pool> = Lazy::new(|| Pool {
+    target: AtomicUsize::new(get_num_threads()),
+    ..Pool::default()
+});
+
+/// Threads kept alive between searches. Jobs go on a shared queue that any idle
+/// worker takes from. There's no work stealing, as each job is a search thread that
+/// keeps its worker until the search stops.
+#[derive(Default)]
+struct Pool {
+    queue: Mutex<VecDeque<Job>>,
+    job_queued: Condvar,
+    // The queue's length, for spinning workers to check without the lock
+    pending: AtomicUsize,
+    workers: AtomicUsize,
+    idle: AtomicUsize,
+    // Idle workers beyond this end
+    target: AtomicUsize,
+}
+
+impl Pool {
+    fn spawn_worker(&'static self) {
+        self.workers.fetch_add(1, Ordering::SeqCst);
+
+        thread::spawn(move || {
+            while let Some(job) = self.take_job() {
+                // A panicking job fails its handle's join, but the worker carries on so
+                // the pool's count of workers stays right
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+        });
+    }
+
+    /// Waits for the next job, or returns `None` if the worker isn't needed anymore.
+    fn take_job(&self) -> Option<Job> {
+        self.idle.fetch_add(1, Ordering::SeqCst);
+
+        for _ in 0..SPIN_ITERATIONS {
+            if self.pending.load(Ordering::Acquire) > 0 {
+                break;
+            }
+            hint::spin_loop();
+        }
+
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if let Some(job) = queue.pop_front() {
+                self.pending.fetch_sub(1, Ordering::Release);
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                return Some(job);
+            }
+
+            if self.workers.load(Ordering::SeqCst) > self.target.load(Ordering::SeqCst) {
+                self.idle.fetch_sub(1, Ordering::SeqCst);
+                self.workers.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+
+            queue = self.job_queued.wait(queue).unwrap();
+        }
+    }
+}
+
+/// Waits for a job given to the pool to finish.
+pub struct JobHandle {
+    done: Receiver<()>,
+}
+
+impl JobHandle {
+    /// Fails if the job panicked.
+    pub fn join(self) -> Result<(), RecvError> {
+        self.done.recv()
+    }
+}
+
+/// Keeps `threads` workers around, starting them now or ending idle ones beyond it.
+/// Busy workers finish their jobs first.
+pub fn resize(threads: usize) {
+    POOL.target.store(threads, Ordering::SeqCst);
+
+    let workers = POOL.workers.load(Ordering::SeqCst);
+    for _ in workers..threads {
+        POOL.spawn_worker();
+    }
+
+    // Idle workers recheck whether they're still needed
+    let _queue = POOL.queue.lock().unwrap();
+    POOL.job_queued.notify_all();
+}
+
+/// Runs `f` on an idle worker, starting a new one if none will be free for it.
+///
+/// # Safety
+///
+/// Like `crossbeam::spawn_unsafe`, `f` may borrow data that must outlive the job, so
+/// the returned handle has to be joined before any of it is dropped.
+pub unsafe fn spawn_unsafe<'a, F>(f: F) -> JobHandle
+where
+    F: FnOnce() + Send + 'a,
+{
+    let (done_sender, done) = channel();
+
+    let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+        f();
+        done_sender.send(()).unwrap_or(());
+    });
+    let job: Job = mem::transmute(job);
+
+    let mut queue = POOL.queue.lock().unwrap();
+    queue.push_back(job);
+    POOL.pending.fetch_add(1, Ordering::Release);
+
+    // Every job needs a worker of its own, as jobs run until the search stops
+    if queue.len() > POOL.idle.load(Ordering::SeqCst) {
+        POOL.spawn_worker();
+    }
+
+    POOL.job_queued.notify_one();
+
+    JobHandle { done }
+}
@@ -11,7 +11,9 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::str;
 
-use crate::mcts::Mcts;
+use crate::datafmt::{ChessBoard, PolicyRecord};
+use crate::mcts::{eval_to_cp, Mcts};
+pub use crate::state::FeatureSet;
 use crate::state::{self, Builder as StateBuilder};
 use crate::transposition_table::TranspositionTable;
 
@@ -25,6 +27,7 @@ pub enum GameResult {
 }
 
 impl GameResult {
+    #[must_use]
     pub fn flip(self) -> Self {
         match self {
             GameResult::WhiteWin => GameResult::BlackWin,
@@ -34,8 +37,27 @@ impl GameResult {
     }
 }
 
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Libsvm,
+    Bullet,
+}
+
+impl OutputFormat {
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "libsvm" => Some(OutputFormat::Libsvm),
+            "bullet" => Some(OutputFormat::Bullet),
+            _ => None,
+        }
+    }
+}
+
 struct ValueDataGenerator {
     out_file: BufWriter<File>,
+    policy_file: Option<BufWriter<File>>,
+    features: FeatureSet,
     state: StateBuilder,
     skip: bool,
     rows_written: usize,
@@ -98,35 +120,52 @@ impl Visitor for ValueDataGenerator {
 
                 let eval = mcts.eval();
 
-                let crnt_result = if state.side_to_move() == Color::White {
-                    game_result
+                if let Some(policy_file) = self.policy_file.as_mut() {
+                    let score = eval_to_cp(eval) as i16;
+                    let board = ChessBoard::from_state(&state, score, game_result);
+                    board.write(&mut self.out_file).unwrap();
+
+                    let visits: Vec<_> = mcts
+                        .tree()
+                        .root_node()
+                        .hots()
+                        .iter()
+                        .filter_map(|h| Some((h.get_move().to_move(&state)?, h.visits())))
+                        .collect();
+                    PolicyRecord::from_visits(&state, board, &visits)
+                        .write(policy_file)
+                        .unwrap();
                 } else {
-                    game_result.flip()
-                };
-                let wdl = match crnt_result {
-                    GameResult::WhiteWin => 1,
-                    GameResult::BlackWin => -1,
-                    GameResult::Draw => 0,
-                };
-
-                let mut board_features = [0i8; state::NUMBER_FEATURES];
-                let mut move_features = [0i8; state::NUMBER_MOVE_IDX];
-
-                state.features_map(|idx| board_features[idx] = 1);
-
-                for m in moves.as_slice() {
-                    move_features[state.move_to_index(m)] = 2;
+                    let crnt_result = if state.side_to_move() == Color::White {
+                        game_result
+                    } else {
+                        game_result.flip()
+                    };
+                    let wdl = match crnt_result {
+                        GameResult::WhiteWin => 1,
+                        GameResult::BlackWin => -1,
+                        GameResult::Draw => 0,
+                    };
+
+                    let mut board_features = vec![0i8; self.features.inputs()];
+                    let mut move_features = [0i8; state::NUMBER_MOVE_IDX];
+
+                    state.feature_set_map(self.features, |idx| board_features[idx] = 1);
+
+                    for m in moves.as_slice() {
+                        move_features[state.move_to_index(m)] = 2;
+                    }
+
+                    move_features[state.move_to_index(&made)] = 1;
+
+                    let mut f_vec =
+                        Vec::with_capacity(1 + state::NUMBER_MOVE_IDX + board_features.len());
+                    f_vec.push(wdl);
+                    f_vec.extend_from_slice(&move_features);
+                    f_vec.extend_from_slice(&board_features);
+
+                    write_libsvm(&f_vec, &mut self.out_file, eval);
                 }
-
-                move_features[state.move_to_index(&made)] = 1;
-
-                let mut f_vec =
-                    Vec::with_capacity(1 + state::NUMBER_MOVE_IDX + state::NUMBER_FEATURES);
-                f_vec.push(wdl);
-                f_vec.extend_from_slice(&move_features);
-                f_vec.extend_from_slice(&board_features);
-
-                write_libsvm(&f_vec, &mut self.out_file, eval);
             }
             state.make_move(&made);
         }
@@ -139,6 +178,11 @@ impl Visitor for ValueDataGenerator {
     fn end_game(&mut self) -> Self::Result {}
 }
 
+/// Writes a libsvm row of the non-zero `features` after `label`.
+///
+/// # Panics
+///
+/// Panics if writing to `f` fails.
 pub fn write_libsvm<W: Write>(features: &[i8], f: &mut W, label: f32) {
     write!(f, "{label}").unwrap();
     for (index, value) in features.iter().enumerate() {
@@ -149,9 +193,16 @@ pub fn write_libsvm<W: Write>(features: &[i8], f: &mut W, label: f32) {
     writeln!(f).unwrap();
 }
 
-fn run_value_gen(in_path: &str, out_file: BufWriter<File>) -> ValueDataGenerator {
+fn run_value_gen(
+    in_path: &str,
+    out_file: BufWriter<File>,
+    policy_file: Option<BufWriter<File>>,
+    features: FeatureSet,
+) -> ValueDataGenerator {
     let mut generator = ValueDataGenerator {
         out_file,
+        policy_file,
+        features,
         state: StateBuilder::default(),
         skip: true,
         rows_written: 0,
@@ -167,8 +218,22 @@ fn run_value_gen(in_path: &str, out_file: BufWriter<File>) -> ValueDataGenerator
     generator
 }
 
-pub fn train(in_path: &str, out_path: &str) {
+/// Writes training data for the positions of the games in `in_path`. Libsvm rows
+/// hold the inputs of `features`; bullet records hold the board, so the trainer
+/// computes the inputs itself.
+///
+/// # Panics
+///
+/// Panics if `in_path` can't be read or the output files can't be written.
+pub fn train(in_path: &str, out_path: &str, format: OutputFormat, features: FeatureSet) {
     let out_file = BufWriter::new(File::create(out_path).expect("create"));
+    // Bullet value records can't carry policy targets, so those go alongside
+    let policy_file = match format {
+        OutputFormat::Libsvm => None,
+        OutputFormat::Bullet => Some(BufWriter::new(
+            File::create(format!("{out_path}.policy")).expect("create"),
+        )),
+    };
     println!("Featurizing {in_path}...");
-    run_value_gen(in_path, out_file);
+    run_value_gen(in_path, out_file, policy_file, features);
 }
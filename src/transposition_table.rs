@@ -1,80 +1,273 @@
-use dashmap::DashMap;
-use nohash_hasher::BuildNoHashHasher;
-use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
-use std::sync::{Arc, Mutex};
-
-use crate::arena::{Allocator, Arena, Error as ArenaError};
-use crate::options::get_hash_size_mb;
+use arc_swap::ArcSwap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::arena::{self, Allocator, Arena, Error as ArenaError, Handle};
+use crate::options::{get_hash_size_mb, is_repetition_hashing};
+use crate::output;
 use crate::search_tree::{HotMoveInfo, SearchNode};
 use crate::state::State;
 
-type Table = DashMap<u64, AtomicPtr<SearchNode>, BuildNoHashHasher<u64>>;
+const HALFMOVE_BUCKET_SIZE: usize = 20;
+
+// Roughly what a node and its moves take up in the arena, to size the index
+const BYTES_PER_NODE: usize = 512;
+const ENTRIES_PER_BUCKET: usize = 8;
+// Buckets looked at to estimate hashfull
+const HASHFULL_SAMPLE: usize = 1000 / ENTRIES_PER_BUCKET;
+
+// Arena usage in permille past which tables are flipped between playouts, before
+// an allocation fails. Arenas too small to keep a chunk spare at this usage only
+// flip once full.
+const FLIP_WATERMARK: usize = 950;
+const MIN_WATERMARK_CHUNKS: usize = 1000 / (1000 - FLIP_WATERMARK);
+
+/// The position hash, optionally salted with the repetition count and a halfmove
+/// clock bucket so positions with different draw prospects aren't merged.
+pub fn table_key(state: &State) -> u64 {
+    let hash = state.zobrist_key();
+
+    if hash == 0 || !is_repetition_hashing() {
+        return hash;
+    }
+
+    let bucket =
+        state.repetition_count().min(2) + 3 * (state.halfmove_counter() / HALFMOVE_BUCKET_SIZE);
+
+    hash ^ (bucket as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+// An entry packs the top bits of the key and a count of the hits it has had above
+// the node's handle, so it is read and replaced in one go. Handles are never null,
+// which leaves zero for empty.
+const KEY_MASK: u64 = !0xFF_FFFF_FFFF;
+const HITS_SHIFT: u32 = 32;
+
+fn pack(hash: u64, handle: Handle) -> u64 {
+    (hash & KEY_MASK) | u64::from(handle.bits())
+}
+
+// The top bits of the key rule out most entries without touching their nodes, but
+// only the full key kept in the node tells positions sharing them apart.
+fn matches(entry: u64, hash: u64) -> bool {
+    entry != 0
+        && (entry ^ hash) & KEY_MASK == 0
+        && unsafe { unpack(entry).get::<SearchNode>() }.map_or(false, |n| n.key() == hash)
+}
+
+fn unpack(entry: u64) -> Handle {
+    Handle::from_bits(entry as u32)
+}
+
+fn hits(entry: u64) -> u8 {
+    (entry >> HITS_SHIFT) as u8
+}
+
+// Counts a hit on the entry, unless it is saturated or has just been replaced
+fn touch(slot: &AtomicU64, entry: u64) {
+    if hits(entry) < u8::MAX {
+        let _ = slot.compare_exchange(
+            entry,
+            entry + (1 << HITS_SHIFT),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// One cache line of entries.
+#[repr(align(64))]
+#[derive(Default)]
+struct Bucket([AtomicU64; ENTRIES_PER_BUCKET]);
+
+struct Index {
+    buckets: Box<[Bucket]>,
+    len: AtomicUsize,
+}
+
+impl Index {
+    fn for_size_mb(size_mb: usize) -> Self {
+        let entries = size_mb * (1 << 20) / BYTES_PER_NODE;
+        let num_buckets = (entries / ENTRIES_PER_BUCKET).max(1).next_power_of_two();
+
+        Self {
+            buckets: (0..num_buckets).map(|_| Bucket::default()).collect(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn bucket(&self, hash: u64) -> &Bucket {
+        &self.buckets[hash as usize & (self.buckets.len() - 1)]
+    }
+
+    fn lookup(&self, hash: u64) -> Option<Handle> {
+        self.bucket(hash).0.iter().find_map(|slot| {
+            let entry = slot.load(Ordering::Acquire);
+            matches(entry, hash).then(|| {
+                touch(slot, entry);
+                unpack(entry)
+            })
+        })
+    }
 
+    // Takes an empty entry if there is one, otherwise evicts the one with fewest hits
+    fn insert(&self, hash: u64, value: Handle) -> Handle {
+        let bucket = &self.bucket(hash).0;
+        let new = pack(hash, value);
+
+        loop {
+            let mut victim: Option<(&AtomicU64, u64, u8)> = None;
+
+            // Entries are only emptied all at once, so nothing follows an empty one
+            for slot in bucket {
+                let entry = slot.load(Ordering::Acquire);
+
+                if entry == 0 {
+                    victim = Some((slot, 0, 0));
+                    break;
+                }
+
+                if matches(entry, hash) {
+                    touch(slot, entry);
+                    return unpack(entry);
+                }
+
+                let count = hits(entry);
+                if victim.map_or(true, |(_, _, fewest)| count < fewest) {
+                    victim = Some((slot, entry, count));
+                }
+            }
+
+            let (slot, old, _) = victim.expect("buckets are never empty");
+
+            if slot
+                .compare_exchange(old, new, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                if old == 0 {
+                    self.len.fetch_add(1, Ordering::Relaxed);
+                }
+                return value;
+            }
+
+            // Another thread wrote the entry first, which may have been this position
+        }
+    }
+
+    fn clear(&self) {
+        for bucket in self.buckets.iter() {
+            for entry in &bucket.0 {
+                entry.store(0, Ordering::Relaxed);
+            }
+        }
+        self.len.store(0, Ordering::Relaxed);
+    }
+
+    fn hashfull(&self) -> usize {
+        let sample = &self.buckets[..HASHFULL_SAMPLE.min(self.buckets.len())];
+        let used = sample
+            .iter()
+            .flat_map(|b| &b.0)
+            .filter(|e| e.load(Ordering::Relaxed) != 0)
+            .count();
+
+        used * 1000 / (sample.len() * ENTRIES_PER_BUCKET)
+    }
+}
+
+/// Maps positions to their nodes in a fixed number of cache line sized buckets. When
+/// a bucket is full the node found there least often is dropped from the index, though
+/// it stays in the arena for whichever parents already link to it.
 pub struct TranspositionTable {
-    table: Table,
-    #[allow(dead_code)]
+    index: ArcSwap<Index>,
     arena: Box<Arena>,
 }
 
 impl TranspositionTable {
     pub fn empty() -> Self {
-        let table = Table::default();
-        let arena = Box::new(Arena::new(get_hash_size_mb() / 2));
+        Self::with_size_mb(get_hash_size_mb() / 2)
+    }
 
-        Self::new(table, arena)
+    pub fn with_size_mb(size_mb: usize) -> Self {
+        Self::new(size_mb)
     }
 
     pub fn for_root() -> Self {
-        Self::new(Table::default(), Box::new(Arena::new(2)))
+        Self::new(2)
     }
 
     pub fn zero() -> Self {
-        Self::new(Table::default(), Box::new(Arena::new(0)))
+        Self::new(0)
     }
 
-    fn new(table: Table, arena: Box<Arena>) -> Self {
-        Self { table, arena }
+    fn new(size_mb: usize) -> Self {
+        Self {
+            index: ArcSwap::from_pointee(Index::for_size_mb(size_mb)),
+            arena: Box::new(Arena::new(size_mb)),
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.table.is_empty()
+        self.index.load().len.load(Ordering::Relaxed) == 0
     }
 
     pub fn arena(&self) -> &Arena {
         &self.arena
     }
 
+    /// Resizes the arena and rebuilds the index to match. Nodes already linked into
+    /// the tree are kept, but can no longer be found by position.
+    pub fn set_size_mb(&self, size_mb: usize) {
+        self.arena.set_max_size_mb(size_mb);
+        self.index.store(Arc::new(Index::for_size_mb(size_mb)));
+    }
+
     pub fn clear(&self) {
-        self.table.clear();
+        self.index.load().clear();
         self.arena.clear();
     }
 
-    pub fn insert<'a>(&'a self, key: &State, value: &'a SearchNode) -> Option<&'a SearchNode> {
-        let hash = key.hash();
+    /// Share of index entries in use, in permille, estimated from the first buckets.
+    pub fn hashfull(&self) -> usize {
+        self.index.load().hashfull()
+    }
+
+    /// Starts loading the bucket `state` would be found in.
+    pub fn prefetch(&self, state: &State) {
+        let hash = table_key(state);
+        if hash != 0 {
+            arena::prefetch::<Bucket>(self.index.load().bucket(hash));
+        }
+    }
+
+    /// Inserts the node behind `value` unless the position is already there,
+    /// returning whichever node the table now holds for it.
+    pub fn insert(&self, key: &State, value: Handle) -> Option<Handle> {
+        let hash = table_key(key);
         if hash == 0 {
             return None;
         }
 
-        if let Some(value_here) = self.table.get(&hash) {
-            unsafe { Some(&*value_here.load(Ordering::Relaxed)) }
-        } else {
-            self.table
-                .insert(hash, AtomicPtr::new(value as *const _ as *mut _));
-            Some(value)
-        }
+        Some(self.index.load().insert(hash, value))
     }
 
-    pub fn lookup<'a>(&'a self, key: &State) -> Option<&'a SearchNode> {
-        let hash = key.hash();
+    pub fn lookup(&self, key: &State) -> Option<Handle> {
+        let hash = table_key(key);
+        if hash == 0 {
+            return None;
+        }
 
-        self.table
-            .get(&hash)
-            .map(|v| unsafe { &*v.load(Ordering::Relaxed) })
+        self.index.load().lookup(hash)
     }
 
     pub fn lookup_into(&self, state: &State, dest: &mut SearchNode) {
-        if let Some(src) = self.lookup(state) {
-            dest.set_flag(src.flag());
+        let src = self
+            .lookup(state)
+            .and_then(|handle| unsafe { handle.get::<SearchNode>() });
+
+        if let Some(src) = src {
+            dest.copy_flag_from(src);
 
             let lhs = dest.hots();
             let rhs = src.hots();
@@ -90,7 +283,29 @@ pub struct LRTable {
     left: TranspositionTable,
     right: TranspositionTable,
     is_left_current: Arc<AtomicBool>,
-    flip_lock: Mutex<()>,
+    flipping: AtomicBool,
+    active_playouts: AtomicUsize,
+    // Counted since the last reset, for telemetry. Lookups and inserts are only
+    // counted when asked for, as every thread would contend on them.
+    counting: AtomicBool,
+    flips: AtomicUsize,
+    lookups: AtomicUsize,
+    hits: AtomicUsize,
+    inserts: AtomicUsize,
+    // Inserts that found the position already there, from another thread or a collision
+    existing: AtomicUsize,
+}
+
+/// Held for the duration of a playout. Tables are only flipped while no guard is alive,
+/// so nodes reached during a playout stay valid without taking a lock.
+pub struct PlayoutGuard<'a> {
+    active_playouts: &'a AtomicUsize,
+}
+
+impl Drop for PlayoutGuard<'_> {
+    fn drop(&mut self) {
+        self.active_playouts.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl LRTable {
@@ -99,20 +314,163 @@ impl LRTable {
             left,
             right,
             is_left_current: Arc::new(AtomicBool::new(true)),
-            flip_lock: Mutex::new(()),
+            flipping: AtomicBool::new(false),
+            active_playouts: AtomicUsize::new(0),
+            counting: AtomicBool::new(false),
+            flips: AtomicUsize::new(0),
+            lookups: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            inserts: AtomicUsize::new(0),
+            existing: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn enter_playout(&self) -> PlayoutGuard {
+        loop {
+            while self.flipping.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+
+            self.active_playouts.fetch_add(1, Ordering::SeqCst);
+
+            if !self.flipping.load(Ordering::SeqCst) {
+                return PlayoutGuard {
+                    active_playouts: &self.active_playouts,
+                };
+            }
+
+            // A flip started in between, back off until it is done
+            self.active_playouts.fetch_sub(1, Ordering::SeqCst);
         }
     }
 
+    /// Waits for all in-flight playouts to finish, then flips if the current arena is
+    /// still full or past the watermark. Only one thread flips; others return immediately.
+    /// Must not be called while holding a `PlayoutGuard`.
+    pub fn flip_when_quiescent<F: FnOnce()>(&self, on_flip: F) {
+        if self
+            .flipping
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+
+        while self.active_playouts.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+
+        if self.is_arena_full() || self.is_arena_past_watermark() {
+            self.flip_tables();
+            on_flip();
+        }
+
+        self.flipping.store(false, Ordering::SeqCst);
+    }
+
+    /// Sizes both tables to `size_mb` each.
+    pub fn set_size_mb(&self, size_mb: usize) {
+        self.left.set_size_mb(size_mb);
+        self.right.set_size_mb(size_mb);
+    }
+
+    /// Index occupancy of the table currently being filled, in permille.
+    pub fn hashfull(&self) -> usize {
+        self.current_table().hashfull()
+    }
+
+    pub fn prefetch(&self, state: &State) {
+        self.current_table().prefetch(state);
+    }
+
     pub fn is_arena_full(&self) -> bool {
         self.current_table().arena().full()
     }
 
-    pub fn insert<'a>(&'a self, key: &State, value: &'a SearchNode) -> Option<&'a SearchNode> {
-        self.current_table().insert(key, value)
+    /// Whether the current arena is full enough to flip before allocations start to
+    /// fail. Cheap enough to check before every playout.
+    pub fn is_arena_past_watermark(&self) -> bool {
+        let arena = self.current_table().arena();
+
+        arena.capacity_chunks() >= MIN_WATERMARK_CHUNKS && arena.usage_permille() >= FLIP_WATERMARK
     }
 
-    pub fn lookup<'a>(&'a self, key: &State) -> Option<&'a SearchNode> {
-        self.current_table().lookup(key)
+    pub fn insert(&self, key: &State, value: Handle) -> Option<Handle> {
+        let result = self.current_table().insert(key, value);
+
+        if self.is_counting() {
+            self.inserts.fetch_add(1, Ordering::Relaxed);
+            if result.map_or(false, |node| node != value) {
+                self.existing.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        result
+    }
+
+    pub fn lookup(&self, key: &State) -> Option<Handle> {
+        let result = self.current_table().lookup(key);
+
+        if self.is_counting() {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            if result.is_some() {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        result
+    }
+
+    /// Turns counting lookups and inserts on or off, for the statistics reported by
+    /// `print_stats` and telemetry.
+    pub fn set_counting(&self, counting: bool) {
+        self.counting.store(counting, Ordering::Relaxed);
+    }
+
+    fn is_counting(&self) -> bool {
+        self.counting.load(Ordering::Relaxed)
+    }
+
+    pub fn flips(&self) -> usize {
+        self.flips.load(Ordering::Relaxed)
+    }
+
+    /// The share of lookups that found a node.
+    pub fn hit_rate(&self) -> f32 {
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        let hits = self.hits.load(Ordering::Relaxed);
+
+        hits as f32 / lookups.max(1) as f32
+    }
+
+    pub fn print_stats(&self) {
+        let lookups = self.lookups.load(Ordering::Relaxed);
+        let hits = self.hits.load(Ordering::Relaxed);
+
+        output::info(format!(
+            "info string tt lookups {} hits {} misses {} hitrate {:.1}% inserts {} existing {} flips {}",
+            lookups,
+            hits,
+            lookups.saturating_sub(hits),
+            self.hit_rate() * 100.,
+            self.inserts.load(Ordering::Relaxed),
+            self.existing.load(Ordering::Relaxed),
+            self.flips()
+        ));
+    }
+
+    pub fn chunks_allocated(&self) -> usize {
+        self.left.arena().chunks_allocated() + self.right.arena().chunks_allocated()
+    }
+
+    pub fn reset_counters(&self) {
+        self.flips.store(0, Ordering::Relaxed);
+        self.lookups.store(0, Ordering::Relaxed);
+        self.hits.store(0, Ordering::Relaxed);
+        self.inserts.store(0, Ordering::Relaxed);
+        self.existing.store(0, Ordering::Relaxed);
+        self.left.arena().reset_counters();
+        self.right.arena().reset_counters();
     }
 
     pub fn lookup_into(&self, state: &State, dest: &mut SearchNode) {
@@ -139,7 +497,8 @@ impl LRTable {
         }
     }
 
-    pub fn flip_tables(&self) {
+    fn flip_tables(&self) {
+        self.flips.fetch_add(1, Ordering::Relaxed);
         self.previous_table().clear();
         self.is_left_current.store(
             !self.is_left_current.load(Ordering::SeqCst),
@@ -147,6 +506,22 @@ impl LRTable {
         );
     }
 
+    /// Empties both tables and resets the counters, keeping their sizes.
+    pub fn clear(&self) {
+        self.left.clear();
+        self.right.clear();
+        self.reset_counters();
+    }
+
+    /// Both tables, the current one first.
+    pub fn into_tables(self) -> (TranspositionTable, TranspositionTable) {
+        if self.is_left_current() {
+            (self.left, self.right)
+        } else {
+            (self.right, self.left)
+        }
+    }
+
     pub fn table(self) -> TranspositionTable {
         if self.left.is_empty() {
             self.right
@@ -157,10 +532,6 @@ impl LRTable {
         }
     }
 
-    pub fn flip_lock(&self) -> &Mutex<()> {
-        &self.flip_lock
-    }
-
     pub fn allocator(&self) -> LRAllocator {
         LRAllocator::from_arenas(
             self.is_left_current.clone(),
@@ -193,7 +564,7 @@ impl<'a> LRAllocator<'a> {
         self.is_left_current.load(Ordering::Relaxed)
     }
 
-    pub fn alloc_node(&self) -> Result<&'a mut SearchNode, ArenaError> {
+    pub fn alloc_node(&self) -> Result<(Handle, &'a mut SearchNode), ArenaError> {
         if self.is_left_current() {
             self.left.alloc_one()
         } else {
@@ -1,30 +1,105 @@
 use fastapprox::faster;
 use std::f32;
 
+use crate::options::{get_cpuct_base, get_cpuct_factor, is_cpuct_schedule};
 use crate::search::SCALE;
 use crate::search_tree::HotMoveInfo;
 
-pub fn choose_child(moves: &[HotMoveInfo], cpuct: f32, is_root: bool) -> &HotMoveInfo {
+// Visits per step of the default exploration constant
+const DEFAULT_CPUCT_BASE: u64 = 8192;
+
+/// The exploration constant, which grows with the visits of the parent. By default
+/// it is `init * (1 + ln(1 + visits / 8192))`, with whole steps of 8192 visits. With
+/// `CPuctSchedule` it is `init + factor * ln((visits + base) / base)` instead.
+#[derive(Clone, Copy, Debug)]
+pub struct Cpuct {
+    pub init: f32,
+    pub base: f32,
+    pub factor: f32,
+    pub schedule: bool,
+}
+
+impl Cpuct {
+    pub fn new(init: f32) -> Self {
+        if is_cpuct_schedule() {
+            Self {
+                init,
+                base: get_cpuct_base(),
+                factor: get_cpuct_factor(),
+                schedule: true,
+            }
+        } else {
+            Self {
+                init,
+                base: DEFAULT_CPUCT_BASE as f32,
+                factor: init,
+                schedule: false,
+            }
+        }
+    }
+
+    pub fn at(&self, visits: u64) -> f32 {
+        let growth = if self.schedule {
+            (visits as f32 + self.base) / self.base
+        } else {
+            ((visits + DEFAULT_CPUCT_BASE) / DEFAULT_CPUCT_BASE) as f32
+        };
+
+        self.init + self.factor * faster::ln(growth)
+    }
+}
+
+pub fn choose_child(
+    moves: &[HotMoveInfo],
+    cpuct: Cpuct,
+    fpu_reduction: f32,
+    moves_left_slope: f32,
+    is_root: bool,
+) -> &HotMoveInfo {
     let total_visits = moves.iter().map(|v| u64::from(v.visits())).sum::<u64>() + 1;
     let sqrt_total_visits = (total_visits as f32).sqrt();
 
-    let exploration_constant =
-        (cpuct + cpuct * faster::ln(((total_visits + 8192) / 8192) as f32)) * SCALE;
+    // Unvisited children are valued at the parent's value minus a reduction
+    let parent_rewards = moves.iter().map(HotMoveInfo::sum_rewards).sum::<i64>() as f32;
+    let parent_value = parent_rewards / total_visits as f32;
+    let fpu = parent_value - fpu_reduction * SCALE;
+
+    let exploration_constant = cpuct.at(total_visits) * SCALE;
 
     let explore_coef = exploration_constant * sqrt_total_visits;
 
+    // Plies left on average below the parent, against which each child's are compared
+    let parent_plies = if moves_left_slope == 0. {
+        0.
+    } else {
+        moves.iter().map(HotMoveInfo::sum_plies).sum::<u64>() as f32
+            / (total_visits - 1).max(1) as f32
+    };
+
     let mut best_score = (f32::NEG_INFINITY, 1.);
     let mut choice = None;
 
+    // Root moves proven to lose are skipped, unless there's nothing else
+    let skip_pruned = is_root && !moves.iter().all(HotMoveInfo::is_pruned);
+
     for mov in moves {
+        if skip_pruned && mov.is_pruned() {
+            continue;
+        }
+
         if let Some(pc) = mov.get_move().promotion() {
             if !is_root && pc != shakmaty::Role::Queen {
                 continue;
             }
         }
 
-        let sum_rewards = mov.sum_rewards() as f32;
         let child_visits = mov.visits();
+        let sum_rewards = if child_visits == 0 {
+            fpu
+        } else {
+            mov.sum_rewards() as f32
+                + moves_left_bonus(mov, parent_plies, moves_left_slope) * child_visits as f32
+        };
         let policy_evaln = mov.policy();
 
         let numerator = sum_rewards + explore_coef * policy_evaln;
@@ -46,3 +121,22 @@ pub fn choose_child(moves: &[HotMoveInfo], cpuct: f32, is_root: bool) -> &HotMov
 
     choice.unwrap()
 }
+
+// Largest difference in plies left that the moves left bonus rewards
+const MAX_MOVES_LEFT_DIFF: f32 = 20.;
+
+// A bonus per visit for moves that end the game sooner than their siblings when
+// winning, and later when losing, growing with how decisive the move looks.
+fn moves_left_bonus(mov: &HotMoveInfo, parent_plies: f32, slope: f32) -> f32 {
+    if slope == 0. {
+        return 0.;
+    }
+
+    match (mov.average_reward(), mov.average_plies()) {
+        (Some(q), Some(plies)) => {
+            let shorter = (parent_plies - plies).clamp(-MAX_MOVES_LEFT_DIFF, MAX_MOVES_LEFT_DIFF);
+            slope * shorter * q
+        }
+        _ => 0.,
+    }
+}
@@ -1,15 +1,31 @@
 use std::io::{stdin, BufRead};
+use std::num::NonZeroUsize;
 use std::str::{FromStr, SplitWhitespace};
 use std::sync::mpsc::{channel, SendError};
 use std::thread;
 
+use crate::bench;
+use crate::book::{clear_book, set_book_file};
+use crate::evaluation::{
+    set_eval_file, set_evaluator, set_policy_file, NetEvaluator, SocketEvaluator,
+};
 use crate::options::{
-    set_chess960, set_cpuct, set_cvisits_selection, set_hash_size_mb, set_num_threads,
+    find_tunable, set_batch_size, set_book_best_move, set_chess960, set_cpuct_schedule,
+    set_dag_backup, set_deterministic, set_elo, set_hash_size_mb, set_history_guard,
+    set_human_like, set_human_like_rate, set_info_interval, set_info_verbosity, set_lenient_fen,
+    set_limit_strength, set_max_backup_weight, set_max_playout_length, set_move_overhead,
+    set_num_thread_groups, set_num_threads, set_numa, set_repetition_hashing, set_root_noise_alpha,
+    set_root_noise_epsilon, set_score_type, set_search_stats, set_show_wdl, set_syzygy_probe_depth,
+    set_syzygy_probe_limit, set_verbose_move_stats, TUNABLES,
 };
+use crate::output;
 use crate::search::Search;
 use crate::search_tree::print_size_list;
 use crate::state::State;
+use crate::strength::{set_skill_seed, MAX_ELO, MIN_ELO};
 use crate::tablebase::set_tablebase_directory;
+use crate::telemetry::{clear_telemetry_file, set_telemetry_file};
+use crate::threadpool;
 use crate::transposition_table::TranspositionTable;
 
 pub type Tokens<'a> = SplitWhitespace<'a>;
@@ -18,6 +34,13 @@ const ENGINE_NAME: &str = "Princhess";
 const ENGINE_AUTHOR: &str = "Princess Lana";
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
 
+const SPSA_R_END: f32 = 0.002;
+
+const EXPORT_DEPTH: usize = 4;
+const EXPORT_MIN_VISITS: u32 = 100;
+
+// Sending can't fail, as the receiver outlives every sender
+#[allow(clippy::missing_panics_doc)]
 pub fn main(commands: Vec<String>) {
     let mut search = Search::new(State::default(), TranspositionTable::empty());
     let (sender, receiver) = channel();
@@ -41,48 +64,166 @@ pub fn main(commands: Vec<String>) {
         if let Some(first_word) = tokens.next() {
             match first_word {
                 "uci"        => uci(),
-                "isready"    => println!("readyok"),
+                "isready"    => output!("readyok"),
                 "setoption"  => {
                     let option = UciOption::parse(tokens);
 
                     if let Some(opt) = option {
                         opt.set();
+
+                        if opt.name() == "hash" {
+                            search.resize_table();
+                        }
                     }
                 }
-                "ucinewgame" => {
-                    search = Search::new(State::default(), TranspositionTable::empty());
-                }
+                "ucinewgame" => search = search.new_game(),
                 "position"   => {
-                    if let Some(state) = State::from_tokens(tokens) {
-                        debug!("\n{:?}", state.board());
-                        let prev_table = search.table();
-                        search = Search::new(state, prev_table);
-                    } else {
-                        error!("Couldn't parse '{}' as position", line);
+                    match State::from_tokens(tokens) {
+                        Ok(state) => {
+                            debug!("\n{:?}", state.board());
+                            search = search.set_position(state);
+                        }
+                        Err(e) => output!("info string error {e}"),
                     }
                 },
                 "stop"       => search = search.stop_and_print(),
-                "quit"       => return,
+                "quit"       => break,
                 "go"         => search = search.go(tokens, &sender),
+                "ponderhit"  => search = search.ponderhit(&sender),
                 "movelist"   => search.print_move_list(),
+                "eval"       => search.print_eval(),
+                "d"          => search.print_board(),
+                "display"    => search.print_board(),
+                "tree"       => search.print_tree(tokens.next() == Some("children")),
+                "savetree"   => {
+                    if let Some(path) = tokens.next() {
+                        search = search.save_tree(path);
+                    }
+                },
+                "exporttree" => {
+                    if let Some(path) = tokens.next() {
+                        let depth = tokens.next().and_then(|d| d.parse().ok());
+                        let min_visits = tokens.next().and_then(|v| v.parse().ok());
+                        search.export_tree(
+                            path,
+                            depth.unwrap_or(EXPORT_DEPTH),
+                            min_visits.unwrap_or(EXPORT_MIN_VISITS),
+                        );
+                    }
+                },
+                "loadtree"   => {
+                    if let Some(path) = tokens.next() {
+                        search = search.load_tree(path);
+                    }
+                },
                 "sizelist"   => print_size_list(),
+                "perft"      => {
+                    let mut args = tokens.peekable();
+                    let divide = args.next_if_eq(&"divide").is_some();
+                    match args.next().and_then(|d| d.parse().ok()) {
+                        Some(depth) => search.perft(depth, divide),
+                        None => error!("Usage: perft [divide] <depth>"),
+                    }
+                },
+                "spsa"       => print_spsa(),
+                "bench"      => {
+                    let playouts = tokens.next().and_then(|p| p.parse().ok());
+                    bench::run(playouts.unwrap_or(bench::DEFAULT_BENCH_PLAYOUTS));
+                },
                 _ => error!("Unknown command: {} (this engine uses a reduced set of commands from the UCI protocol)", first_word)
             }
         }
     }
+
+    output::flush();
 }
 
 pub fn uci() {
-    println!("id name {} {}", ENGINE_NAME, VERSION.unwrap_or("unknown"));
-    println!("id author {ENGINE_AUTHOR}");
-    println!("option name Hash type spin min 8 max 65536 default 16");
-    println!("option name Threads type spin min 1 max 255 default 1");
-    println!("option name SyzygyPath type string");
-    println!("option name CPuct type string default 1.85");
-    println!("option name CVisitsSelection type string default 0.01");
-    println!("option name UCI_Chess960 type check default false");
-
-    println!("uciok");
+    output!("id name {} {}", ENGINE_NAME, VERSION.unwrap_or("unknown"));
+    output!("id author {ENGINE_AUTHOR}");
+    output!("option name Hash type spin min 8 max 65536 default 16");
+    output!("option name Threads type spin min 1 max 255 default 1");
+    output!("option name ThreadGroups type spin min 1 max 64 default 1");
+    output!("option name NUMA type check default false");
+    output!("option name BatchSize type spin min 1 max 256 default 1");
+    output!("option name MaxPlayoutLength type spin min 1 max 256 default 256");
+    output!("option name Deterministic type check default false");
+    output!("option name MoveOverhead type spin min 0 max 5000 default 50");
+    output!("option name InfoInterval type spin min 0 max 60000 default 1000");
+    output!("option name InfoVerbosity type spin min 0 max 2 default 1");
+    output!("option name SyzygyPath type string default <empty>");
+    output!("option name SyzygyProbeLimit type spin min 0 max 7 default 7");
+    output!("option name SyzygyProbeDepth type spin min 0 max 256 default 256");
+    output!("option name EvalFile type string default <empty>");
+    output!("option name PolicyFile type string default <empty>");
+    output!("option name EvalServer type string default <empty>");
+    output!("option name BookFile type string default <empty>");
+    output!("option name BookBestMove type check default false");
+    output!("option name TelemetryFile type string default <empty>");
+    output!("option name CPuctSchedule type check default false");
+    for t in TUNABLES {
+        output!("option name {} type string default {}", t.name, t.default);
+    }
+    output!("option name RootNoiseEpsilon type string default 0");
+    output!("option name RootNoiseAlpha type string default 0.3");
+    output!("option name DagBackup type check default false");
+    output!("option name MaxBackupWeight type string default 0");
+    output!("option name RepetitionHashing type check default false");
+    output!("option name HistoryGuard type check default false");
+    output!("option name UCI_Chess960 type check default false");
+    output!("option name LenientFen type check default false");
+    output!("option name UCI_ShowWDL type check default false");
+    output!(
+        "option name ScoreType type combo default centipawn var centipawn var win_percentage var Q"
+    );
+    output!("option name UCI_LimitStrength type check default false");
+    output!("option name UCI_Elo type spin min {MIN_ELO} max {MAX_ELO} default {MAX_ELO}");
+    output!("option name HumanLike type check default false");
+    output!("option name HumanLikeRate type string default 0.1");
+    output!("option name SkillSeed type spin min 0 max 2147483647 default 0");
+    output!("option name VerboseMoveStats type check default false");
+    output!("option name SearchStats type check default false");
+    output!("option name Ponder type check default false");
+
+    output!("uciok");
+}
+
+// Tunables as OpenBench SPSA inputs: name, type, value, min, max, c_end and r_end
+fn print_spsa() {
+    for t in TUNABLES {
+        output!(
+            "{}, float, {}, {}, {}, {}, {}",
+            t.name,
+            (t.get)(),
+            t.min,
+            t.max,
+            t.step,
+            SPSA_R_END
+        );
+    }
+}
+
+/// Sets an option as `setoption name <name> value <value>` would.
+pub fn set_option(name: &str, value: &str) {
+    UciOption {
+        name: name.to_lowercase(),
+        value: Some(value.to_owned()),
+    }
+    .set();
+}
+
+fn set_threads(threads: usize) {
+    let available = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    if threads > available {
+        warn!(
+            "{} threads is more than the {} this machine can run at once",
+            threads, available
+        );
+    }
+
+    set_num_threads(threads);
+    // Threads are started now rather than on the next go
+    threadpool::resize(threads.max(1));
 }
 
 struct UciOption {
@@ -136,12 +277,82 @@ impl UciOption {
                     set_tablebase_directory(path);
                 }
             }
-            "threads" => self.set_option(set_num_threads),
+            "batchsize" => self.set_option(set_batch_size),
+            "maxplayoutlength" => self.set_option(set_max_playout_length),
+            "deterministic" => self.set_option(set_deterministic),
+            "moveoverhead" => self.set_option(set_move_overhead),
+            "infointerval" => self.set_option(set_info_interval),
+            "infoverbosity" => self.set_option(set_info_verbosity),
+            "syzygyprobelimit" => self.set_option(set_syzygy_probe_limit),
+            "syzygyprobedepth" => self.set_option(set_syzygy_probe_depth),
+            "evalfile" => {
+                if let Some(path) = self.value() {
+                    match set_eval_file(path) {
+                        Ok(()) => info!("Loaded eval net from {}", path),
+                        Err(e) => error!("Unable to load eval net from {}: {:?}", path, e),
+                    }
+                }
+            }
+            "policyfile" => {
+                if let Some(path) = self.value() {
+                    match set_policy_file(path) {
+                        Ok(()) => info!("Loaded policy net from {}", path),
+                        Err(e) => error!("Unable to load policy net from {}: {:?}", path, e),
+                    }
+                }
+            }
+            "evalserver" => match self.value().as_deref() {
+                None | Some("<empty>") => set_evaluator(Box::new(NetEvaluator)),
+                Some(addr) => match SocketEvaluator::connect(addr) {
+                    Ok(evaluator) => {
+                        set_evaluator(Box::new(evaluator));
+                        info!("Connected to eval server at {}", addr);
+                    }
+                    Err(e) => error!("Unable to connect to eval server at {}: {:?}", addr, e),
+                },
+            },
+            "bookfile" => match self.value().as_deref() {
+                None | Some("<empty>") => clear_book(),
+                Some(path) => match set_book_file(path) {
+                    Ok(()) => info!("Loaded opening book from {}", path),
+                    Err(e) => error!("Unable to load opening book from {}: {:?}", path, e),
+                },
+            },
+            "bookbestmove" => self.set_option(set_book_best_move),
+            "telemetryfile" => match self.value().as_deref() {
+                None | Some("<empty>") => clear_telemetry_file(),
+                Some(path) => match set_telemetry_file(path) {
+                    Ok(()) => info!("Writing telemetry to {}", path),
+                    Err(e) => error!("Unable to open telemetry file {}: {:?}", path, e),
+                },
+            },
+            "threads" => self.set_option(set_threads),
+            "threadgroups" => self.set_option(set_num_thread_groups),
+            "numa" => self.set_option(set_numa),
             "hash" => self.set_option(set_hash_size_mb),
-            "cpuct" => self.set_option(set_cpuct),
-            "cvisitsselection" => self.set_option(set_cvisits_selection),
+            "scoretype" => self.set_option(set_score_type),
+            "rootnoiseepsilon" => self.set_option(set_root_noise_epsilon),
+            "rootnoisealpha" => self.set_option(set_root_noise_alpha),
+            "cpuctschedule" => self.set_option(set_cpuct_schedule),
+            "dagbackup" => self.set_option(set_dag_backup),
+            "maxbackupweight" => self.set_option(set_max_backup_weight),
+            "repetitionhashing" => self.set_option(set_repetition_hashing),
+            "historyguard" => self.set_option(set_history_guard),
             "uci_chess960" => self.set_option(set_chess960),
-            _ => warn!("Badly formatted or unknown option"),
+            "lenientfen" => self.set_option(set_lenient_fen),
+            "uci_showwdl" => self.set_option(set_show_wdl),
+            "uci_limitstrength" => self.set_option(set_limit_strength),
+            "uci_elo" => self.set_option(set_elo),
+            "humanlike" => self.set_option(set_human_like),
+            "humanlikerate" => self.set_option(set_human_like_rate),
+            "skillseed" => self.set_option(set_skill_seed),
+            "verbosemovestats" => self.set_option(set_verbose_move_stats),
+            "searchstats" => self.set_option(set_search_stats),
+            "ponder" => (),
+            name => match find_tunable(name) {
+                Some(t) => self.set_option(t.set),
+                None => warn!("Badly formatted or unknown option"),
+            },
         }
     }
 
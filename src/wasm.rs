@@ -0,0 +1,73 @@
+//! Bindings for running the engine in a browser, built by the `wasm` crate without
+//! default features, as there are neither threads nor tablebase files there.
+
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+
+use crate::engine::{Engine, EngineOptions, Limits, SearchResult};
+
+#[allow(clippy::module_name_repetitions)]
+#[wasm_bindgen]
+pub struct WasmEngine {
+    engine: Engine,
+}
+
+#[wasm_bindgen]
+impl WasmEngine {
+    #[wasm_bindgen(constructor)]
+    #[must_use]
+    pub fn new(hash_size_mb: usize) -> Self {
+        Self {
+            engine: Engine::new(EngineOptions {
+                threads: 1,
+                hash_size_mb,
+            }),
+        }
+    }
+
+    /// Sets the position from a FEN, or the start position if it's empty, followed
+    /// by space separated moves in UCI notation.
+    #[wasm_bindgen(js_name = setPosition)]
+    #[must_use]
+    pub fn set_position(&self, fen: &str, moves: &str) -> bool {
+        let fen = Some(fen).filter(|f| !f.is_empty());
+        let moves: Vec<&str> = moves.split_whitespace().collect();
+
+        self.engine.set_position(fen, &moves)
+    }
+
+    /// Searches for `movetime_ms` and returns the best move, or an empty string if
+    /// there are no moves.
+    #[must_use]
+    pub fn go(&self, movetime_ms: u32) -> String {
+        self.search(movetime_ms).best_move.unwrap_or_default()
+    }
+
+    /// Searches for `movetime_ms` and returns the result as JSON.
+    #[wasm_bindgen(js_name = goJson)]
+    #[must_use]
+    pub fn go_json(&self, movetime_ms: u32) -> String {
+        let result = self.search(movetime_ms);
+        let pv: Vec<String> = result.pv.iter().map(|m| format!("\"{m}\"")).collect();
+
+        format!(
+            "{{\"bestmove\":{},\"pv\":[{}],\"q\":{},\"draw\":{},\"nodes\":{}}}",
+            result
+                .best_move
+                .map_or("null".to_string(), |m| format!("\"{m}\"")),
+            pv.join(","),
+            result.eval,
+            result.draw,
+            result.nodes
+        )
+    }
+}
+
+impl WasmEngine {
+    fn search(&self, movetime_ms: u32) -> SearchResult {
+        self.engine.go(Limits {
+            movetime: Some(Duration::from_millis(u64::from(movetime_ms))),
+            ..Limits::default()
+        })
+    }
+}
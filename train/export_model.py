@@ -1,5 +1,6 @@
 import numpy
 import os
+import struct
 import sys
 from numpy import array2string
 from tensorflow import keras
@@ -15,7 +16,25 @@ def write_coefs(file, coefs):
         print(array2string(coefs, separator=","), file=f)
 
 
-def model_to_coefs(file):
+def fnv1a(data):
+    h = 0xCBF29CE484222325
+    for b in data:
+        h = ((h ^ b) * 0x100000001B3) & 0xFFFFFFFFFFFFFFFF
+    return h
+
+
+# Binary format read by the engine's EvalFile and PolicyFile options
+def write_net(file, magic, inputs, outputs, arrays):
+    data = numpy.concatenate([a.astype("<f4").ravel() for a in arrays]).tobytes()
+
+    with open(file, "wb") as f:
+        f.write(magic)
+        f.write(struct.pack("<II", inputs, outputs))
+        f.write(data)
+        f.write(struct.pack("<Q", fnv1a(data)))
+
+
+def model_to_coefs(file, curve=None):
     print(f"Exporting from {file}...")
     model = keras.models.load_model(
         file,
@@ -28,6 +47,7 @@ def model_to_coefs(file):
         os.mkdir(output_folder)
 
     idx = 0
+    hidden = []
 
     while idx < len(model.layers) - 1:
         if isinstance(model.layers[idx], keras.layers.Dense):
@@ -36,6 +56,7 @@ def model_to_coefs(file):
                 os.path.join(output_folder, f"hidden_weights_{idx}"), hidden_weights
             )
             write_coefs(os.path.join(output_folder, f"hidden_bias_{idx}"), hidden_bias)
+            hidden.append((hidden_weights, hidden_bias))
 
         idx += 1
 
@@ -44,6 +65,36 @@ def model_to_coefs(file):
         os.path.join(output_folder, "output_weights"), numpy.transpose(output_weights)
     )
 
+    if not hidden:
+        outputs, inputs = numpy.transpose(output_weights).shape
+        write_net(
+            os.path.join(output_folder, "policy.net"),
+            b"PPOL",
+            inputs,
+            outputs,
+            [numpy.transpose(output_weights)],
+        )
+    elif len(hidden) == 1:
+        hidden_weights, hidden_bias = hidden[0]
+        inputs, outputs = hidden_weights.shape
+        write_net(
+            os.path.join(output_folder, "eval.net"),
+            b"PEVL",
+            inputs,
+            outputs,
+            [hidden_bias, hidden_weights, numpy.transpose(output_weights)]
+            + ([numpy.array(curve)] if curve else []),
+        )
+
+
+# --curve=WIN,LOSS appends the centipawn scales of the eval net's score curve
+curve = None
+files = []
+for arg in sys.argv[1:]:
+    if arg.startswith("--curve="):
+        curve = [float(s) for s in arg[len("--curve=") :].split(",")]
+    else:
+        files.append(arg)
 
-for f in sys.argv[1:]:
-    model_to_coefs(f)
+for f in files:
+    model_to_coefs(f, curve)
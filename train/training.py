@@ -18,6 +18,10 @@ from tensorflow.nn import softmax_cross_entropy_with_logits
 
 # pieces + last capture + threats
 INPUT_SIZE = 768 + (5 * 64) + 768
+
+# data written with --features extended also has pawn structure + mobility
+if os.environ.get("PRINCHESS_FEATURES") == "extended":
+    INPUT_SIZE += (3 * 2 * 64) + (2 * 4 * 16)
 HIDDEN_LAYERS = 192
 
 ENTRIES_PER_FILE = 1000000
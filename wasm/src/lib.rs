@@ -0,0 +1,3 @@
+//! The browser build of Princhess, kept apart so only it is built as a `cdylib`.
+
+pub use princhess::wasm::WasmEngine;